@@ -0,0 +1,79 @@
+// Compares receiving one datagram per system call with receiving them in
+// batches. Each round queues a burst of full data packets from a local
+// sender, then times how long the receiver takes to take them off the socket
+// and feed them to a `FileManager`. Run with `cargo bench --bench recv_batch`.
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use segmented_file_system_client::packet::{data_packet::DataPacket, PacketRef, MAX_DATA_LEN};
+use segmented_file_system_client::{DatagramBatch, FileManager};
+
+/// Small enough to fit in a default-sized socket receive buffer, so the
+/// burst is queued in full before the receiver starts.
+const BURST: usize = 64;
+const ROUNDS: usize = 2000;
+
+fn main() {
+    println!(
+        "{:>6} {:>12} {:>12} {:>10}",
+        "batch", "ns/packet", "packets/s", "MiB/s"
+    );
+    for recv_batch in [1, 8, 32, 64] {
+        let (packets, elapsed) = run(recv_batch);
+        let per_second = packets as f64 / elapsed.as_secs_f64();
+        let mib_per_second = per_second * MAX_DATA_LEN as f64 / (1024.0 * 1024.0);
+        println!(
+            "{recv_batch:>6} {:>12.0} {per_second:>12.0} {mib_per_second:>10.1}",
+            elapsed.as_nanos() as f64 / packets as f64
+        );
+    }
+}
+
+/// Runs every round with batches of `recv_batch`, returning how many packets
+/// were received and how long receiving and processing them took. Receives
+/// that time out aren't counted, so the time is only spent on packets.
+fn run(recv_batch: usize) -> (usize, Duration) {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.connect(receiver.local_addr().unwrap()).unwrap();
+
+    let datagrams: Vec<Vec<u8>> = (0..BURST as u16)
+        .map(|packet_number| {
+            DataPacket::new(1, packet_number, vec![7; MAX_DATA_LEN], false).to_bytes()
+        })
+        .collect();
+
+    let mut file_manager = FileManager::default();
+    let mut batch = DatagramBatch::new(recv_batch);
+    let mut packets = 0;
+    let mut elapsed = Duration::ZERO;
+
+    for _ in 0..ROUNDS {
+        for datagram in &datagrams {
+            sender.send(datagram).unwrap();
+        }
+
+        let mut received = 0;
+        while received < BURST {
+            let started = Instant::now();
+            // A datagram dropped by the kernel shows up as a read timeout,
+            // which ends the round without being timed
+            if batch.recv(&receiver).is_err() {
+                break;
+            }
+            for datagram in batch.iter() {
+                let packet = PacketRef::try_from(datagram).unwrap();
+                file_manager.process_packet_ref(packet).unwrap();
+                received += 1;
+            }
+            elapsed += started.elapsed();
+        }
+        packets += received;
+    }
+
+    (packets, elapsed)
+}
@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+
+/// A packet that contradicts what the client already knows about its file.
+///
+/// The packet is dropped, so the first version of whatever it contradicts
+/// wins and the output isn't silently corrupted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketAnomaly {
+    /// A second header for the same file ID with a different name.
+    ConflictingFileName {
+        file_id: u8,
+        file_name: OsString,
+        rejected_name: OsString,
+    },
+    /// The same packet number arrived twice with different data.
+    ConflictingPayload { file_id: u8, packet_number: u16 },
+    /// A last-packet marker disagrees with an earlier one.
+    ConflictingLastPacket {
+        file_id: u8,
+        expected_number_of_packets: usize,
+        packet_number: u16,
+    },
+    /// A packet numbered past the file's declared last packet.
+    BeyondLastPacket {
+        file_id: u8,
+        expected_number_of_packets: usize,
+        packet_number: u16,
+    },
+    /// A second checksum for the same file ID with a different digest.
+    ConflictingChecksum { file_id: u8 },
+}
+
+impl fmt::Display for PacketAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketAnomaly::ConflictingFileName {
+                file_id,
+                file_name,
+                rejected_name,
+            } => write!(
+                f,
+                "file {file_id} is already named {:?}, ignoring new name {:?}",
+                file_name.to_string_lossy(),
+                rejected_name.to_string_lossy()
+            ),
+            PacketAnomaly::ConflictingPayload {
+                file_id,
+                packet_number,
+            } => write!(
+                f,
+                "file {file_id} packet {packet_number} arrived again with different data"
+            ),
+            PacketAnomaly::ConflictingLastPacket {
+                file_id,
+                expected_number_of_packets,
+                packet_number,
+            } => write!(
+                f,
+                "file {file_id} packet {packet_number} is marked last, but packet {} already was",
+                expected_number_of_packets - 1
+            ),
+            PacketAnomaly::BeyondLastPacket {
+                file_id,
+                expected_number_of_packets,
+                packet_number,
+            } => write!(
+                f,
+                "file {file_id} packet {packet_number} is past the last packet ({})",
+                expected_number_of_packets - 1
+            ),
+            PacketAnomaly::ConflictingChecksum { file_id } => write!(
+                f,
+                "file {file_id} already has a checksum, ignoring a different one"
+            ),
+        }
+    }
+}
+
+impl Error for PacketAnomaly {}
+
+/// Running totals kept by the `FileManager`, and by the receive loop for
+/// the datagrams it drops before they get there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketStats {
+    /// Data and header packets processed.
+    pub packets: usize,
+    /// Exact repeats of a packet that had already arrived.
+    pub duplicate_packets: usize,
+    /// Bytes of file data stored, not counting duplicates.
+    pub stored_bytes: u64,
+    /// Packets dropped as a `PacketAnomaly`.
+    pub anomalies: usize,
+    /// Datagrams dropped unread because of the address they came from.
+    pub stray_datagrams: usize,
+    /// Datagrams dropped unread because their MAC didn't verify.
+    pub unauthenticated_datagrams: usize,
+    /// Datagrams dropped unread because they were too long to be packets.
+    pub oversized_datagrams: usize,
+    /// Control messages dropped because they were of an unknown kind or cut
+    /// short.
+    pub unrecognized_control_messages: usize,
+}
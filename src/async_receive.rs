@@ -0,0 +1,185 @@
+use crate::extension::{Features, Negotiation};
+use crate::packet::MAX_DATAGRAM_LEN;
+use crate::receive::{is_nothing_received, receive_datagram, Idle, Peer, Progress, TransferClock};
+use crate::{ClientConfig, ClientError, FileManager, SourcePolicy};
+use std::io;
+use tokio::net::{self, UdpSocket};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task;
+use tokio::time;
+
+impl ClientConfig {
+    /// Like `connect`, but binds a tokio socket for use with the async
+    /// `receive_files`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either address can't be resolved, or binding or
+    /// connecting fails.
+    pub async fn connect_async(&self) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((self.bind_host.as_str(), self.bind_port)).await?;
+        if self.accept_from == SourcePolicy::Server {
+            socket
+                .connect((self.server_host.as_str(), self.server_port))
+                .await?;
+        } else {
+            socket.set_broadcast(true)?;
+        }
+        Ok(socket)
+    }
+}
+
+impl Peer {
+    async fn send_async(&self, socket: &UdpSocket, datagram: &[u8]) -> io::Result<usize> {
+        let datagram = self.seal(datagram)?;
+        match self.target() {
+            Some(address) => socket.send_to(&datagram, address).await,
+            None => socket.send(&datagram).await,
+        }
+    }
+}
+
+/// The async counterpart of `crate::receive_files`: the same hello retry,
+/// extensions, idle deadline and completion policy, the same source
+/// filtering, parsing and reassembly, but waiting on a tokio socket instead
+/// of blocking a thread.
+///
+/// Each wakeup takes up to `config.recv_batch` datagrams that are already
+/// queued on the socket before calling `Progress::batch_processed`, so a
+/// server that never pauses can't keep the loop from checking whether the
+/// transfer is done, or from being cancelled.
+///
+/// Transfers share nothing, so any number of them can run concurrently on
+/// one runtime, each with its own socket and `FileManager`.
+///
+/// # Blocking
+///
+/// Storing packets and writing files goes through `FileManager`, which uses
+/// blocking `std::fs` calls, and syncs every file before publishing it. On
+/// a multi-threaded runtime that work runs under
+/// `tokio::task::block_in_place`, so the worker hands its other tasks to
+/// another thread first. A current-thread runtime has nowhere to hand them,
+/// so they wait while a file is written; use a multi-threaded runtime, or
+/// `ReassemblyMode::InMemory` without a journal, where only a completed file
+/// touches the disk.
+///
+/// # Cancellation
+///
+/// Dropping the future (e.g. from `tokio::select!` or `tokio::time::timeout`)
+/// abandons the transfer between two batches of packets. Files that were already
+/// completed stay on disk, `file_manager` keeps everything received so far,
+/// and dropping `file_manager` removes any partial streaming files.
+///
+/// # Errors
+///
+/// Returns `ClientError::Timeout` if nothing arrives for `config.idle_timeout`,
+/// or `ClientError::MissingChecksums` if by then the only files left are
+/// waiting for checksums the server never sent, or another error if the
+/// socket fails or the server sends a malformed packet.
+pub async fn receive_files(
+    socket: &UdpSocket,
+    file_manager: &mut FileManager,
+    config: &ClientConfig,
+    progress: &mut impl Progress,
+) -> Result<(), ClientError> {
+    // One byte longer than any valid datagram, so a longer one fills it and
+    // can be dropped, rather than arriving cut short and looking valid
+    let mut buf = [0; MAX_DATAGRAM_LEN + 1];
+    let mut negotiation = Negotiation::new(config.extensions);
+    // Files wait for their checksums until the server says it won't send them
+    file_manager.require_checksums |= config.extensions.contains(Features::CHECKSUM);
+    let mut peer = if let Ok(server) = socket.peer_addr() {
+        Peer::new(config, server, true)?
+    } else {
+        let addrs = net::lookup_host((config.server_host.as_str(), config.server_port)).await?;
+        let server = config.pick_server_addr(addrs, socket.local_addr()?)?;
+        Peer::new(config, server, false)?
+    };
+    let hello = peer.hello(&negotiation);
+
+    // Send a hello to initiate communication with the server
+    peer.send_async(socket, &hello).await?;
+    let mut clock = TransferClock::new();
+    file_manager.start_clock();
+
+    while !clock.transfer_done(file_manager, config) {
+        match time::timeout(config.read_timeout, socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, source))) => {
+                let admitted = blocking(|| -> Result<bool, ClientError> {
+                    let mut admitted = receive_datagram(
+                        &mut peer,
+                        Some(source),
+                        &buf[..len],
+                        &mut negotiation,
+                        file_manager,
+                        progress,
+                    )?;
+
+                    // Take whatever else has already arrived without waiting,
+                    // up to a batch
+                    for _ in 1..config.recv_batch {
+                        match socket.try_recv_from(&mut buf) {
+                            Ok((len, source)) => {
+                                admitted |= receive_datagram(
+                                    &mut peer,
+                                    Some(source),
+                                    &buf[..len],
+                                    &mut negotiation,
+                                    file_manager,
+                                    progress,
+                                )?;
+                            }
+                            Err(e) if is_nothing_received(&e) => break,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    progress.batch_processed()?;
+                    Ok(admitted)
+                })?;
+                if admitted {
+                    clock.packet_received();
+                    continue;
+                }
+            }
+            Ok(Err(e)) if is_nothing_received(&e) => progress.idle()?,
+            Ok(Err(e)) => return Err(e.into()),
+            // The read timeout expired
+            Err(_) => progress.idle()?,
+        }
+
+        match clock.nothing_received(file_manager, config)? {
+            Idle::Wait => {}
+            Idle::ResendHello => {
+                peer.send_async(socket, &hello).await?;
+            }
+            Idle::RequestMissing => {
+                for request in negotiation.requests(file_manager) {
+                    peer.send_async(socket, &request).await?;
+                }
+            }
+            Idle::WriteUnnamed => blocking(|| -> Result<(), ClientError> {
+                for event in file_manager.write_unnamed_files()? {
+                    progress.file_event(&event)?;
+                }
+                Ok(())
+            })?,
+        }
+    }
+
+    if let Some(done) = negotiation.done() {
+        // Only saves the server some waiting, so a failure doesn't matter
+        let _ = peer.send_async(socket, &done).await;
+    }
+    Ok(())
+}
+
+/// Runs `f`, which may block on the disk, without holding up the runtime's
+/// other tasks where that is possible: on a multi-threaded runtime the
+/// worker hands them to another thread first.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    if Handle::current().runtime_flavor() == RuntimeFlavor::MultiThread {
+        task::block_in_place(f)
+    } else {
+        f()
+    }
+}
@@ -0,0 +1,305 @@
+use crate::packet::MAX_DATAGRAM_LEN;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// A ring of datagram-sized buffers that can be filled with several datagrams
+/// by a single system call.
+///
+/// On Linux, `recv` uses `recvmmsg` to read as many datagrams as are already
+/// queued, up to the batch's capacity. Elsewhere, or with a capacity of one,
+/// it falls back to one `UdpSocket::recv` per call. Everything is allocated
+/// up front, so receiving never allocates.
+///
+/// Datagrams longer than `MAX_DATAGRAM_LEN` can't be packets, and are
+/// dropped rather than handed on cut short; `oversized` counts them.
+pub struct DatagramBatch {
+    /// One byte longer than any valid datagram, so a longer one shows.
+    buffers: Box<[[u8; BUFFER_LEN]]>,
+    lens: Box<[usize]>,
+    /// Where each datagram came from, if it was an IP address.
+    sources: Box<[Option<SocketAddr>]>,
+    received: usize,
+    oversized: usize,
+    #[cfg(target_os = "linux")]
+    headers: MessageHeaders,
+}
+
+const BUFFER_LEN: usize = MAX_DATAGRAM_LEN + 1;
+
+impl DatagramBatch {
+    /// A batch that holds up to `capacity` datagrams (at least one).
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut buffers = vec![[0; BUFFER_LEN]; capacity].into_boxed_slice();
+        DatagramBatch {
+            #[cfg(target_os = "linux")]
+            headers: MessageHeaders::new(&mut buffers),
+            buffers,
+            lens: vec![0; capacity].into_boxed_slice(),
+            sources: vec![None; capacity].into_boxed_slice(),
+            received: 0,
+            oversized: 0,
+        }
+    }
+
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Waits for at least one datagram on `socket`, honouring its read
+    /// timeout, then takes whatever else is already queued, replacing the
+    /// previous contents of the batch. Returns the number of datagrams.
+    ///
+    /// # Errors
+    ///
+    /// Returns the socket's error, e.g. `WouldBlock` if the read timeout
+    /// expired with nothing received.
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.received = 0;
+        self.oversized = 0;
+        self.received = if self.capacity() == 1 {
+            self.recv_one(socket)?
+        } else {
+            self.recv_many(socket)?
+        };
+        Ok(self.received)
+    }
+
+    /// The datagrams from the last `recv`, in the order they arrived.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.buffers
+            .iter()
+            .zip(self.lens.iter())
+            .take(self.received)
+            .map(|(buffer, &len)| &buffer[..len])
+    }
+
+    /// How many datagrams the last `recv` dropped for being longer than
+    /// `MAX_DATAGRAM_LEN`.
+    #[must_use]
+    pub fn oversized(&self) -> usize {
+        self.oversized
+    }
+
+    /// Like `iter`, but with the address each datagram came from.
+    pub fn iter_with_sources(&self) -> impl Iterator<Item = (Option<SocketAddr>, &[u8])> {
+        self.sources.iter().copied().zip(self.iter())
+    }
+
+    fn recv_one(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let (len, source) = socket.recv_from(&mut self.buffers[0])?;
+        if len > MAX_DATAGRAM_LEN {
+            self.oversized = 1;
+            return Ok(0);
+        }
+        self.lens[0] = len;
+        self.sources[0] = Some(source);
+        Ok(1)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_many(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        use std::ptr;
+
+        let headers = &mut self.headers.headers;
+        for header in headers.iter_mut() {
+            // The kernel overwrites these with what it received
+            header.msg_hdr.msg_namelen = NAME_LEN;
+            header.msg_hdr.msg_flags = 0;
+        }
+        let count = u32::try_from(headers.len()).unwrap_or(u32::MAX);
+
+        // SAFETY: every header points at one iovec and one name, and every
+        // iovec at one of our buffers, all of which are owned by the batch.
+        // `MSG_WAITFORONE` blocks (up to the socket's read timeout) for the
+        // first datagram only, and a null timeout leaves the rest to that
+        // flag.
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                count,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        let received = usize::try_from(received).map_err(|_| io::Error::last_os_error())?;
+
+        // Close up the gaps left by oversized datagrams
+        let mut kept = 0;
+        for i in 0..received {
+            let header = &self.headers.headers[i];
+            let len = header.msg_len as usize;
+            if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 || len > MAX_DATAGRAM_LEN {
+                self.oversized += 1;
+                continue;
+            }
+            if kept != i {
+                self.buffers.swap(kept, i);
+            }
+            self.lens[kept] = len;
+            self.sources[kept] = socket_addr(&self.headers.names[i]);
+            kept += 1;
+        }
+        Ok(kept)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_many(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.recv_one(socket)
+    }
+}
+
+/// The `recvmmsg` arguments for a batch, each header pointing at one of its
+/// buffers and at somewhere for the sender's address.
+#[cfg(target_os = "linux")]
+struct MessageHeaders {
+    headers: Box<[libc::mmsghdr]>,
+    names: Box<[libc::sockaddr_storage]>,
+    // Only read by the kernel, through `headers`
+    #[allow(dead_code)]
+    iovecs: Box<[libc::iovec]>,
+}
+
+// SAFETY: the raw pointers only point into the boxed slices of the batch
+// that owns them, which don't move when the batch does, and are only used
+// by `recv_many`, which takes the batch mutably.
+#[cfg(target_os = "linux")]
+unsafe impl Send for MessageHeaders {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for MessageHeaders {}
+
+#[cfg(target_os = "linux")]
+impl MessageHeaders {
+    fn new(buffers: &mut [[u8; BUFFER_LEN]]) -> Self {
+        use std::ptr;
+
+        let mut iovecs: Box<[libc::iovec]> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+        // SAFETY: `sockaddr_storage` is a plain C struct for which all zeroes
+        // is a valid (empty) value.
+        let mut names: Box<[libc::sockaddr_storage]> =
+            vec![unsafe { std::mem::zeroed() }; iovecs.len()].into_boxed_slice();
+        let headers = iovecs
+            .iter_mut()
+            .zip(names.iter_mut())
+            .map(|(iovec, name)| {
+                // SAFETY: as above, for `mmsghdr`.
+                let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header.msg_hdr.msg_name = ptr::from_mut(name).cast();
+                header.msg_hdr.msg_namelen = NAME_LEN;
+                header
+            })
+            .collect();
+        MessageHeaders {
+            headers,
+            names,
+            iovecs,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(clippy::cast_possible_truncation)]
+const NAME_LEN: libc::socklen_t = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+/// The address `recvmmsg` filled in, or `None` if it isn't IPv4 or IPv6.
+#[cfg(target_os = "linux")]
+fn socket_addr(name: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::ptr;
+
+    // SAFETY: `sockaddr_storage` is big enough and aligned for every address
+    // type, and `ss_family` says which one the kernel wrote into it.
+    match libc::c_int::from(name.ss_family) {
+        libc::AF_INET => {
+            let addr = unsafe { &*ptr::from_ref(name).cast::<libc::sockaddr_in>() };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*ptr::from_ref(name).cast::<libc::sockaddr_in6>() };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_recv_takes_every_queued_datagram() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        for datagram in [&[1][..], &[2, 2], &[3, 3, 3]] {
+            sender.send(datagram).unwrap();
+        }
+
+        let mut batch = DatagramBatch::new(8);
+        let mut datagrams = Vec::new();
+        while datagrams.len() < 3 {
+            batch.recv(&receiver).unwrap();
+            datagrams.extend(batch.iter().map(<[u8]>::to_vec));
+        }
+        assert_eq!(datagrams, [vec![1], vec![2, 2], vec![3, 3, 3]]);
+
+        sender.send(&[4]).unwrap();
+        batch.recv(&receiver).unwrap();
+        assert_eq!(
+            batch.iter_with_sources().collect::<Vec<_>>(),
+            [(Some(sender.local_addr().unwrap()), &[4][..])]
+        );
+
+        // Datagrams too long to be packets are dropped, not cut short
+        sender.send(&[5; MAX_DATAGRAM_LEN + 1]).unwrap();
+        sender.send(&[6]).unwrap();
+        let mut datagrams = Vec::new();
+        let mut oversized = 0;
+        while datagrams.is_empty() {
+            batch.recv(&receiver).unwrap();
+            oversized += batch.oversized();
+            datagrams.extend(batch.iter().map(<[u8]>::to_vec));
+        }
+        assert_eq!(oversized, 1);
+        assert_eq!(datagrams, [vec![6]]);
+
+        let mut single = DatagramBatch::new(1);
+        sender.send(&[7; MAX_DATAGRAM_LEN + 1]).unwrap();
+        assert_eq!(single.recv(&receiver).unwrap(), 0);
+        assert_eq!(single.oversized(), 1);
+
+        // Nothing left: the read timeout still applies
+        let error = batch.recv(&receiver).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        assert_eq!(batch.iter().count(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+// A native replacement for `tests/lib/Segmented-File-System-server.jar`.
+//
+// Usage: segmented-file-system-server [--port PORT] [--delay-us MICROS]
+//        [--loss PERCENT] [--header-loss PERCENT] [--legacy]
+//        [--key-file PATH] PATH...
+//
+// Each PATH is either a file to serve or a directory whose files are all
+// served. `--loss` and `--header-loss` drop that share of outgoing data and
+// header packets, and `--legacy` ignores the client's extension offers, like
+// the original server. With `--key-file`, or a key in `SFS_KEY`, only clients
+// with the same key are served.
+
+#![warn(clippy::pedantic)]
+#![warn(clippy::style)]
+#![warn(clippy::perf)]
+#![warn(clippy::complexity)]
+#![warn(clippy::correctness)]
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use segmented_file_system_client::packet::auth::PacketKey;
+use segmented_file_system_client::server::{
+    files_in_directory, ServedFile, Server, DEFAULT_SERVER_PORT, MAX_FAILURES_IN_A_ROW,
+};
+use segmented_file_system_client::Features;
+
+const USAGE: &str = "usage: segmented-file-system-server [--port PORT] [--delay-us MICROS] \
+                     [--loss PERCENT] [--header-loss PERCENT] [--legacy] \
+                     [--key-file PATH] PATH...";
+
+fn main() {
+    let mut port = DEFAULT_SERVER_PORT;
+    let mut packet_delay = Duration::from_micros(100);
+    let mut packet_loss = 0;
+    let mut header_loss = 0;
+    let mut features = Features::ALL;
+    let mut key_file = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = parse_value(&arg, args.next().as_deref()),
+            "--delay-us" => {
+                packet_delay = Duration::from_micros(parse_value(&arg, args.next().as_deref()));
+            }
+            "--loss" => packet_loss = parse_percent(&arg, args.next().as_deref()),
+            "--header-loss" => header_loss = parse_percent(&arg, args.next().as_deref()),
+            "--legacy" => features = Features::NONE,
+            "--key-file" => match args.next() {
+                Some(path) => key_file = Some(PathBuf::from(path)),
+                None => exit_with_usage("--key-file needs a path"),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    if paths.is_empty() {
+        exit_with_usage("no files to serve");
+    }
+
+    let mut files = Vec::new();
+    for path in &paths {
+        let loaded = if path.is_dir() {
+            files_in_directory(path)
+        } else {
+            ServedFile::from_path(path).map(|file| vec![file])
+        };
+        match loaded {
+            Ok(loaded) => files.extend(loaded),
+            Err(e) => {
+                eprintln!("Could not read {}: {e}", path.display());
+                process::exit(1);
+            }
+        }
+    }
+
+    let key = match &key_file {
+        Some(path) => PacketKey::from_file(path).map(Some),
+        None => PacketKey::from_env(),
+    };
+    let key = key.unwrap_or_else(|e| {
+        eprintln!("Could not read the key: {e}");
+        process::exit(1);
+    });
+
+    let mut server = match Server::bind(("0.0.0.0", port), files) {
+        Ok(server) => server
+            .with_packet_delay(packet_delay)
+            .with_packet_loss(packet_loss)
+            .with_header_loss(header_loss)
+            .with_features(features)
+            .with_key(key),
+        Err(e) => {
+            eprintln!("Could not serve on port {port}: {e}");
+            process::exit(1);
+        }
+    };
+
+    println!("Serving {} path(s) on port {port}", paths.len());
+    let mut failures = 0;
+    loop {
+        match server.serve_one() {
+            Ok(client) => {
+                println!("Sent files to {client}");
+                failures = 0;
+            }
+            Err(e) => {
+                eprintln!("Transfer failed: {e}");
+                failures += 1;
+                if failures >= MAX_FAILURES_IN_A_ROW {
+                    eprintln!("Giving up after {failures} failed transfers in a row");
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&str>) -> T {
+    match value.map(str::parse) {
+        Some(Ok(value)) => value,
+        _ => exit_with_usage(&format!("{flag} needs a numeric value")),
+    }
+}
+
+fn parse_percent(flag: &str, value: Option<&str>) -> u8 {
+    match parse_value(flag, value) {
+        percent @ 0..=100 => percent,
+        _ => exit_with_usage(&format!("{flag} needs a percentage from 0 to 100")),
+    }
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{message}\n{USAGE}");
+    process::exit(2);
+}
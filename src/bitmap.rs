@@ -0,0 +1,156 @@
+use std::ops::RangeInclusive;
+
+/// One bit per possible packet number, recording which packets of a file
+/// have arrived.
+///
+/// Storage grows with the highest packet number seen, so a small file costs
+/// a few bytes and the largest possible file (65,536 packets) costs 8 KiB.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReceivedBitmap {
+    words: Vec<u64>,
+    count: usize,
+}
+
+impl ReceivedBitmap {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks `packet_number` as received, returning `false` if it already was.
+    pub fn insert(&mut self, packet_number: u16) -> bool {
+        let (word, bit) = Self::position(packet_number);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+
+        let was_set = self.words[word] & bit != 0;
+        self.words[word] |= bit;
+        if !was_set {
+            self.count += 1;
+        }
+        !was_set
+    }
+
+    #[must_use]
+    pub fn contains(&self, packet_number: u16) -> bool {
+        let (word, bit) = Self::position(packet_number);
+        self.words.get(word).is_some_and(|w| w & bit != 0)
+    }
+
+    /// Clears every packet number from `first` upwards, returning the ones
+    /// that had been set.
+    pub fn remove_from(&mut self, first: u16) -> Vec<u16> {
+        let removed: Vec<u16> = (first..=u16::MAX)
+            .take_while(|&packet_number| usize::from(packet_number) / 64 < self.words.len())
+            .filter(|&packet_number| self.contains(packet_number))
+            .collect();
+
+        for &packet_number in &removed {
+            let (word, bit) = Self::position(packet_number);
+            self.words[word] &= !bit;
+        }
+        self.count -= removed.len();
+        removed
+    }
+
+    /// The runs of packet numbers that haven't been received, in order.
+    ///
+    /// With `len`, the file's number of packets, the gaps below it are
+    /// listed. Without it the file's end isn't known yet, so everything
+    /// after the highest packet received is reported as missing too, as a
+    /// final range running to `u16::MAX`.
+    #[must_use]
+    pub fn missing_ranges(&self, len: Option<usize>) -> Vec<RangeInclusive<u16>> {
+        let end = len.unwrap_or(usize::from(u16::MAX) + 1);
+        let mut ranges = Vec::new();
+        let mut gap_start = None;
+
+        for packet_number in (0..=u16::MAX).take_while(|&n| usize::from(n) < end) {
+            match (self.contains(packet_number), gap_start) {
+                (false, None) => gap_start = Some(packet_number),
+                (true, Some(start)) => {
+                    ranges.push(start..=packet_number - 1);
+                    gap_start = None;
+                }
+                _ => {}
+            }
+            // Past the last stored word nothing else can be set
+            if gap_start.is_some() && usize::from(packet_number) / 64 >= self.words.len() {
+                break;
+            }
+        }
+
+        if let Some(start) = gap_start {
+            let last = u16::try_from(end - 1).unwrap_or(u16::MAX);
+            ranges.push(start..=last);
+        }
+        ranges
+    }
+
+    /// How many distinct packet numbers have been received.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    fn position(packet_number: u16) -> (usize, u64) {
+        let packet_number = usize::from(packet_number);
+        (packet_number / 64, 1 << (packet_number % 64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_count() {
+        let mut bitmap = ReceivedBitmap::new();
+        assert!(!bitmap.contains(0));
+
+        assert!(bitmap.insert(0));
+        assert!(bitmap.insert(u16::MAX));
+        assert!(!bitmap.insert(0));
+
+        assert!(bitmap.contains(0));
+        assert!(bitmap.contains(u16::MAX));
+        assert!(!bitmap.contains(1));
+        assert_eq!(bitmap.count(), 2);
+    }
+
+    #[test]
+    fn test_remove_from() {
+        let mut bitmap = ReceivedBitmap::new();
+        for packet_number in [1, 5, 70, 200] {
+            bitmap.insert(packet_number);
+        }
+
+        assert_eq!(bitmap.remove_from(5), [5, 70, 200]);
+        assert_eq!(bitmap.count(), 1);
+        assert!(bitmap.contains(1));
+        assert!(!bitmap.contains(70));
+        assert!(bitmap.remove_from(2).is_empty());
+    }
+
+    #[test]
+    fn test_missing_ranges() {
+        let mut bitmap = ReceivedBitmap::new();
+        assert_eq!(bitmap.missing_ranges(Some(3)), [0..=2]);
+        assert_eq!(bitmap.missing_ranges(None), [0..=u16::MAX]);
+
+        for packet_number in [1, 2, 5, 130] {
+            bitmap.insert(packet_number);
+        }
+        assert_eq!(
+            bitmap.missing_ranges(Some(200)),
+            [0..=0, 3..=4, 6..=129, 131..=199]
+        );
+        assert_eq!(
+            bitmap.missing_ranges(None),
+            [0..=0, 3..=4, 6..=129, 131..=u16::MAX]
+        );
+        assert_eq!(bitmap.missing_ranges(Some(3)), [0..=0]);
+        assert!(bitmap.missing_ranges(Some(0)).is_empty());
+    }
+}
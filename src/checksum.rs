@@ -0,0 +1,59 @@
+use sha2::{Digest as _, Sha256};
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+/// The length of a `Digest`.
+pub const DIGEST_LEN: usize = 32;
+
+/// The SHA-256 digest of a whole file, as sent with the checksum extension.
+pub type Digest = [u8; DIGEST_LEN];
+
+/// How much of a spooled file is read at a time while hashing it.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// The digest of `contents`.
+#[must_use]
+pub fn digest(contents: &[u8]) -> Digest {
+    Sha256::digest(contents).into()
+}
+
+/// The digest of everything in `file`, read from the start without moving
+/// its cursor.
+pub(crate) fn file_digest(file: &File) -> io::Result<Digest> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; READ_CHUNK];
+    let mut offset = 0;
+    loop {
+        let len = file.read_at(&mut buffer, offset)?;
+        if len == 0 {
+            return Ok(hasher.finalize().into());
+        }
+        hasher.update(&buffer[..len]);
+        offset += len as u64;
+    }
+}
+
+/// `digest` as lowercase hex, the way `sha256sum` prints it.
+#[must_use]
+pub fn to_hex(digest: &Digest) -> String {
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        // Writing to a String can't fail
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_matches_sha256sum() {
+        assert_eq!(
+            to_hex(&digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
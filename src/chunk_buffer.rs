@@ -0,0 +1,135 @@
+use crate::bitmap::ReceivedBitmap;
+use crate::packet::MAX_DATA_LEN;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+/// What happened to a chunk handed to `ChunkBuffer::write_chunk` or
+/// `SpoolFile::write_chunk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChunkWrite {
+    Stored,
+    /// The same chunk was already stored.
+    Duplicate,
+    /// A different chunk with this packet number was already stored; the
+    /// new one was not written.
+    Conflict,
+}
+
+/// The in-memory counterpart of `SpoolFile`: a file's chunks laid out in one
+/// buffer, each at `packet_number * MAX_DATA_LEN`, plus a bitmap of which
+/// ones have arrived.
+///
+/// Every chunk but the last is expected to fill `MAX_DATA_LEN` bytes, so once
+/// all of them are in, the buffer is the file and can be written in one go.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ChunkBuffer {
+    data: Vec<u8>,
+    received: ReceivedBitmap,
+}
+
+impl ChunkBuffer {
+    /// Copies one chunk into its place in the buffer.
+    ///
+    /// A repeated packet number is compared against what is already stored
+    /// rather than overwriting it. The last chunk fixes the buffer's length.
+    pub(crate) fn write_chunk(
+        &mut self,
+        packet_number: u16,
+        data: &[u8],
+        is_last: bool,
+    ) -> ChunkWrite {
+        let offset = usize::from(packet_number) * MAX_DATA_LEN;
+        let end = offset + data.len();
+
+        if !self.received.insert(packet_number) {
+            let matches =
+                self.data.get(offset..end) == Some(data) && (!is_last || self.data.len() == end);
+            return if matches {
+                ChunkWrite::Duplicate
+            } else {
+                ChunkWrite::Conflict
+            };
+        }
+
+        if self.data.len() < end || is_last {
+            self.data.resize(end, 0);
+        }
+        self.data[offset..end].copy_from_slice(data);
+
+        ChunkWrite::Stored
+    }
+
+    /// The stored chunk for `packet_number`, if it has arrived.
+    pub(crate) fn get(&self, packet_number: u16) -> Option<&[u8]> {
+        if !self.received.contains(packet_number) {
+            return None;
+        }
+        let offset = usize::from(packet_number) * MAX_DATA_LEN;
+        let end = self.data.len().min(offset + MAX_DATA_LEN);
+        Some(&self.data[offset..end])
+    }
+
+    /// Forgets every chunk from `first` on, returning their packet numbers.
+    pub(crate) fn remove_from(&mut self, first: u16) -> Vec<u16> {
+        self.received.remove_from(first)
+    }
+
+    pub(crate) fn received(&self) -> &ReceivedBitmap {
+        &self.received
+    }
+
+    pub(crate) fn received_packets(&self) -> usize {
+        self.received.count()
+    }
+
+    /// Writes the chunks received so far into `file` at their offsets,
+    /// leaving holes where chunks are missing.
+    pub(crate) fn write_partial(&self, file: &File) -> io::Result<()> {
+        let number_of_chunks = self.data.len().div_ceil(MAX_DATA_LEN);
+        for packet_number in (0..=u16::MAX).take(number_of_chunks) {
+            if let Some(chunk) = self.get(packet_number) {
+                let offset = u64::from(packet_number) * MAX_DATA_LEN as u64;
+                file.write_all_at(chunk, offset)?;
+            }
+        }
+        file.set_len(self.data.len() as u64)
+    }
+
+    /// The whole file, once every chunk has arrived.
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_land_at_their_offsets() {
+        let mut buffer = ChunkBuffer::default();
+        let full = vec![7; MAX_DATA_LEN];
+
+        assert_eq!(buffer.write_chunk(1, &[1, 2], true), ChunkWrite::Stored);
+        assert_eq!(buffer.write_chunk(0, &full, false), ChunkWrite::Stored);
+        assert_eq!(buffer.write_chunk(0, &full, false), ChunkWrite::Duplicate);
+        assert_eq!(buffer.write_chunk(1, &[1, 3], true), ChunkWrite::Conflict);
+
+        assert_eq!(buffer.received_packets(), 2);
+        assert_eq!(buffer.get(1), Some(&[1, 2][..]));
+        assert_eq!(buffer.as_slice().len(), MAX_DATA_LEN + 2);
+        assert_eq!(&buffer.as_slice()[..MAX_DATA_LEN], &full[..]);
+    }
+
+    #[test]
+    fn test_remove_from_forgets_chunks() {
+        let mut buffer = ChunkBuffer::default();
+        buffer.write_chunk(0, &[1], false);
+        buffer.write_chunk(3, &[4], false);
+
+        assert_eq!(buffer.remove_from(1), [3]);
+        assert_eq!(buffer.get(3), None);
+        assert_eq!(buffer.received_packets(), 1);
+    }
+}
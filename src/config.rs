@@ -0,0 +1,201 @@
+use crate::extension::Features;
+use crate::file_manager::{ExistingFilePolicy, FileManager, ReassemblyMode};
+use crate::journal::{Journal, JOURNAL_DIR};
+use crate::limits::Limits;
+use crate::packet::auth::PacketKey;
+use crate::server::DEFAULT_SERVER_PORT;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The port the client binds to unless told otherwise.
+pub const DEFAULT_CLIENT_PORT: u16 = 7077;
+
+/// The OutOfMoney.com server always sends three files.
+pub const DEFAULT_EXPECTED_FILES: usize = 3;
+
+/// How many datagrams to take per system call where batching is supported.
+pub const DEFAULT_RECV_BATCH: usize = 32;
+
+/// How the client decides the server has sent everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Done once this many files have been completely received.
+    FileCount(usize),
+    /// Done once every file seen so far is complete and nothing else has
+    /// arrived for this long, for servers that send an unknown number of files.
+    QuietPeriod(Duration),
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Completion::FileCount(DEFAULT_EXPECTED_FILES)
+    }
+}
+
+/// Which datagrams the client accepts, by the address they came from.
+///
+/// Anything else is dropped before it is parsed, counted in
+/// `PacketStats::stray_datagrams` and reported to `Progress::stray_datagram`,
+/// so other hosts on the network can't inject packets into the transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SourcePolicy {
+    /// Only datagrams from the server address. `ClientConfig::connect`
+    /// connects the socket to it, so the operating system filters as well.
+    #[default]
+    Server,
+    /// Send the hello to the server address, which may be a broadcast
+    /// address, and keep to whichever address answers first: the rest of the
+    /// session, requests included, is with that server alone.
+    FirstResponder,
+    /// Datagrams from anywhere, for servers that answer from a different
+    /// address than the one the hello went to.
+    Any,
+}
+
+/// Where to find the server, where to listen, and where to put the files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    pub server_host: String,
+    pub server_port: u16,
+    pub bind_host: String,
+    /// Port 0 lets the operating system pick an ephemeral port.
+    pub bind_port: u16,
+    pub output_dir: PathBuf,
+    pub completion: Completion,
+    pub reassembly: ReassemblyMode,
+    /// Stop at the first packet that contradicts an earlier one.
+    pub strict: bool,
+    /// The most datagrams to receive per system call; 1 receives them one at
+    /// a time.
+    pub recv_batch: usize,
+    /// How long a single `recv` waits before the client checks its timers.
+    pub read_timeout: Duration,
+    /// How long to wait for the first packet before re-sending the hello.
+    pub hello_interval: Duration,
+    /// Give up if nothing arrives from the server for this long.
+    pub idle_timeout: Duration,
+    /// Protocol extensions to offer the server in the hello. Servers that
+    /// don't know about them just ignore the offer.
+    pub extensions: Features,
+    /// With `Features::NACK` or `Features::RESEND_HEADER` accepted, ask for
+    /// missing packets or headers again after this long without receiving
+    /// any.
+    pub nack_interval: Duration,
+    /// The name pattern for files whose header never arrives, e.g.
+    /// `file-{id}.bin`; see `FileManager::fallback_name`.
+    pub fallback_name: Option<String>,
+    /// With a `fallback_name`, how long to wait without receiving anything
+    /// before giving up on missing headers and writing those files under it.
+    pub header_grace: Duration,
+    /// Keep a journal of received chunks in `output_dir`, and take up where
+    /// an earlier run that was stopped left off.
+    pub resume: bool,
+    /// What to do about a received file whose name is already taken.
+    pub existing_files: ExistingFilePolicy,
+    /// Which addresses packets are accepted from.
+    pub accept_from: SourcePolicy,
+    /// A key shared with the server, to authenticate every datagram of the
+    /// transfer with. The server must have the same key.
+    pub key: Option<PacketKey>,
+    /// Encrypt the transfer, file names included, with keys agreed with the
+    /// server in the hello. With a `key` as well, the key goes into the
+    /// agreement instead of signing each datagram.
+    pub encrypt: bool,
+    /// Caps on how much the server can make the client hold, for a server
+    /// that can't be trusted not to misbehave.
+    pub limits: Limits,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            server_host: String::from("127.0.0.1"),
+            server_port: DEFAULT_SERVER_PORT,
+            bind_host: String::from("0.0.0.0"),
+            bind_port: DEFAULT_CLIENT_PORT,
+            output_dir: PathBuf::from("."),
+            completion: Completion::default(),
+            reassembly: ReassemblyMode::InMemory,
+            strict: false,
+            recv_batch: DEFAULT_RECV_BATCH,
+            read_timeout: Duration::from_millis(250),
+            hello_interval: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(30),
+            extensions: Features::NONE,
+            nack_interval: Duration::from_millis(500),
+            fallback_name: None,
+            header_grace: Duration::from_secs(2),
+            resume: false,
+            existing_files: ExistingFilePolicy::Overwrite,
+            accept_from: SourcePolicy::Server,
+            key: None,
+            encrypt: false,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Binds the local socket and, with `SourcePolicy::Server`, connects it
+    /// to the server. With the other policies the socket is left unconnected
+    /// so it can hear from other addresses, and may send broadcasts.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either address can't be resolved, or binding or
+    /// connecting fails.
+    pub fn connect(&self) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((self.bind_host.as_str(), self.bind_port))?;
+        if self.accept_from == SourcePolicy::Server {
+            socket.connect((self.server_host.as_str(), self.server_port))?;
+        } else {
+            socket.set_broadcast(true)?;
+        }
+        Ok(socket)
+    }
+
+    /// The first address the server host resolves to in the same family as
+    /// `local`, the address the socket is bound to.
+    pub(crate) fn server_addr(&self, local: SocketAddr) -> io::Result<SocketAddr> {
+        let addrs = (self.server_host.as_str(), self.server_port).to_socket_addrs()?;
+        self.pick_server_addr(addrs, local)
+    }
+
+    /// The first of `addrs`, which the server host resolved to, in the same
+    /// family as `local`.
+    pub(crate) fn pick_server_addr(
+        &self,
+        mut addrs: impl Iterator<Item = SocketAddr>,
+        local: SocketAddr,
+    ) -> io::Result<SocketAddr> {
+        addrs
+            .find(|addr| addr.is_ipv4() == local.is_ipv4())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has no address to reach from {local}", self.server_host),
+                )
+            })
+    }
+
+    /// An empty `FileManager` that writes where this configuration says.
+    #[must_use]
+    pub fn file_manager(&self) -> FileManager {
+        let expected_files = match self.completion {
+            Completion::FileCount(count) => Some(count),
+            Completion::QuietPeriod(_) => None,
+        };
+        FileManager::new(self.output_dir.clone(), expected_files)
+            .with_reassembly(self.reassembly)
+            .with_existing_files(self.existing_files)
+            .with_strict(self.strict)
+            .with_fallback_name(self.fallback_name.clone())
+            .with_limits(self.limits)
+            .with_journal(
+                self.resume
+                    .then(|| Journal::new(self.output_dir.join(JOURNAL_DIR))),
+            )
+    }
+}
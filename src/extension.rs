@@ -0,0 +1,446 @@
+use crate::checksum::{Digest, DIGEST_LEN};
+use crate::packet::auth::{Nonce, NONCE_LEN};
+use crate::packet::encryption::{PublicKey, PUBLIC_KEY_LEN};
+use crate::packet::MAX_PACKET_LEN;
+use crate::FileManager;
+use std::ops::{BitAnd, BitOr, RangeInclusive};
+
+/// The first byte of every control message.
+///
+/// Servers that predate the extensions only ever set the two low bits of a
+/// packet's status byte, so a control message can't be mistaken for one of
+/// their packets.
+pub const CONTROL_BYTE: u8 = 0xF0;
+
+const HELLO: u8 = 0;
+const ACCEPT: u8 = 1;
+const NACK: u8 = 2;
+const DONE: u8 = 3;
+const RESEND_HEADER: u8 = 4;
+const CHECKSUM: u8 = 5;
+const RESEND_CHECKSUM: u8 = 6;
+const SIGNED_HELLO: u8 = 7;
+const ENCRYPTED_HELLO: u8 = 8;
+const KEY_EXCHANGE: u8 = 9;
+
+/// The control byte and the message kind.
+const CONTROL_HEADER_LEN: usize = 2;
+/// A file ID and the first and last packet numbers of a range.
+const NACK_RANGE_LEN: usize = 5;
+
+/// The most ranges that fit in a single `ControlMessage::Nack` datagram.
+pub const MAX_NACK_RANGES: usize = (MAX_PACKET_LEN - CONTROL_HEADER_LEN) / NACK_RANGE_LEN;
+
+/// A set of protocol extensions, as offered by a client or accepted by a
+/// server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Features(u8);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// The client may ask for data packets it is missing to be sent again.
+    pub const NACK: Features = Features(0b01);
+    /// The client may ask for the header packets of files whose name it
+    /// hasn't received to be sent again.
+    pub const RESEND_HEADER: Features = Features(0b10);
+    /// The server sends a digest of every file, which the client checks
+    /// before writing the file out.
+    pub const CHECKSUM: Features = Features(0b100);
+    /// Every extension this version of the crate understands.
+    pub const ALL: Features = Features(0b111);
+
+    /// The features set in `bits`, ignoring any this version doesn't know.
+    #[must_use]
+    pub fn from_bits(bits: u8) -> Self {
+        Features(bits & Features::ALL.0)
+    }
+
+    #[must_use]
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    #[must_use]
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[must_use]
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Features;
+
+    fn bitand(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+/// Data packets a client is still missing from one file.
+///
+/// A range ending at `u16::MAX` means the client hasn't seen the file's last
+/// packet yet, and asks for everything up to the end of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingPackets {
+    pub file_id: u8,
+    pub packets: RangeInclusive<u16>,
+}
+
+/// A message that negotiates or uses a protocol extension.
+///
+/// A client only sends extension messages other than `Hello` once the server
+/// has accepted the extension, so a server that knows nothing about them
+/// (and treats every datagram as a hello) never sees one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Client to server: a hello that offers these extensions.
+    Hello(Features),
+    /// Server to client, ahead of the files: the offered extensions the
+    /// server will honour.
+    Accept(Features),
+    /// Client to server: please send these data packets again.
+    Nack(Vec<MissingPackets>),
+    /// Client to server: every file arrived, so no more requests will follow.
+    Done,
+    /// Client to server: please send the header packets of these files again.
+    ResendHeader(Vec<u8>),
+    /// Server to client, after a file's packets: the digest of the whole
+    /// file.
+    Checksum { file_id: u8, digest: Digest },
+    /// Client to server: please send the checksums of these files again.
+    ResendChecksum(Vec<u8>),
+    /// Client to server, with a pre-shared key: a hello that offers these
+    /// extensions and starts a session with this nonce. It is signed like
+    /// every other datagram of the session.
+    SignedHello { offered: Features, nonce: Nonce },
+    /// Client to server, in the clear: a hello that offers these extensions
+    /// and asks for an encrypted transfer, with the client's half of the key
+    /// exchange.
+    EncryptedHello {
+        offered: Features,
+        public_key: PublicKey,
+    },
+    /// Server to client, in the clear, answering an encrypted hello: the
+    /// server's half of the key exchange. Everything after it, both ways, is
+    /// encrypted.
+    KeyExchange { public_key: PublicKey },
+}
+
+impl ControlMessage {
+    /// Parses a control message, returning `None` for anything else,
+    /// including ordinary packets.
+    #[must_use]
+    pub fn parse(datagram: &[u8]) -> Option<Self> {
+        let (&[CONTROL_BYTE, kind], body) = datagram.split_first_chunk()? else {
+            return None;
+        };
+
+        match (kind, body) {
+            (HELLO, &[bits]) => Some(ControlMessage::Hello(Features::from_bits(bits))),
+            (ACCEPT, &[bits]) => Some(ControlMessage::Accept(Features::from_bits(bits))),
+            (NACK, _) if !body.is_empty() && body.len() % NACK_RANGE_LEN == 0 => {
+                let ranges = body
+                    .chunks_exact(NACK_RANGE_LEN)
+                    .map(|range| MissingPackets {
+                        file_id: range[0],
+                        packets: u16::from_be_bytes([range[1], range[2]])
+                            ..=u16::from_be_bytes([range[3], range[4]]),
+                    })
+                    .collect();
+                Some(ControlMessage::Nack(ranges))
+            }
+            (DONE, &[]) => Some(ControlMessage::Done),
+            (RESEND_HEADER, _) if !body.is_empty() => {
+                Some(ControlMessage::ResendHeader(body.to_vec()))
+            }
+            (CHECKSUM, &[file_id, ref digest @ ..]) => Some(ControlMessage::Checksum {
+                file_id,
+                digest: digest.try_into().ok()?,
+            }),
+            (RESEND_CHECKSUM, _) if !body.is_empty() => {
+                Some(ControlMessage::ResendChecksum(body.to_vec()))
+            }
+            (SIGNED_HELLO, &[bits, ref nonce @ ..]) => Some(ControlMessage::SignedHello {
+                offered: Features::from_bits(bits),
+                nonce: nonce.try_into().ok()?,
+            }),
+            (ENCRYPTED_HELLO, &[bits, ref public_key @ ..]) => {
+                Some(ControlMessage::EncryptedHello {
+                    offered: Features::from_bits(bits),
+                    public_key: public_key.try_into().ok()?,
+                })
+            }
+            (KEY_EXCHANGE, public_key) => Some(ControlMessage::KeyExchange {
+                public_key: public_key.try_into().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes the message as a datagram.
+    ///
+    /// A `Nack` with more than `MAX_NACK_RANGES` ranges won't fit in one
+    /// datagram; use `ControlMessage::nacks` to split them up.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ControlMessage::Hello(features) => vec![CONTROL_BYTE, HELLO, features.bits()],
+            ControlMessage::Accept(features) => vec![CONTROL_BYTE, ACCEPT, features.bits()],
+            ControlMessage::Nack(ranges) => {
+                let mut bytes =
+                    Vec::with_capacity(CONTROL_HEADER_LEN + ranges.len() * NACK_RANGE_LEN);
+                bytes.extend([CONTROL_BYTE, NACK]);
+                for range in ranges {
+                    bytes.push(range.file_id);
+                    bytes.extend(range.packets.start().to_be_bytes());
+                    bytes.extend(range.packets.end().to_be_bytes());
+                }
+                bytes
+            }
+            ControlMessage::Done => vec![CONTROL_BYTE, DONE],
+            ControlMessage::ResendHeader(file_ids) => {
+                let mut bytes = vec![CONTROL_BYTE, RESEND_HEADER];
+                bytes.extend(file_ids);
+                bytes
+            }
+            ControlMessage::Checksum { file_id, digest } => {
+                let mut bytes = Vec::with_capacity(CONTROL_HEADER_LEN + 1 + DIGEST_LEN);
+                bytes.extend([CONTROL_BYTE, CHECKSUM, *file_id]);
+                bytes.extend(digest);
+                bytes
+            }
+            ControlMessage::ResendChecksum(file_ids) => {
+                let mut bytes = vec![CONTROL_BYTE, RESEND_CHECKSUM];
+                bytes.extend(file_ids);
+                bytes
+            }
+            ControlMessage::SignedHello { offered, nonce } => {
+                let mut bytes = Vec::with_capacity(CONTROL_HEADER_LEN + 1 + NONCE_LEN);
+                bytes.extend([CONTROL_BYTE, SIGNED_HELLO, offered.bits()]);
+                bytes.extend(nonce);
+                bytes
+            }
+            ControlMessage::EncryptedHello {
+                offered,
+                public_key,
+            } => {
+                let mut bytes = Vec::with_capacity(CONTROL_HEADER_LEN + 1 + PUBLIC_KEY_LEN);
+                bytes.extend([CONTROL_BYTE, ENCRYPTED_HELLO, offered.bits()]);
+                bytes.extend(public_key);
+                bytes
+            }
+            ControlMessage::KeyExchange { public_key } => {
+                let mut bytes = Vec::with_capacity(CONTROL_HEADER_LEN + PUBLIC_KEY_LEN);
+                bytes.extend([CONTROL_BYTE, KEY_EXCHANGE]);
+                bytes.extend(public_key);
+                bytes
+            }
+        }
+    }
+
+    /// As many `Nack` messages as it takes to ask for all of `missing`.
+    #[must_use]
+    pub fn nacks(missing: &[MissingPackets]) -> Vec<ControlMessage> {
+        missing
+            .chunks(MAX_NACK_RANGES)
+            .map(|ranges| ControlMessage::Nack(ranges.to_vec()))
+            .collect()
+    }
+}
+
+/// The client's side of the negotiation: what it offered in its hello and
+/// what the server accepted.
+pub(crate) struct Negotiation {
+    offered: Features,
+    accepted: Features,
+}
+
+impl Negotiation {
+    pub(crate) fn new(offered: Features) -> Self {
+        Negotiation {
+            offered,
+            accepted: Features::NONE,
+        }
+    }
+
+    /// The hello to send for an unprotected transfer: the original one-byte
+    /// hello if there is nothing to offer, so the client behaves exactly as
+    /// it always has.
+    pub(crate) fn hello(&self) -> Vec<u8> {
+        if self.offered.is_empty() {
+            crate::receive::HELLO.to_vec()
+        } else {
+            ControlMessage::Hello(self.offered).to_bytes()
+        }
+    }
+
+    pub(crate) fn offered(&self) -> Features {
+        self.offered
+    }
+
+    pub(crate) fn accepted(&self) -> Features {
+        self.accepted
+    }
+
+    /// Takes in `datagram` if it is a control message from the server,
+    /// returning it for whatever else it means to the transfer. Nothing is
+    /// treated as a control message unless extensions were offered.
+    pub(crate) fn handle(&mut self, datagram: &[u8]) -> Option<ControlMessage> {
+        if self.offered.is_empty() {
+            return None;
+        }
+
+        let message = ControlMessage::parse(datagram)?;
+        if let ControlMessage::Accept(features) = message {
+            self.accepted = features & self.offered;
+        }
+        Some(message)
+    }
+
+    /// Whether `datagram` is meant as a control message, even if `handle`
+    /// can't make sense of it: a kind from a newer server, or a message cut
+    /// short. Once extensions were offered, no packet starts with
+    /// `CONTROL_BYTE`, so such a datagram must not be parsed as one.
+    pub(crate) fn is_control_message(&self, datagram: &[u8]) -> bool {
+        !self.offered.is_empty() && datagram.first() == Some(&CONTROL_BYTE)
+    }
+
+    /// The requests for every packet and header `file_manager` is still
+    /// missing, as far as the server accepted such requests.
+    pub(crate) fn requests(&self, file_manager: &FileManager) -> Vec<Vec<u8>> {
+        let mut requests = Vec::new();
+        if self.accepted.contains(Features::NACK) {
+            requests.extend(ControlMessage::nacks(&file_manager.missing_packets()));
+        }
+        if self.accepted.contains(Features::RESEND_HEADER) {
+            let unnamed = file_manager.unnamed_files();
+            if !unnamed.is_empty() {
+                requests.push(ControlMessage::ResendHeader(unnamed));
+            }
+        }
+        if self.accepted.contains(Features::CHECKSUM) {
+            let unverified = file_manager.awaiting_checksum();
+            if !unverified.is_empty() {
+                requests.push(ControlMessage::ResendChecksum(unverified));
+            }
+        }
+        requests.iter().map(ControlMessage::to_bytes).collect()
+    }
+
+    /// Tells the server it can stop waiting for requests, if it was waiting.
+    pub(crate) fn done(&self) -> Option<Vec<u8>> {
+        (!self.accepted.is_empty()).then(|| ControlMessage::Done.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_messages_round_trip() {
+        let messages = [
+            ControlMessage::Hello(Features::NACK),
+            ControlMessage::Accept(Features::NONE),
+            ControlMessage::Nack(vec![
+                MissingPackets {
+                    file_id: 3,
+                    packets: 0..=4,
+                },
+                MissingPackets {
+                    file_id: 200,
+                    packets: 300..=u16::MAX,
+                },
+            ]),
+            ControlMessage::Done,
+            ControlMessage::ResendHeader(vec![0, 9, 255]),
+            ControlMessage::Checksum {
+                file_id: 7,
+                digest: crate::checksum::digest(b"seven"),
+            },
+            ControlMessage::ResendChecksum(vec![7]),
+            ControlMessage::SignedHello {
+                offered: Features::ALL,
+                nonce: [9; NONCE_LEN],
+            },
+            ControlMessage::EncryptedHello {
+                offered: Features::NACK,
+                public_key: [8; PUBLIC_KEY_LEN],
+            },
+            ControlMessage::KeyExchange {
+                public_key: [7; PUBLIC_KEY_LEN],
+            },
+        ];
+        for message in messages {
+            assert_eq!(ControlMessage::parse(&message.to_bytes()), Some(message));
+        }
+    }
+
+    #[test]
+    fn test_packets_are_not_control_messages() {
+        // A header packet, a data packet and the original hello
+        for datagram in [&[0, 1, b'a'][..], &[3, 1, 0, 0, 7], &[0]] {
+            assert_eq!(ControlMessage::parse(datagram), None);
+        }
+        // A truncated NACK
+        assert_eq!(ControlMessage::parse(&[CONTROL_BYTE, NACK, 1, 0]), None);
+        assert_eq!(ControlMessage::parse(&[CONTROL_BYTE, RESEND_HEADER]), None);
+        assert_eq!(
+            ControlMessage::parse(&[CONTROL_BYTE, CHECKSUM, 1, 2, 3]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_nacks_fit_in_a_datagram() {
+        let missing: Vec<MissingPackets> = (0..=u16::from(u8::MAX))
+            .map(|n| MissingPackets {
+                file_id: 0,
+                packets: n..=n,
+            })
+            .collect();
+        let nacks = ControlMessage::nacks(&missing);
+
+        assert_eq!(nacks.len(), 2);
+        assert!(nacks
+            .iter()
+            .all(|nack| nack.to_bytes().len() <= MAX_PACKET_LEN));
+    }
+
+    #[test]
+    fn test_negotiation_only_uses_what_was_offered() {
+        let mut legacy = Negotiation::new(Features::NONE);
+        assert_eq!(legacy.hello(), [0]);
+        // Without an offer, even a well-formed accept is just a header packet
+        assert!(legacy
+            .handle(&ControlMessage::Accept(Features::NACK).to_bytes())
+            .is_none());
+
+        let mut negotiation = Negotiation::new(Features::NACK);
+        assert!(negotiation.done().is_none());
+        assert!(negotiation
+            .handle(&ControlMessage::Accept(Features::ALL).to_bytes())
+            .is_some());
+        assert_eq!(negotiation.accepted(), Features::NACK);
+        assert!(negotiation.done().is_some());
+
+        // Unknown or truncated control messages are still control messages
+        for datagram in [&[CONTROL_BYTE, 42][..], &[CONTROL_BYTE, ACCEPT]] {
+            assert!(negotiation.handle(datagram).is_none());
+            assert!(negotiation.is_control_message(datagram));
+            assert!(!legacy.is_control_message(datagram));
+        }
+        assert!(!negotiation.is_control_message(&[0, 1, b'a']));
+    }
+}
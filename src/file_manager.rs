@@ -1,124 +1,931 @@
-use crate::packet::{data_packet::DataPacket, header_packet::HeaderPacket, Packet};
-use crate::PacketGroup;
-use std::collections::HashMap;
-use std::fs::File;
+use crate::anomaly::{PacketAnomaly, PacketStats};
+use crate::checksum::{self, Digest};
+use crate::chunk_buffer::{ChunkBuffer, ChunkWrite};
+use crate::extension::MissingPackets;
+use crate::file_name::{confined_path, fallback_file_name, DEFAULT_FALLBACK_NAME};
+use crate::journal::Journal;
+use crate::limits::{LimitExceeded, Limits};
+use crate::packet::{
+    data_packet::DataPacketRef, header_packet::HeaderPacketRef, Packet, PacketParseError,
+    PacketRef, MAX_DATA_LEN,
+};
+use crate::packet_groups::PacketGroups;
+use crate::publish::{publish_with, Published};
+use crate::report::{FileReport, TransferReport};
+use crate::spool::SpoolFile;
+use crate::{ClientError, PacketGroup};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
+/// Hands each `FileManager` its own `spool_tag`.
+static NEXT_SPOOL_TAG: AtomicU64 = AtomicU64::new(0);
+
+/// Where a file's chunks are kept until the whole file has arrived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReassemblyMode {
+    /// Every chunk is held in memory and the file is written in one go.
+    #[default]
+    InMemory,
+    /// Chunks are written straight into a sparse temporary file in the
+    /// output directory, so memory use stays small however big the file is.
+    Streaming,
+}
+
+/// What to do when a finished file's name is already taken in the output
+/// directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    /// Replace the existing file, in one step once the new one is complete.
+    #[default]
+    Overwrite,
+    /// Keep the existing file and drop the new one.
+    Skip,
+    /// Write the new file as `name-1.ext`, `name-2.ext`, ... instead.
+    Rename,
+    /// Stop with `ClientError::FileExists`.
+    Fail,
+}
+
+/// Something the `FileManager` did in response to a packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileEvent {
+    /// Every packet of a file (and its name) arrived, so it was written to
+    /// `path` and its buffers were freed.
+    Completed {
+        file_id: u8,
+        path: PathBuf,
+        bytes: u64,
+    },
+    /// Every data packet of a file arrived but its header never did, so it
+    /// was written under its fallback name instead.
+    CompletedUnnamed {
+        file_id: u8,
+        path: PathBuf,
+        bytes: u64,
+    },
+    /// A file was complete, but `path` already existed and
+    /// `ExistingFilePolicy::Skip` kept it, so the file was dropped.
+    Skipped { file_id: u8, path: PathBuf },
+}
+
+/// Collects packets into per-file `PacketGroup`s and writes out the
+/// reassembled files.
 pub struct FileManager {
-    pub packet_groups: Vec<PacketGroup>,
+    pub packet_groups: PacketGroups,
+    /// Directory the reassembled files are written into.
+    pub output_dir: PathBuf,
+    /// How many files the transfer contains, if known. Without it the
+    /// transfer counts as done once every file seen so far is complete.
+    pub expected_files: Option<usize>,
+    pub reassembly: ReassemblyMode,
+    /// What to do about a file that is already in `output_dir`.
+    pub existing_files: ExistingFilePolicy,
+    /// Treat every `PacketAnomaly` as an error instead of dropping the packet.
+    pub strict: bool,
+    /// Hold every file back until its digest has arrived, as the receive
+    /// loop arranges whenever it offers `Features::CHECKSUM`, until the
+    /// server declines it (see `stop_requiring_checksums`). Files with a
+    /// digest are checked against it either way.
+    pub require_checksums: bool,
+    pub stats: PacketStats,
+    /// Anomalies found since the last call to `take_anomalies`.
+    pub anomalies: Vec<PacketAnomaly>,
+    /// The name pattern for files whose header never arrives, with `{id}`
+    /// standing for the file ID (see `fallback_file_name`). Without one
+    /// such files are never written.
+    pub fallback_name: Option<String>,
+    /// Where received chunks are recorded so a later run can resume the
+    /// transfer; see `Journal`.
+    pub journal: Option<Journal>,
+    /// Caps on how much the server can make us hold, checked for every packet.
+    pub limits: Limits,
+    /// When the transfer started, which `Limits::max_duration` counts from:
+    /// when the hello was sent, or for a `FileManager` fed by hand, when the
+    /// first packet was processed.
+    pub started: Option<Instant>,
+    /// Goes into the names of this `FileManager`'s spool files, so that
+    /// transfers running at once in one process don't share them even in
+    /// the same output directory. Every `FileManager` gets a different one.
+    pub spool_tag: u64,
+}
+
+/// A file that was still missing packets when the transfer stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncompleteFile {
+    pub file_id: u8,
+    pub file_name: Option<OsString>,
+    pub expected_number_of_packets: Option<usize>,
+    pub received_packets: usize,
+}
+
+impl fmt::Display for IncompleteFile {
+    /// The same line as the file's `FileReport`, without the missing ranges.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        FileReport {
+            file_id: self.file_id,
+            file_name: self.file_name.clone(),
+            expected_number_of_packets: self.expected_number_of_packets,
+            received_packets: self.received_packets,
+            missing: Vec::new(),
+            written: false,
+            failed: false,
+        }
+        .fmt(f)
+    }
 }
 
 impl Default for FileManager {
     fn default() -> Self {
         Self {
-            packet_groups: Vec::new(),
+            packet_groups: PacketGroups::new(),
+            output_dir: PathBuf::from("."),
+            expected_files: None,
+            reassembly: ReassemblyMode::InMemory,
+            existing_files: ExistingFilePolicy::Overwrite,
+            strict: false,
+            require_checksums: false,
+            stats: PacketStats::default(),
+            anomalies: Vec::new(),
+            fallback_name: None,
+            journal: None,
+            limits: Limits::default(),
+            started: None,
+            spool_tag: NEXT_SPOOL_TAG.fetch_add(1, Ordering::Relaxed),
         }
     }
 }
 
 impl FileManager {
+    #[must_use]
+    pub fn new(output_dir: impl Into<PathBuf>, expected_files: Option<usize>) -> Self {
+        Self {
+            output_dir: output_dir.into(),
+            expected_files,
+            ..Self::default()
+        }
+    }
+
+    /// Switches how chunks are stored until a file is complete.
+    #[must_use]
+    pub fn with_reassembly(mut self, reassembly: ReassemblyMode) -> Self {
+        self.reassembly = reassembly;
+        self
+    }
+
+    /// Switches what happens to files whose name is already taken.
+    #[must_use]
+    pub fn with_existing_files(mut self, existing_files: ExistingFilePolicy) -> Self {
+        self.existing_files = existing_files;
+        self
+    }
+
+    /// Fails on the first `PacketAnomaly` instead of dropping the packet.
+    #[must_use]
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Names files whose header never arrives using `pattern`, e.g.
+    /// `file-{id}.bin`.
+    #[must_use]
+    pub fn with_fallback_name(mut self, pattern: Option<String>) -> Self {
+        self.fallback_name = pattern;
+        self
+    }
+
+    /// Records every chunk in `journal`, and takes up what earlier runs
+    /// recorded there.
+    #[must_use]
+    pub fn with_journal(mut self, journal: Option<Journal>) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Stops the transfer with `ClientError::LimitExceeded` when a packet
+    /// breaks one of `limits`.
+    #[must_use]
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Hands over the anomalies found since the last call.
+    pub fn take_anomalies(&mut self) -> Vec<PacketAnomaly> {
+        mem::take(&mut self.anomalies)
+    }
+
+    #[must_use]
     pub fn received_all_packets(&self) -> bool {
         if self.packet_groups.is_empty() {
             return false;
         }
 
-        for packet_group in &self.packet_groups {
-            if packet_group.expected_number_of_packets.is_none() || 
-               packet_group.expected_number_of_packets != Some(packet_group.packets.len()) {
+        if let Some(expected_files) = self.expected_files {
+            if self.packet_groups.len() < expected_files {
                 return false;
             }
         }
 
-        true
+        self.packet_groups.all_complete()
+    }
+
+    /// Every file that is still missing its name or any of its packets.
+    #[must_use]
+    pub fn incomplete_files(&self) -> Vec<IncompleteFile> {
+        self.packet_groups
+            .iter()
+            .filter(|packet_group| !packet_group.written && !packet_group.failed)
+            .map(|packet_group| IncompleteFile {
+                file_id: packet_group.file_id,
+                file_name: packet_group.file_name.clone(),
+                expected_number_of_packets: packet_group.expected_number_of_packets,
+                received_packets: packet_group.received_packets(),
+            })
+            .collect()
+    }
+
+    /// What has been received of every file seen so far, including which
+    /// packets are missing.
+    #[must_use]
+    pub fn report(&self) -> TransferReport {
+        TransferReport {
+            files: self
+                .packet_groups
+                .iter()
+                .map(|packet_group| FileReport {
+                    file_id: packet_group.file_id,
+                    file_name: packet_group.file_name.clone(),
+                    expected_number_of_packets: packet_group.expected_number_of_packets,
+                    received_packets: packet_group.received_packets(),
+                    missing: packet_group.missing_ranges(),
+                    written: packet_group.written,
+                    failed: packet_group.failed,
+                })
+                .collect(),
+        }
+    }
+
+    /// The IDs of the files whose header hasn't arrived, e.g. to ask the
+    /// server to send it again.
+    #[must_use]
+    pub fn unnamed_files(&self) -> Vec<u8> {
+        self.packet_groups
+            .iter()
+            .filter(|packet_group| {
+                !packet_group.written && !packet_group.failed && packet_group.file_name.is_none()
+            })
+            .map(|packet_group| packet_group.file_id)
+            .collect()
+    }
+
+    /// The IDs of the files that have every data packet but are still
+    /// waiting for their digest, e.g. to ask the server to send it again.
+    #[must_use]
+    pub fn awaiting_checksum(&self) -> Vec<u8> {
+        self.packet_groups
+            .iter()
+            .filter(|packet_group| {
+                !packet_group.written
+                    && packet_group.needs_digest
+                    && packet_group.digest.is_none()
+                    && packet_group.has_all_packets()
+            })
+            .map(|packet_group| packet_group.file_id)
+            .collect()
+    }
+
+    /// Stops holding files back for digests that aren't coming, once the
+    /// server has declined `Features::CHECKSUM`, and writes the files that
+    /// were only waiting for theirs.
+    ///
+    /// # Errors
+    ///
+    /// The same as `process_packet`, for the files it writes.
+    pub fn stop_requiring_checksums(&mut self) -> Result<Vec<FileEvent>, ClientError> {
+        self.require_checksums = false;
+        let held: Vec<u8> = self
+            .packet_groups
+            .iter()
+            .filter(|packet_group| packet_group.needs_digest && packet_group.digest.is_none())
+            .map(|packet_group| packet_group.file_id)
+            .collect();
+
+        let mut events = Vec::new();
+        for file_id in held {
+            self.packet_groups
+                .update(file_id, |packet_group| packet_group.needs_digest = false);
+            events.extend(self.write_if_complete(file_id)?);
+        }
+        Ok(events)
+    }
+
+    /// The data packets still missing from every file seen so far, e.g. to
+    /// ask the server to send them again.
+    #[must_use]
+    pub fn missing_packets(&self) -> Vec<MissingPackets> {
+        self.packet_groups
+            .iter()
+            .flat_map(|packet_group| {
+                packet_group
+                    .missing_ranges()
+                    .into_iter()
+                    .map(|packets| MissingPackets {
+                        file_id: packet_group.file_id,
+                        packets,
+                    })
+            })
+            .collect()
     }
 
-    pub fn process_packet(&mut self, packet: Packet) {
+    /// Adds `packet` to its file, writing the file out as soon as it is
+    /// complete.
+    ///
+    /// Packets that contradict earlier ones are dropped and recorded for
+    /// `take_anomalies`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ClientError::PacketParseError` for a data packet carrying
+    /// more than `MAX_DATA_LEN` bytes, which would spill into the next
+    /// packet's place in the file, and stores none of it.
+    ///
+    /// Returns an error if the completed file can't be written, including
+    /// `ClientError::InvalidFileName` if its name could escape `output_dir`
+    /// and `ClientError::FileExists` if the name is taken under
+    /// `ExistingFilePolicy::Fail`. In strict mode, returns
+    /// `ClientError::PacketAnomaly` for a packet that contradicts an earlier
+    /// one. Returns `ClientError::LimitExceeded` for a packet that breaks one
+    /// of `limits`, without storing the packet.
+    // Takes the packet by value so callers can keep handing packets over as
+    // they always have
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn process_packet(&mut self, packet: Packet) -> Result<Option<FileEvent>, ClientError> {
+        self.process_packet_ref(packet.as_ref())
+    }
+
+    /// Like `process_packet`, but for a packet borrowed from the receive
+    /// buffer. Only the data that has to be kept is copied.
+    ///
+    /// # Errors
+    ///
+    /// The same as `process_packet`.
+    pub fn process_packet_ref(
+        &mut self,
+        packet: PacketRef<'_>,
+    ) -> Result<Option<FileEvent>, ClientError> {
+        if let PacketRef::DataPacket(data_packet) = packet {
+            if data_packet.data.len() > MAX_DATA_LEN {
+                return Err(PacketParseError::InvalidDataPacket.into());
+            }
+        }
+        self.check_limits(packet)?;
+        self.stats.packets += 1;
+        let anomalies_before = self.anomalies.len();
+
         match packet {
-            Packet::HeaderPacket(header_packet) => self.process_header_packet(header_packet),
-            Packet::DataPacket(data_packet) => self.process_data_packet(data_packet),
+            PacketRef::HeaderPacket(header_packet) => {
+                self.process_header_packet(header_packet);
+                self.resume_from_journal(header_packet.file_id)?;
+            }
+            PacketRef::DataPacket(data_packet) => self.process_data_packet(data_packet)?,
+        }
+
+        let new_anomalies = self.anomalies.len() - anomalies_before;
+        self.stats.anomalies += new_anomalies;
+        if self.strict && new_anomalies > 0 {
+            return Err(ClientError::PacketAnomaly(
+                self.anomalies.remove(anomalies_before),
+            ));
         }
+
+        self.write_if_complete(packet.file_id())
     }
 
-    pub fn process_header_packet(&mut self, header_packet: HeaderPacket) {
-        let file_id = header_packet.file_id;
-        let file_name = header_packet.file_name;
+    /// Starts the clock `Limits::max_duration` counts from, unless it is
+    /// already running.
+    pub(crate) fn start_clock(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+    }
 
-        // Check if we already have a packet group for this file ID
-        for packet_group in &mut self.packet_groups {
-            if packet_group.file_id == file_id {
-                packet_group.file_name = Some(file_name);
-                return;
+    /// Fails once the transfer has gone on for longer than
+    /// `Limits::max_duration`, so that it stops even while nothing arrives.
+    pub(crate) fn check_duration(&self) -> Result<(), LimitExceeded> {
+        let elapsed = self.started.map(|started| started.elapsed());
+        match (self.limits.max_duration, elapsed) {
+            (Some(limit), Some(elapsed)) if elapsed > limit => {
+                Err(LimitExceeded::Duration { limit })
             }
+            _ => Ok(()),
         }
+    }
 
-        // If not, create a new packet group
-        let packet_group = PacketGroup {
-            file_name: Some(file_name),
-            file_id,
-            expected_number_of_packets: None,
-            packets: HashMap::new(),
-        };
-        self.packet_groups.push(packet_group);
+    /// Checks `packet` against every limit before it is stored. Duplicates
+    /// don't count towards the total size, since they aren't stored again.
+    fn check_limits(&mut self, packet: PacketRef<'_>) -> Result<(), LimitExceeded> {
+        let limits = self.limits;
+        self.start_clock();
+        self.check_duration()?;
+
+        let file_id = packet.file_id();
+        if let Some(limit) = limits.max_files {
+            if self.packet_groups.get(file_id).is_none()
+                && self.packet_groups.in_progress() >= limit
+            {
+                return Err(LimitExceeded::Files { file_id, limit });
+            }
+        }
+
+        match packet {
+            PacketRef::HeaderPacket(header_packet) => {
+                let len = header_packet.file_name.len();
+                if let Some(limit) = limits.max_name_len.filter(|&limit| len > limit) {
+                    return Err(LimitExceeded::NameLength {
+                        file_id,
+                        len,
+                        limit,
+                    });
+                }
+            }
+            PacketRef::DataPacket(data_packet) => {
+                let end = u64::from(data_packet.packet_number) * MAX_DATA_LEN as u64
+                    + data_packet.data.len() as u64;
+                if let Some(limit) = limits.max_file_bytes.filter(|&limit| end > limit) {
+                    return Err(LimitExceeded::FileBytes { file_id, limit });
+                }
+
+                let total = self.stats.stored_bytes + data_packet.data.len() as u64;
+                if let Some(limit) = limits.max_total_bytes.filter(|&limit| total > limit) {
+                    if !self.is_stored(data_packet) {
+                        return Err(LimitExceeded::TotalBytes { limit });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a chunk with the same number as `data_packet` is already on
+    /// hand, or its file no longer takes any, so storing it adds nothing.
+    fn is_stored(&self, data_packet: DataPacketRef<'_>) -> bool {
+        self.packet_groups
+            .get(data_packet.file_id)
+            .is_some_and(|packet_group| {
+                let received = match &packet_group.spool {
+                    Some(spool) => spool.received(),
+                    None => packet_group.chunks.received(),
+                };
+                packet_group.written
+                    || packet_group.failed
+                    || received.contains(data_packet.packet_number)
+            })
+    }
+
+    /// Records the digest the server sent for `file_id`, writing the file out
+    /// if it was only waiting for that.
+    ///
+    /// A digest that arrives after its file was written can no longer be
+    /// checked and is dropped, as is one for a file no packet has arrived
+    /// for yet: holding on to it would count as a file in progress that
+    /// may never come. Such a file asks for its digest again once its data
+    /// is in (see `awaiting_checksum`).
+    ///
+    /// # Errors
+    ///
+    /// The same as `process_packet`, including
+    /// `ClientError::ChecksumMismatch` if the file doesn't match `digest`.
+    pub fn process_checksum(
+        &mut self,
+        file_id: u8,
+        digest: Digest,
+    ) -> Result<Option<FileEvent>, ClientError> {
+        if self.packet_groups.get(file_id).is_none() {
+            return Ok(None);
+        }
+        let anomalies_before = self.anomalies.len();
+
+        self.packet_groups
+            .update(file_id, |packet_group| match packet_group.digest {
+                None => packet_group.digest = Some(digest),
+                Some(existing) if existing == digest => self.stats.duplicate_packets += 1,
+                Some(_) => self
+                    .anomalies
+                    .push(PacketAnomaly::ConflictingChecksum { file_id }),
+            });
+
+        let new_anomalies = self.anomalies.len() - anomalies_before;
+        self.stats.anomalies += new_anomalies;
+        if self.strict && new_anomalies > 0 {
+            return Err(ClientError::PacketAnomaly(
+                self.anomalies.remove(anomalies_before),
+            ));
+        }
+
+        self.write_if_complete(file_id)
+    }
+
+    /// Records the file name in the file's packet group.
+    pub fn process_header_packet(&mut self, header_packet: HeaderPacketRef<'_>) {
+        let file_id = header_packet.file_id;
+        let file_name = header_packet.file_name;
+
+        self.packet_groups
+            .update(file_id, |packet_group| match &packet_group.file_name {
+                None => packet_group.file_name = Some(file_name.to_os_string()),
+                Some(existing) if existing == file_name => self.stats.duplicate_packets += 1,
+                Some(existing) => self.anomalies.push(PacketAnomaly::ConflictingFileName {
+                    file_id,
+                    file_name: existing.clone(),
+                    rejected_name: file_name.to_os_string(),
+                }),
+            });
     }
 
-    pub fn process_data_packet(&mut self, data_packet: DataPacket) {
+    /// Stores the packet's data in the file's packet group.
+    ///
+    /// # Errors
+    ///
+    /// In streaming mode, or with a `journal`, returns an error if the chunk
+    /// can't be written to disk.
+    pub fn process_data_packet(
+        &mut self,
+        data_packet: DataPacketRef<'_>,
+    ) -> Result<(), ClientError> {
         let file_id = data_packet.file_id;
         let is_last_data_packet = data_packet.is_last_data_packet();
         let packet_number = data_packet.packet_number;
 
-        // Try to find an existing packet group
-        for packet_group in &mut self.packet_groups {
-            if packet_group.file_id == file_id {
-                // If this is the last packet, update the expected number of packets
-                if is_last_data_packet {
-                    packet_group.expected_number_of_packets = Some(packet_number as usize + 1);
+        self.packet_groups.update(file_id, |packet_group| {
+            // Check the packet against the last packet we already know about
+            if let Some(expected_number_of_packets) = packet_group.expected_number_of_packets {
+                let anomaly = if is_last_data_packet
+                    && packet_number as usize + 1 != expected_number_of_packets
+                {
+                    Some(PacketAnomaly::ConflictingLastPacket {
+                        file_id,
+                        expected_number_of_packets,
+                        packet_number,
+                    })
+                } else if packet_number as usize >= expected_number_of_packets {
+                    Some(PacketAnomaly::BeyondLastPacket {
+                        file_id,
+                        expected_number_of_packets,
+                        packet_number,
+                    })
+                } else {
+                    None
+                };
+                if let Some(anomaly) = anomaly {
+                    self.anomalies.push(anomaly);
+                    return Ok(());
                 }
-                
-                packet_group.packets.insert(packet_number, data_packet.data);
-                return;
             }
-        }
 
-        // If no packet group exists, create a new one
-        let mut packets = HashMap::new();
-        packets.insert(packet_number, data_packet.data);
-        
-        let expected_packets = if is_last_data_packet {
-            Some(packet_number as usize + 1)
-        } else {
-            None
-        };
-        
-        let packet_group = PacketGroup {
-            file_name: None,
-            file_id,
-            expected_number_of_packets: expected_packets,
-            packets,
-        };
-        
-        self.packet_groups.push(packet_group);
-    }
-
-    pub fn write_all_files(&self) -> io::Result<()> {
-        for packet_group in &self.packet_groups {
-            if let Some(file_name) = &packet_group.file_name {
-                if let Some(expected_packets) = packet_group.expected_number_of_packets {
-                    if packet_group.packets.len() == expected_packets {
-                        // Write the file if all packets received
-                        let mut file = File::create(Path::new(&file_name))?;
-                        
-                        // Write the packets in order by packet number
-                        for packet_num in 0..expected_packets {
-                            if let Some(data) = packet_group.packets.get(&(packet_num as u16)) {
-                                file.write_all(data)?;
-                            }
-                        }
+            // Late duplicates of a file that is already on disk, or was given
+            // up on, are dropped
+            if packet_group.written || packet_group.failed {
+                self.stats.duplicate_packets += 1;
+                return Ok(());
+            }
+
+            // If this is the last packet, update the expected number of packets,
+            // dropping anything we already stored past it
+            if is_last_data_packet {
+                let expected_number_of_packets = packet_number as usize + 1;
+                packet_group.expected_number_of_packets = Some(expected_number_of_packets);
+
+                let first_beyond = packet_number.saturating_add(1);
+                let beyond = match &mut packet_group.spool {
+                    Some(spool) => spool.remove_from(first_beyond),
+                    None => packet_group.chunks.remove_from(first_beyond),
+                };
+                for stored in beyond {
+                    self.anomalies.push(PacketAnomaly::BeyondLastPacket {
+                        file_id,
+                        expected_number_of_packets,
+                        packet_number: stored,
+                    });
+                }
+            }
+
+            let outcome = match self.reassembly {
+                ReassemblyMode::InMemory => packet_group.chunks.write_chunk(
+                    packet_number,
+                    data_packet.data,
+                    is_last_data_packet,
+                ),
+                ReassemblyMode::Streaming => {
+                    let spool = match &mut packet_group.spool {
+                        Some(spool) => spool,
+                        None => packet_group.spool.insert(SpoolFile::create(
+                            &self.output_dir,
+                            self.spool_tag,
+                            file_id,
+                        )?),
+                    };
+                    spool.write_chunk(packet_number, data_packet.data, is_last_data_packet)?
+                }
+            };
+
+            match outcome {
+                ChunkWrite::Stored => {
+                    self.stats.stored_bytes += data_packet.data.len() as u64;
+                    if let Some(journal) = &mut self.journal {
+                        journal.record_chunk(&data_packet)?;
                     }
                 }
+                ChunkWrite::Duplicate => self.stats.duplicate_packets += 1,
+                ChunkWrite::Conflict => self.anomalies.push(PacketAnomaly::ConflictingPayload {
+                    file_id,
+                    packet_number,
+                }),
             }
+
+            Ok(())
+        })
+    }
+
+    /// Once `file_id` has a name, stores the chunks earlier runs recorded in
+    /// the journal under that name, then removes their logs. The chunks are
+    /// held to the same `limits` as packets from the server.
+    fn resume_from_journal(&mut self, file_id: u8) -> Result<(), ClientError> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+        let Some(file_name) = self
+            .packet_groups
+            .get(file_id)
+            .filter(|packet_group| !packet_group.is_written())
+            .and_then(|packet_group| packet_group.file_name())
+        else {
+            return Ok(());
+        };
+        let Some(saved) = journal.record_name(file_id, file_name)? else {
+            return Ok(());
+        };
+
+        for chunk in &saved.chunks {
+            self.check_limits(PacketRef::DataPacket(chunk.as_ref()))?;
+            self.process_data_packet(chunk.as_ref())?;
         }
-        
+        saved.remove_logs()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Writes every complete file that hasn't been written yet into
+    /// `output_dir`, along with any file that has all its data but no name
+    /// if there is a `fallback_name`.
+    ///
+    /// Files are normally written by `process_packet` as soon as they are
+    /// complete, so this only has work to do for groups built by hand or
+    /// whose header never arrived.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any file can't be created or written. If a name is
+    /// rejected the remaining files are still written, and the first rejected
+    /// name is reported as `ClientError::InvalidFileName`.
+    pub fn write_all_files(&mut self) -> Result<(), ClientError> {
+        let mut rejected = None;
+
+        let file_ids: Vec<u8> = self.packet_groups.file_ids().collect();
+        for file_id in file_ids {
+            match self.write_group(file_id, true) {
+                Ok(_) => {}
+                Err(e @ ClientError::InvalidFileName { .. }) => {
+                    rejected.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        rejected.map_or(Ok(()), Err)
+    }
+
+    /// Writes every file that has all its data packets but no name under its
+    /// fallback name, for when the header is presumed lost. Does nothing
+    /// without a `fallback_name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file can't be created or written, including
+    /// `ClientError::InvalidFileName` if the fallback name is unusable.
+    pub fn write_unnamed_files(&mut self) -> Result<Vec<FileEvent>, ClientError> {
+        let mut events = Vec::new();
+        if self.fallback_name.is_none() {
+            return Ok(events);
+        }
+
+        for file_id in self.unnamed_files() {
+            events.extend(self.write_group(file_id, true)?);
+        }
+        Ok(events)
+    }
+
+    /// Writes out every file that is still incomplete, for when the transfer
+    /// is being abandoned, returning the paths written.
+    ///
+    /// Each file gets what has arrived so far at the right offsets, with
+    /// holes where packets are missing, and a `.missing` sidecar listing the
+    /// missing packet ranges one per line. A file whose name never arrived
+    /// gets its fallback name, or `DEFAULT_FALLBACK_NAME` if there is none.
+    /// Files with no data at all are skipped. Existing files are dealt with
+    /// as `existing_files` says, and a file that isn't written because of it
+    /// gets no sidecar.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file can't be written, including
+    /// `ClientError::InvalidFileName` if its name could escape `output_dir`.
+    pub fn write_partial_files(&mut self) -> Result<Vec<PathBuf>, ClientError> {
+        let mut paths = Vec::new();
+        let report = self.report();
+        let pattern = self
+            .fallback_name
+            .as_deref()
+            .unwrap_or(DEFAULT_FALLBACK_NAME);
+
+        for file in report.files {
+            if file.written
+                || file.received_packets == 0
+                || self.packet_groups[file.file_id].is_failed()
+            {
+                continue;
+            }
+            let file_id = file.file_id;
+            let file_name = file
+                .file_name
+                .clone()
+                .unwrap_or_else(|| fallback_file_name(pattern, file_id));
+            let path = confined_path(&self.output_dir, &file_name).map_err(|reason| {
+                ClientError::InvalidFileName {
+                    file_id,
+                    file_name,
+                    reason,
+                }
+            })?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let existing_files = self.existing_files;
+            let published = self
+                .packet_groups
+                .update(file_id, |packet_group| match packet_group.spool.take() {
+                    Some(spool) => spool
+                        .publish(&path, existing_files)
+                        .map(|(published, _)| published),
+                    None => publish_with(&path, existing_files, |file| {
+                        packet_group.chunks.write_partial(file)
+                    }),
+                })
+                .map_err(|e| publish_error(e, file_id, &path))?;
+            let Published::At(path) = published else {
+                continue;
+            };
+
+            let mut sidecar = path.clone().into_os_string();
+            sidecar.push(".missing");
+            let mut missing = file.missing_ranges_text("\n");
+            if !missing.is_empty() {
+                missing.push('\n');
+            }
+            fs::write(&sidecar, missing)?;
+
+            paths.push(path);
+            paths.push(PathBuf::from(sidecar));
+        }
+
+        Ok(paths)
+    }
+
+    /// Writes the group for `file_id` if it has its name and every packet,
+    /// then frees its buffers.
+    fn write_if_complete(&mut self, file_id: u8) -> Result<Option<FileEvent>, ClientError> {
+        self.write_group(file_id, false)
+    }
+
+    /// Writes the group for `file_id` if it has every packet and a name,
+    /// which may be its fallback name if `use_fallback` is set, then frees
+    /// its buffers.
+    fn write_group(
+        &mut self,
+        file_id: u8,
+        use_fallback: bool,
+    ) -> Result<Option<FileEvent>, ClientError> {
+        let output_dir = &self.output_dir;
+        let existing_files = self.existing_files;
+        let require_checksums = self.require_checksums;
+        let fallback_name = self.fallback_name.as_deref().filter(|_| use_fallback);
+        let event = self
+            .packet_groups
+            .update(file_id, |packet_group| -> Result<_, ClientError> {
+                packet_group.needs_digest |= require_checksums;
+                if packet_group.written
+                    || packet_group.failed
+                    || !packet_group.has_all_packets()
+                    || (packet_group.needs_digest && packet_group.digest.is_none())
+                {
+                    return Ok(None);
+                }
+                let (file_name, named) = match (&packet_group.file_name, fallback_name) {
+                    (Some(file_name), _) => (file_name.clone(), true),
+                    (None, Some(pattern)) => (fallback_file_name(pattern, file_id), false),
+                    (None, None) => return Ok(None),
+                };
+
+                if let Some(expected) = packet_group.digest {
+                    let actual = match &packet_group.spool {
+                        Some(spool) => spool.digest()?,
+                        None => checksum::digest(packet_group.chunks.as_slice()),
+                    };
+                    if actual != expected {
+                        give_up(packet_group);
+                        return Err(ClientError::ChecksumMismatch {
+                            file_id,
+                            file_name,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+
+                let path = confined_path(output_dir, &file_name).map_err(|reason| {
+                    give_up(packet_group);
+                    ClientError::InvalidFileName {
+                        file_id,
+                        file_name: file_name.clone(),
+                        reason,
+                    }
+                })?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                let publication = if let Some(spool) = packet_group.spool.take() {
+                    spool.publish(&path, existing_files)
+                } else {
+                    let contents = packet_group.chunks.as_slice();
+                    publish_with(&path, existing_files, |mut file| file.write_all(contents))
+                        .map(|published| (published, contents.len() as u64))
+                };
+                let (published, bytes) =
+                    publication.map_err(|e| publish_error(e, file_id, &path))?;
+                packet_group.chunks = ChunkBuffer::default();
+                packet_group.written = true;
+
+                Ok(Some(match published {
+                    Published::Skipped => FileEvent::Skipped { file_id, path },
+                    Published::At(path) if named => FileEvent::Completed {
+                        file_id,
+                        path,
+                        bytes,
+                    },
+                    Published::At(path) => FileEvent::CompletedUnnamed {
+                        file_id,
+                        path,
+                        bytes,
+                    },
+                }))
+            });
+
+        // The chunks of a file that was given up on are no use to a later run
+        let finished = match &event {
+            Ok(event) => event.is_some(),
+            Err(ClientError::ChecksumMismatch { .. } | ClientError::InvalidFileName { .. }) => true,
+            Err(_) => false,
+        };
+        if let (true, Some(journal)) = (finished, &mut self.journal) {
+            journal.finish(file_id)?;
+        }
+        event
+    }
+}
+
+/// Frees the buffers of a file that can't be written, so that it no longer
+/// holds up the transfer and its later duplicates are dropped.
+fn give_up(packet_group: &mut PacketGroup) {
+    packet_group.chunks = ChunkBuffer::default();
+    packet_group.spool = None;
+    packet_group.failed = true;
+}
+
+/// The error for a file that couldn't be published at `path`, which is
+/// `ClientError::FileExists` if something else is already there. Any other
+/// `AlreadyExists`, e.g. for a temporary file, is passed on as it is.
+fn publish_error(e: io::Error, file_id: u8, path: &Path) -> ClientError {
+    if e.kind() == io::ErrorKind::AlreadyExists && fs::symlink_metadata(path).is_ok() {
+        ClientError::FileExists {
+            file_id,
+            path: path.to_path_buf(),
+        }
+    } else {
+        e.into()
+    }
+}
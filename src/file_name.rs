@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Why a file name from a header packet can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileNameError {
+    /// The name is empty, or only made of `.` components.
+    Empty,
+    /// The name contains a NUL byte, which no file system path can hold.
+    ContainsNul,
+    /// The name is an absolute path.
+    Absolute,
+    /// The name has a `..` component that could climb out of the output directory.
+    ParentDirectory,
+}
+
+impl fmt::Display for FileNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            FileNameError::Empty => "file name is empty",
+            FileNameError::ContainsNul => "file name contains a NUL byte",
+            FileNameError::Absolute => "file name is an absolute path",
+            FileNameError::ParentDirectory => "file name contains a `..` component",
+        };
+        f.write_str(message)
+    }
+}
+
+impl Error for FileNameError {}
+
+/// Stands for the file ID in a fallback name pattern.
+pub const FILE_ID_PLACEHOLDER: &str = "{id}";
+
+/// A fallback name pattern that keeps files apart by ID.
+pub const DEFAULT_FALLBACK_NAME: &str = "file-{id}.bin";
+
+/// The name for file `file_id` if its header never arrives: `pattern` with
+/// every `{id}` replaced by the file ID.
+///
+/// The result goes through `confined_path` like any other name.
+#[must_use]
+pub fn fallback_file_name(pattern: &str, file_id: u8) -> OsString {
+    OsString::from(pattern.replace(FILE_ID_PLACEHOLDER, &file_id.to_string()))
+}
+
+/// Turns a file name sent by the server into a path inside `output_dir`.
+///
+/// Relative names with subdirectories are allowed, and `.` components are
+/// dropped, but anything that could point outside `output_dir` is rejected.
+///
+/// # Errors
+///
+/// Returns a `FileNameError` describing why the name was rejected.
+pub fn confined_path(output_dir: &Path, file_name: &OsStr) -> Result<PathBuf, FileNameError> {
+    if file_name.as_bytes().contains(&0) {
+        return Err(FileNameError::ContainsNul);
+    }
+
+    let mut relative = PathBuf::new();
+    for component in Path::new(file_name).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(FileNameError::ParentDirectory),
+            Component::RootDir | Component::Prefix(_) => return Err(FileNameError::Absolute),
+        }
+    }
+
+    if relative.as_os_str().is_empty() {
+        return Err(FileNameError::Empty);
+    }
+
+    Ok(output_dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn confined(file_name: &str) -> Result<PathBuf, FileNameError> {
+        confined_path(Path::new("out"), OsStr::new(file_name))
+    }
+
+    #[test]
+    fn test_plain_and_nested_names_stay_inside() {
+        assert_eq!(confined("small.txt"), Ok(PathBuf::from("out/small.txt")));
+        assert_eq!(confined("./a/./b.bin"), Ok(PathBuf::from("out/a/b.bin")));
+    }
+
+    #[test]
+    fn test_escaping_names_are_rejected() {
+        assert_eq!(
+            confined("../../etc/passwd"),
+            Err(FileNameError::ParentDirectory)
+        );
+        assert_eq!(confined("a/../../b"), Err(FileNameError::ParentDirectory));
+        assert_eq!(confined("/etc/passwd"), Err(FileNameError::Absolute));
+        assert_eq!(confined(""), Err(FileNameError::Empty));
+        assert_eq!(confined("./"), Err(FileNameError::Empty));
+        assert_eq!(confined("bad\0name"), Err(FileNameError::ContainsNul));
+    }
+
+    #[test]
+    fn test_fallback_file_name() {
+        assert_eq!(fallback_file_name(DEFAULT_FALLBACK_NAME, 7), "file-7.bin");
+        assert_eq!(fallback_file_name("{id}/{id}.dat", 255), "255/255.dat");
+        assert_eq!(fallback_file_name("lost", 1), "lost");
+    }
+}
@@ -0,0 +1,364 @@
+use crate::packet::data_packet::{DataPacket, DataPacketRef};
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory, inside the output directory, that holds the journal.
+pub const JOURNAL_DIR: &str = ".sfs-journal";
+
+/// Starts every log, so stray files in the journal directory are ignored.
+const MAGIC: &[u8] = b"SFSJ\x01";
+
+const NAME_RECORD: u8 = 0;
+const CHUNK_RECORD: u8 = 1;
+
+/// An on-disk record of every chunk received, so a client that is killed can
+/// pick up where it left off instead of starting the transfer again.
+///
+/// Each run appends to one log per file ID: the file's name once its header
+/// arrives, and every chunk as it is stored. File IDs can change between
+/// server sessions, so a later run matches logs to files by name: when a
+/// header arrives, the chunks saved under that name are handed back to be
+/// stored again, and the old logs are removed once they have been. A log
+/// whose file never got a name can't be matched to anything and is removed
+/// when the journal is loaded.
+///
+/// Each run holds a lock on its logs until it is done with them, and a
+/// locked log belongs to a run that is still going, perhaps another client
+/// writing to the same output directory: it is left alone rather than
+/// replayed or removed.
+///
+/// Records are written with one `write` each but not synced, so the journal
+/// survives the client being killed but not necessarily the machine going
+/// down. A record cut short is ignored.
+pub struct Journal {
+    dir: PathBuf,
+    /// Prefixes this run's logs, keeping them apart from earlier runs'.
+    session: String,
+    /// Logs from earlier runs by file name, read on first use.
+    saved: Option<HashMap<OsString, Vec<PathBuf>>>,
+    /// This run's logs by file ID.
+    logs: HashMap<u8, Log>,
+}
+
+struct Log {
+    file: File,
+    path: PathBuf,
+    named: bool,
+}
+
+/// Chunks saved by an earlier run, and the logs they came from.
+pub(crate) struct SavedFile {
+    pub(crate) chunks: Vec<DataPacket>,
+    pub(crate) logs: Vec<PathBuf>,
+}
+
+impl SavedFile {
+    /// Removes the logs once their chunks have been stored again.
+    pub(crate) fn remove_logs(&self) -> io::Result<()> {
+        for path in &self.logs {
+            remove_if_exists(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Journal {
+    /// A journal kept in `dir`, which is created when the first record is
+    /// written. Nothing is read until then either.
+    #[must_use]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Journal {
+            dir: dir.into(),
+            session: format!("{}-{}", started.as_nanos(), process::id()),
+            saved: None,
+            logs: HashMap::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Records that `chunk` was stored.
+    pub(crate) fn record_chunk(&mut self, chunk: &DataPacketRef<'_>) -> io::Result<()> {
+        let mut record = Vec::with_capacity(6 + chunk.data.len());
+        record.extend([CHUNK_RECORD, chunk.status_byte]);
+        record.extend(chunk.packet_number.to_be_bytes());
+        record.extend(record_len(chunk.data)?.to_be_bytes());
+        record.extend(chunk.data);
+        self.log(chunk.file_id)?.file.write_all(&record)
+    }
+
+    /// Records the name of `file_id`, returning what earlier runs saved under
+    /// that name. Returns `None` if the name was already recorded.
+    pub(crate) fn record_name(
+        &mut self,
+        file_id: u8,
+        file_name: &OsStr,
+    ) -> io::Result<Option<SavedFile>> {
+        let log = self.log(file_id)?;
+        if log.named {
+            return Ok(None);
+        }
+        let name = file_name.as_bytes();
+        let mut record = Vec::with_capacity(3 + name.len());
+        record.push(NAME_RECORD);
+        record.extend(record_len(name)?.to_be_bytes());
+        record.extend(name);
+        log.file.write_all(&record)?;
+        log.named = true;
+
+        let Some(logs) = self.saved()?.remove(file_name) else {
+            return Ok(None);
+        };
+        let mut chunks = Vec::new();
+        for path in &logs {
+            let contents = read_log(path)?;
+            chunks.extend(contents.chunks.into_iter().map(|mut chunk| {
+                chunk.file_id = file_id;
+                chunk
+            }));
+        }
+        Ok(Some(SavedFile { chunks, logs }))
+    }
+
+    /// Removes this run's log for `file_id` now that the file is on disk,
+    /// and the journal directory with it if nothing else is left in it.
+    pub(crate) fn finish(&mut self, file_id: u8) -> io::Result<()> {
+        if let Some(log) = self.logs.remove(&file_id) {
+            drop(log.file);
+            remove_if_exists(&log.path)?;
+        }
+        if self.logs.is_empty() && self.saved.as_ref().is_some_and(HashMap::is_empty) {
+            // Fails harmlessly if anything else is still in there
+            let _ = fs::remove_dir(&self.dir);
+        }
+        Ok(())
+    }
+
+    /// This run's log for `file_id`, created if needed.
+    fn log(&mut self, file_id: u8) -> io::Result<&mut Log> {
+        self.saved()?;
+        match self.logs.entry(file_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                fs::create_dir_all(&self.dir)?;
+                // Locked before it gets its final name, so no other run
+                // ever sees it unlocked
+                let path = self.dir.join(format!("{}-{file_id}.log", self.session));
+                let new_path = path.with_extension("new");
+                let mut file = OpenOptions::new()
+                    .append(true)
+                    .create_new(true)
+                    .open(&new_path)?;
+                // Where locks aren't supported, other runs can't tell this
+                // log is in use, and leave unnamed logs alone
+                let _ = file.try_lock();
+                file.write_all(MAGIC)?;
+                fs::rename(&new_path, &path)?;
+                Ok(entry.insert(Log {
+                    file,
+                    path,
+                    named: false,
+                }))
+            }
+        }
+    }
+
+    /// The logs earlier runs left behind, by file name, read the first time
+    /// they are needed.
+    fn saved(&mut self) -> io::Result<&mut HashMap<OsString, Vec<PathBuf>>> {
+        if self.saved.is_none() {
+            self.saved = Some(load(&self.dir)?);
+        }
+        Ok(self.saved.get_or_insert_with(HashMap::new))
+    }
+}
+
+/// Reads the names of the logs in `dir` that no other run is using,
+/// removing those without one.
+fn load(dir: &Path) -> io::Result<HashMap<OsString, Vec<PathBuf>>> {
+    let mut saved: HashMap<OsString, Vec<PathBuf>> = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(saved),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("log")) {
+            continue;
+        }
+        // Held until the log has been dealt with
+        let file = File::open(&path)?;
+        let stale = match file.try_lock() {
+            Ok(()) => true,
+            Err(TryLockError::WouldBlock) => continue,
+            // Without locks a log in use can't be told from a stale one
+            Err(TryLockError::Error(_)) => false,
+        };
+        match read_log(&path)?.file_name {
+            Some(file_name) => saved.entry(file_name).or_default().push(path),
+            None if stale => remove_if_exists(&path)?,
+            None => {}
+        }
+    }
+
+    // Oldest first, so chunks are stored again in the order they arrived
+    for logs in saved.values_mut() {
+        logs.sort();
+    }
+    Ok(saved)
+}
+
+struct LogContents {
+    file_name: Option<OsString>,
+    chunks: Vec<DataPacket>,
+}
+
+/// Reads every complete record in the log at `path`. A file that isn't a
+/// log reads as empty.
+fn read_log(path: &Path) -> io::Result<LogContents> {
+    let mut contents = LogContents {
+        file_name: None,
+        chunks: Vec::new(),
+    };
+    let bytes = fs::read(path)?;
+    let Some(mut records) = bytes.strip_prefix(MAGIC) else {
+        return Ok(contents);
+    };
+
+    loop {
+        match records {
+            [NAME_RECORD, len_hi, len_lo, rest @ ..] => {
+                let len = usize::from(u16::from_be_bytes([*len_hi, *len_lo]));
+                let Some((name, rest)) = rest.split_at_checked(len) else {
+                    break;
+                };
+                contents
+                    .file_name
+                    .get_or_insert_with(|| OsString::from_vec(name.to_vec()));
+                records = rest;
+            }
+            [CHUNK_RECORD, status_byte, n_hi, n_lo, len_hi, len_lo, rest @ ..] => {
+                let len = usize::from(u16::from_be_bytes([*len_hi, *len_lo]));
+                let Some((data, rest)) = rest.split_at_checked(len) else {
+                    break;
+                };
+                contents.chunks.push(DataPacket {
+                    status_byte: *status_byte,
+                    file_id: 0,
+                    packet_number: u16::from_be_bytes([*n_hi, *n_lo]),
+                    data: data.to_vec(),
+                });
+                records = rest;
+            }
+            // The end of the log, or a record cut short when the client was killed
+            _ => break,
+        }
+    }
+    Ok(contents)
+}
+
+fn record_len(bytes: &[u8]) -> io::Result<u16> {
+    u16::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "journal record too long"))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(file_id: u8, packet_number: u16, data: &[u8], is_last: bool) -> DataPacket {
+        DataPacket::new(file_id, packet_number, data.to_vec(), is_last)
+    }
+
+    #[test]
+    fn test_saved_chunks_are_found_by_name() {
+        let dir = std::env::temp_dir().join(format!("sfs-journal-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut first_run = Journal::new(&dir);
+        first_run
+            .record_chunk(&chunk(4, 1, &[2], true).as_ref())
+            .unwrap();
+        assert!(first_run
+            .record_name(4, OsStr::new("a.txt"))
+            .unwrap()
+            .is_none());
+        first_run
+            .record_chunk(&chunk(7, 0, &[9], false).as_ref())
+            .unwrap();
+        drop(first_run);
+        // A record cut short by the client being killed
+        let log = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| read_log(path).unwrap().file_name.is_some())
+            .unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(&[CHUNK_RECORD, 1, 0, 0, 0, 9, 1])
+            .unwrap();
+
+        let mut second_run = Journal::new(&dir);
+        let saved = second_run
+            .record_name(0, OsStr::new("a.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.chunks, [chunk(0, 1, &[2], true)]);
+        // File 7 never got a name, so its log is gone
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        saved.remove_logs().unwrap();
+        second_run.finish(0).unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_logs_in_use_are_left_alone() {
+        let dir = std::env::temp_dir().join(format!("sfs-journal-in-use-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut running = Journal::new(&dir);
+        running
+            .record_chunk(&chunk(3, 0, &[1], false).as_ref())
+            .unwrap();
+        running.record_name(5, OsStr::new("b.txt")).unwrap();
+
+        // Another client starting in the same directory neither removes the
+        // unnamed log nor takes over the named one
+        let mut other = Journal::new(&dir);
+        assert!(other.record_name(0, OsStr::new("b.txt")).unwrap().is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        drop(other);
+
+        // Once the first client is gone its unnamed log is stale
+        drop(running);
+        let mut later = Journal::new(&dir);
+        assert!(later.record_name(0, OsStr::new("b.txt")).unwrap().is_some());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1610 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::style)]
+#![warn(clippy::perf)]
+#![warn(clippy::complexity)]
+#![warn(clippy::correctness)]
+
+//! The reusable half of the segmented file system client: packet parsing,
+//! reassembly of packets into files, and the receive loop that drives them.
+
+pub mod anomaly;
+#[cfg(feature = "async")]
+pub mod async_receive;
+pub mod batch;
+mod bitmap;
+pub mod checksum;
+mod chunk_buffer;
+pub mod config;
+pub mod extension;
+pub mod file_manager;
+pub mod file_name;
+pub mod journal;
+pub mod limits;
+pub mod packet;
+pub mod packet_groups;
+mod publish;
+pub mod receive;
+pub mod report;
+pub mod server;
+mod spool;
+
+use std::{
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt,
+    ops::RangeInclusive,
+    path::PathBuf,
+    time::Duration,
+};
+
+pub use anomaly::{PacketAnomaly, PacketStats};
+pub use batch::DatagramBatch;
+pub use bitmap::ReceivedBitmap;
+pub use config::{ClientConfig, Completion, SourcePolicy};
+pub use extension::{Features, MissingPackets};
+pub use file_manager::{
+    ExistingFilePolicy, FileEvent, FileManager, IncompleteFile, ReassemblyMode,
+};
+pub use file_name::FileNameError;
+pub use journal::Journal;
+pub use limits::{LimitExceeded, Limits};
+pub use packet_groups::PacketGroups;
+pub use receive::{receive_files, Progress};
+pub use report::{FileReport, TransferReport};
+
+/// Everything received so far for a single file ID.
+pub struct PacketGroup {
+    file_name: Option<OsString>,
+    file_id: u8,
+    expected_number_of_packets: Option<usize>,
+    /// In memory mode, the chunks received so far.
+    chunks: chunk_buffer::ChunkBuffer,
+    /// In streaming mode, the on-disk file the chunks are written into.
+    spool: Option<spool::SpoolFile>,
+    /// Set once the file is on disk and `chunks` has been freed.
+    written: bool,
+    /// The digest the server sent for the file, with the checksum extension.
+    digest: Option<checksum::Digest>,
+    /// Whether the file has to wait for its digest before it is written.
+    needs_digest: bool,
+    /// Set once the file was found unfit to write, because it failed its
+    /// checksum or its name was rejected, and its buffers have been freed.
+    failed: bool,
+}
+
+impl PacketGroup {
+    #[must_use]
+    pub fn file_id(&self) -> u8 {
+        self.file_id
+    }
+
+    /// The file name, once the header packet has arrived.
+    #[must_use]
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.file_name.as_deref()
+    }
+
+    /// The number of data packets in the file, once the last packet has arrived.
+    #[must_use]
+    pub fn expected_number_of_packets(&self) -> Option<usize> {
+        self.expected_number_of_packets
+    }
+
+    #[must_use]
+    pub fn received_packets(&self) -> usize {
+        if self.written || self.failed {
+            self.expected_number_of_packets.unwrap_or_default()
+        } else if let Some(spool) = &self.spool {
+            spool.received_packets()
+        } else {
+            self.chunks.received_packets()
+        }
+    }
+
+    /// The runs of data packets still to arrive, as `ReceivedBitmap::missing_ranges`
+    /// reports them: until the last packet has arrived, the final range runs
+    /// to `u16::MAX`.
+    #[must_use]
+    pub fn missing_ranges(&self) -> Vec<RangeInclusive<u16>> {
+        if self.written || self.failed {
+            return Vec::new();
+        }
+        let received = match &self.spool {
+            Some(spool) => spool.received(),
+            None => self.chunks.received(),
+        };
+        received.missing_ranges(self.expected_number_of_packets)
+    }
+
+    /// Whether the file has been written to disk.
+    #[must_use]
+    pub fn is_written(&self) -> bool {
+        self.written
+    }
+
+    /// Whether the file had every packet but couldn't be written, because it
+    /// failed its checksum or its name was rejected.
+    #[must_use]
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Whether every data packet has arrived, whether or not the name has.
+    #[must_use]
+    pub fn has_all_packets(&self) -> bool {
+        self.written
+            || self.failed
+            || self.expected_number_of_packets == Some(self.received_packets())
+    }
+
+    /// The digest the server sent for the file, if any.
+    #[must_use]
+    pub fn digest(&self) -> Option<&checksum::Digest> {
+        self.digest.as_ref()
+    }
+
+    /// Whether the file has been written or has failed, or has its name,
+    /// every data packet and any digest it is waiting for so it can be.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.written
+            || self.failed
+            || (self.file_name.is_some()
+                && self.has_all_packets()
+                && (self.digest.is_some() || !self.needs_digest))
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    IoError(std::io::Error),
+    PacketParseError(packet::PacketParseError),
+    /// Nothing arrived from the server for `idle_timeout`; `incomplete`
+    /// lists the files that were still missing packets.
+    Timeout {
+        idle_timeout: Duration,
+        incomplete: Vec<IncompleteFile>,
+    },
+    /// A packet contradicted an earlier one while running in strict mode.
+    PacketAnomaly(PacketAnomaly),
+    /// The server sent a file name that could escape the output directory.
+    InvalidFileName {
+        file_id: u8,
+        file_name: OsString,
+        reason: FileNameError,
+    },
+    /// A finished file's name was already taken in the output directory,
+    /// under `ExistingFilePolicy::Fail`.
+    FileExists {
+        file_id: u8,
+        path: PathBuf,
+    },
+    /// A reassembled file didn't match the digest the server sent for it,
+    /// so it wasn't written.
+    ChecksumMismatch {
+        file_id: u8,
+        file_name: OsString,
+        expected: checksum::Digest,
+        actual: checksum::Digest,
+    },
+    /// A packet broke one of the `FileManager`'s `Limits`.
+    LimitExceeded(LimitExceeded),
+    /// Nothing arrived for `idle_timeout` while the files in `file_ids` had
+    /// every packet but were still held back for their checksums, which the
+    /// server never accepted sending or which were lost. They weren't
+    /// written.
+    MissingChecksums {
+        idle_timeout: Duration,
+        file_ids: Vec<u8>,
+    },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::IoError(e) => write!(f, "I/O error: {e}"),
+            ClientError::PacketParseError(e) => write!(f, "could not parse packet: {e}"),
+            ClientError::Timeout {
+                idle_timeout,
+                incomplete,
+            } => {
+                write!(
+                    f,
+                    "no packets received for {:.1}s",
+                    idle_timeout.as_secs_f64()
+                )?;
+                if incomplete.is_empty() {
+                    return write!(f, " before any file data arrived");
+                }
+                write!(f, "; incomplete files:")?;
+                for file in incomplete {
+                    write!(f, "\n  {file}")?;
+                }
+                Ok(())
+            }
+            ClientError::PacketAnomaly(anomaly) => write!(f, "inconsistent packet: {anomaly}"),
+            ClientError::InvalidFileName {
+                file_id,
+                file_name,
+                reason,
+            } => write!(
+                f,
+                "refusing to write file {file_id} as {:?}: {reason}",
+                file_name.to_string_lossy()
+            ),
+            ClientError::FileExists { file_id, path } => write!(
+                f,
+                "not writing file {file_id}: {} already exists",
+                path.display()
+            ),
+            ClientError::ChecksumMismatch {
+                file_id,
+                file_name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "file {file_id} ({}) is corrupt: the server's SHA-256 is {} but the \
+                 received file's is {}",
+                file_name.to_string_lossy(),
+                checksum::to_hex(expected),
+                checksum::to_hex(actual)
+            ),
+            ClientError::LimitExceeded(limit) => write!(f, "resource limit exceeded: {limit}"),
+            ClientError::MissingChecksums {
+                idle_timeout,
+                file_ids,
+            } => {
+                write!(
+                    f,
+                    "no checksum arrived for {:.1}s, so these files weren't written unverified:",
+                    idle_timeout.as_secs_f64()
+                )?;
+                for file_id in file_ids {
+                    write!(f, " {file_id}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::IoError(e) => Some(e),
+            ClientError::PacketParseError(e) => Some(e),
+            ClientError::PacketAnomaly(anomaly) => Some(anomaly),
+            ClientError::InvalidFileName { reason, .. } => Some(reason),
+            ClientError::LimitExceeded(limit) => Some(limit),
+            ClientError::Timeout { .. }
+            | ClientError::FileExists { .. }
+            | ClientError::ChecksumMismatch { .. }
+            | ClientError::MissingChecksums { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::IoError(e)
+    }
+}
+
+impl From<LimitExceeded> for ClientError {
+    fn from(e: LimitExceeded) -> Self {
+        ClientError::LimitExceeded(e)
+    }
+}
+
+impl From<packet::PacketParseError> for ClientError {
+    fn from(e: packet::PacketParseError) -> Self {
+        Self::PacketParseError(e)
+    }
+}
+
+// Don't fully delete. This is for testing purposes
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chunk_buffer::ChunkBuffer,
+        file_manager::FileManager,
+        packet::{data_packet::DataPacket, header_packet::HeaderPacket, Packet, PacketRef},
+        *,
+    };
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+
+    /// An empty directory under the system temp dir for tests that complete
+    /// (and therefore write) files.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sfs-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_try_into_header_packet() {
+        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
+        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
+
+        assert_eq!(
+            packet,
+            HeaderPacket {
+                status_byte: 0,
+                file_id: 1,
+                file_name: OsString::from("test")
+            }
+        );
+    }
+
+    #[test]
+    fn test_try_into_data_packet() {
+        let data_packet_bytes: [u8; 6] = [1, 1, 2, 2, 3, 3];
+        let packet = DataPacket::try_from(&data_packet_bytes[..]).unwrap();
+
+        assert_eq!(
+            packet,
+            DataPacket {
+                status_byte: 1,
+                file_id: 1,
+                packet_number: 514,
+                data: vec![3, 3]
+            }
+        );
+    }
+
+    #[test]
+    fn test_header_packet_round_trip() {
+        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
+        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
+
+        assert_eq!(packet.to_bytes(), header_packet_bytes);
+        assert_eq!(
+            HeaderPacket::new(1, OsString::from("test")).to_bytes(),
+            header_packet_bytes
+        );
+    }
+
+    #[test]
+    fn test_data_packet_round_trip() {
+        let data_packet = DataPacket::new(7, 514, vec![3, 3], true);
+        let bytes = data_packet.to_bytes();
+
+        // Last packet bit set, packet number in big endian
+        assert_eq!(bytes, [3, 7, 2, 2, 3, 3]);
+        assert_eq!(DataPacket::try_from(&bytes[..]).unwrap(), data_packet);
+        assert!(!DataPacket::new(7, 0, vec![], false).is_last_data_packet());
+    }
+
+    #[test]
+    fn test_packet_ref_borrows_from_buffer() {
+        let data_packet_bytes: [u8; 6] = [3, 1, 2, 2, 3, 3];
+        let packet = PacketRef::try_from(&data_packet_bytes[..]).unwrap();
+
+        let PacketRef::DataPacket(data_packet) = packet else {
+            panic!("expected a data packet, got {packet:?}");
+        };
+        assert!(data_packet.is_last_data_packet());
+        assert_eq!(data_packet.packet_number, 514);
+        assert_eq!(data_packet.data.as_ptr(), data_packet_bytes[4..].as_ptr());
+        assert_eq!(
+            packet.into_owned(),
+            Packet::DataPacket(DataPacket::new(1, 514, vec![3, 3], true))
+        );
+
+        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
+        let packet = PacketRef::try_from(&header_packet_bytes[..]).unwrap();
+        assert_eq!(packet.file_id(), 1);
+        assert_eq!(
+            packet,
+            Packet::HeaderPacket(HeaderPacket::new(1, OsString::from("test"))).as_ref()
+        );
+        assert_eq!(
+            PacketRef::try_from(&[][..]),
+            Err(packet::PacketParseError::InvalidPacketLength)
+        );
+    }
+
+    #[test]
+    fn test_packet_write_to_reuses_buffer() {
+        let packets = [
+            Packet::HeaderPacket(HeaderPacket::new(2, OsString::from("file.txt"))),
+            Packet::DataPacket(DataPacket::new(2, 0, vec![1, 2, 3], false)),
+            Packet::DataPacket(DataPacket::new(2, 1, vec![4], true)),
+        ];
+
+        let mut buffer = Vec::new();
+        for packet in packets {
+            buffer.clear();
+            packet.write_to(&mut buffer);
+            assert_eq!(buffer.len(), packet.encoded_len());
+            assert_eq!(Packet::try_from(&buffer[..]).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_process_header_packet() {
+        let packet_group1: PacketGroup = PacketGroup {
+            file_name: Some(OsString::from("test")),
+            file_id: 4,
+            expected_number_of_packets: None,
+            chunks: ChunkBuffer::default(),
+            spool: None,
+            written: false,
+            digest: None,
+            needs_digest: false,
+            failed: false,
+        };
+        let mut file_manager: FileManager = FileManager {
+            packet_groups: [packet_group1].into_iter().collect(),
+            ..FileManager::default()
+        };
+
+        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
+        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
+
+        file_manager
+            .process_packet(Packet::HeaderPacket(packet))
+            .unwrap();
+
+        assert_eq!(
+            file_manager.packet_groups[4].file_name,
+            Some(OsString::from("test"))
+        );
+    }
+
+    #[test]
+    fn test_empty_process_header_packet() {
+        let mut file_manager: FileManager = FileManager {
+            packet_groups: PacketGroups::new(),
+            ..FileManager::default()
+        };
+
+        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
+        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
+
+        assert!(file_manager.packet_groups.is_empty());
+        file_manager
+            .process_packet(Packet::HeaderPacket(packet))
+            .unwrap();
+        assert_eq!(file_manager.packet_groups.len(), 1);
+        assert_eq!(
+            file_manager.packet_groups[1].file_name,
+            Some(OsString::from("test"))
+        );
+        assert_eq!(file_manager.packet_groups[1].file_id, 1);
+    }
+
+    #[test]
+    fn test_process_data_packet() {
+        let packet_group1: PacketGroup = PacketGroup {
+            file_name: Some(OsString::from("test")),
+            file_id: 4,
+            expected_number_of_packets: None,
+            chunks: ChunkBuffer::default(),
+            spool: None,
+            written: false,
+            digest: None,
+            needs_digest: false,
+            failed: false,
+        };
+        let mut file_manager: FileManager = FileManager {
+            packet_groups: [packet_group1].into_iter().collect(),
+            ..FileManager::default()
+        };
+
+        let data_packet_bytes: [u8; 6] = [1, 1, 2, 2, 3, 3];
+        let packet = DataPacket::try_from(&data_packet_bytes[..]).unwrap();
+
+        file_manager
+            .process_packet(Packet::DataPacket(packet))
+            .unwrap();
+        assert!(file_manager.packet_groups[1].chunks.get(514).is_some());
+        assert_eq!(
+            file_manager.packet_groups[1].chunks.get(514),
+            Some(&[3, 3][..])
+        );
+    }
+
+    #[test]
+    fn test_empty_process_data_packet() {
+        let mut file_manager: FileManager = FileManager {
+            packet_groups: PacketGroups::new(),
+            ..FileManager::default()
+        };
+
+        let data_packet_bytes: [u8; 6] = [1, 1, 2, 2, 3, 3];
+        let packet = DataPacket::try_from(&data_packet_bytes[..]).unwrap();
+
+        assert!(file_manager.packet_groups.is_empty());
+        file_manager
+            .process_packet(Packet::DataPacket(packet))
+            .unwrap();
+        assert_eq!(file_manager.packet_groups.len(), 1);
+        assert!(file_manager.packet_groups[1].chunks.get(514).is_some());
+        assert_eq!(
+            file_manager.packet_groups[1].chunks.get(514),
+            Some(&[3, 3][..])
+        );
+    }
+
+    #[test]
+    fn test_is_last_data_packet() {
+        // Regular data packet (status byte 1)
+        let regular_packet = DataPacket {
+            status_byte: 1,
+            file_id: 1,
+            packet_number: 0,
+            data: vec![1, 2, 3],
+        };
+        assert!(!regular_packet.is_last_data_packet());
+
+        // Last data packet (status byte 3 - both bits set)
+        let last_packet = DataPacket {
+            status_byte: 3,
+            file_id: 1,
+            packet_number: 5,
+            data: vec![1, 2, 3],
+        };
+        assert!(last_packet.is_last_data_packet());
+    }
+
+    #[test]
+    fn test_process_last_data_packet() {
+        let mut file_manager = FileManager {
+            packet_groups: PacketGroups::new(),
+            ..FileManager::default()
+        };
+
+        // Create a packet with status byte 3 (last packet)
+        let last_data_packet_bytes: [u8; 6] = [3, 1, 0, 5, 3, 3]; // Status byte 3, packet #5
+        let packet = DataPacket::try_from(&last_data_packet_bytes[..]).unwrap();
+
+        file_manager
+            .process_packet(Packet::DataPacket(packet))
+            .unwrap();
+
+        // Check if expected_number_of_packets was set correctly
+        assert_eq!(
+            file_manager.packet_groups[1].expected_number_of_packets,
+            Some(6)
+        ); // Packet #5 + 1
+    }
+
+    #[test]
+    fn test_received_all_packets() {
+        // Test with incomplete file
+        let incomplete_group = PacketGroup {
+            file_name: Some(OsString::from("test")),
+            file_id: 1,
+            expected_number_of_packets: Some(3),
+            chunks: {
+                let mut chunks = ChunkBuffer::default();
+                chunks.write_chunk(0, &[1, 2, 3], false);
+                chunks.write_chunk(1, &[4, 5, 6], false);
+                // Missing packet #2
+                chunks
+            },
+            spool: None,
+            written: false,
+            digest: None,
+            needs_digest: false,
+            failed: false,
+        };
+
+        let file_manager = FileManager {
+            packet_groups: [incomplete_group].into_iter().collect(),
+            ..FileManager::default()
+        };
+
+        assert!(!file_manager.received_all_packets());
+
+        // Test with complete file
+        let complete_group = PacketGroup {
+            file_name: Some(OsString::from("test")),
+            file_id: 1,
+            expected_number_of_packets: Some(3),
+            chunks: {
+                let mut chunks = ChunkBuffer::default();
+                chunks.write_chunk(0, &[1, 2, 3], false);
+                chunks.write_chunk(1, &[4, 5, 6], false);
+                chunks.write_chunk(2, &[7, 8, 9], true);
+                chunks
+            },
+            spool: None,
+            written: false,
+            digest: None,
+            needs_digest: false,
+            failed: false,
+        };
+
+        let file_manager = FileManager {
+            packet_groups: [complete_group].into_iter().collect(),
+            ..FileManager::default()
+        };
+
+        assert!(file_manager.received_all_packets());
+    }
+
+    #[test]
+    fn test_received_all_packets_waits_for_expected_files() {
+        let output_dir = scratch_dir("expected-files");
+        let mut file_manager = FileManager::new(&output_dir, Some(2));
+
+        file_manager
+            .process_packet(Packet::HeaderPacket(HeaderPacket::new(
+                1,
+                OsString::from("first.txt"),
+            )))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(DataPacket::new(1, 0, vec![1], true)))
+            .unwrap();
+        assert!(!file_manager.received_all_packets());
+
+        file_manager
+            .process_packet(Packet::DataPacket(DataPacket::new(2, 0, vec![2], true)))
+            .unwrap();
+        // All of the data is in, but the second file can't be written without
+        // its name
+        assert!(!file_manager.received_all_packets());
+
+        file_manager
+            .process_packet(Packet::HeaderPacket(HeaderPacket::new(
+                2,
+                OsString::from("second.txt"),
+            )))
+            .unwrap();
+        assert!(file_manager.received_all_packets());
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_order_packet_processing() {
+        let output_dir = scratch_dir("out-of-order");
+        let mut file_manager = FileManager::new(&output_dir, None);
+
+        // Process data packets before header. Every packet but the last is
+        // a full chunk.
+        let first_chunk = vec![1; packet::MAX_DATA_LEN];
+        let data_packet1 = DataPacket {
+            status_byte: 1,
+            file_id: 1,
+            packet_number: 0,
+            data: first_chunk.clone(),
+        };
+
+        let data_packet2 = DataPacket {
+            status_byte: 3, // Last packet
+            file_id: 1,
+            packet_number: 1,
+            data: vec![4, 5, 6],
+        };
+
+        file_manager
+            .process_packet(Packet::DataPacket(data_packet1))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(data_packet2))
+            .unwrap();
+
+        // Now process header
+        let header_packet = HeaderPacket {
+            status_byte: 0,
+            file_id: 1,
+            file_name: OsString::from("test.txt"),
+        };
+
+        file_manager
+            .process_packet(Packet::HeaderPacket(header_packet))
+            .unwrap();
+
+        // Verify everything is set correctly
+        assert_eq!(
+            file_manager.packet_groups[1].file_name,
+            Some(OsString::from("test.txt"))
+        );
+        assert_eq!(
+            file_manager.packet_groups[1].expected_number_of_packets,
+            Some(2)
+        );
+        assert_eq!(file_manager.packet_groups[1].received_packets(), 2);
+        assert!(file_manager.received_all_packets());
+        assert_eq!(
+            std::fs::read(output_dir.join("test.txt")).unwrap(),
+            [first_chunk, vec![4, 5, 6]].concat()
+        );
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_multiple_files_interleaved() {
+        let output_dir = scratch_dir("interleaved");
+        let mut file_manager = FileManager::new(&output_dir, None);
+
+        // Process packets from two different files interleaved
+        let header1 = HeaderPacket {
+            status_byte: 0,
+            file_id: 1,
+            file_name: OsString::from("file1.txt"),
+        };
+
+        let data1_file1 = DataPacket {
+            status_byte: 1,
+            file_id: 1,
+            packet_number: 0,
+            data: vec![1, 2, 3],
+        };
+
+        let header2 = HeaderPacket {
+            status_byte: 0,
+            file_id: 2,
+            file_name: OsString::from("file2.txt"),
+        };
+
+        let data1_file2 = DataPacket {
+            status_byte: 1,
+            file_id: 2,
+            packet_number: 0,
+            data: vec![7, 8, 9],
+        };
+
+        let data2_file1 = DataPacket {
+            status_byte: 3, // Last packet
+            file_id: 1,
+            packet_number: 1,
+            data: vec![4, 5, 6],
+        };
+
+        let data2_file2 = DataPacket {
+            status_byte: 3, // Last packet
+            file_id: 2,
+            packet_number: 1,
+            data: vec![10, 11, 12],
+        };
+
+        // Process in interleaved order
+        file_manager
+            .process_packet(Packet::HeaderPacket(header1))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(data1_file2))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::HeaderPacket(header2))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(data1_file1))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(data2_file2))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(data2_file1))
+            .unwrap();
+
+        assert!(file_manager.received_all_packets());
+        assert_eq!(file_manager.packet_groups.len(), 2);
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_edge_case_single_packet_file() {
+        let output_dir = scratch_dir("single-packet");
+        let mut file_manager = FileManager::new(&output_dir, None);
+
+        // File with a single packet
+        let header = HeaderPacket {
+            status_byte: 0,
+            file_id: 1,
+            file_name: OsString::from("single.txt"),
+        };
+
+        let data = DataPacket {
+            status_byte: 3, // Last packet
+            file_id: 1,
+            packet_number: 0,
+            data: vec![1, 2, 3],
+        };
+
+        file_manager
+            .process_packet(Packet::HeaderPacket(header))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::DataPacket(data))
+            .unwrap();
+
+        assert!(file_manager.received_all_packets());
+        assert_eq!(
+            file_manager.packet_groups[1].expected_number_of_packets,
+            Some(1)
+        );
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_written_as_soon_as_complete() {
+        let output_dir = scratch_dir("written-on-completion");
+        let mut file_manager = FileManager::new(&output_dir, None);
+
+        let header = Packet::HeaderPacket(HeaderPacket::new(5, OsString::from("early.txt")));
+        assert_eq!(file_manager.process_packet(header).unwrap(), None);
+        let first_chunk = vec![1; packet::MAX_DATA_LEN];
+        let first = Packet::DataPacket(DataPacket::new(5, 0, first_chunk.clone(), false));
+        assert_eq!(file_manager.process_packet(first).unwrap(), None);
+        // Another file is still in flight, but this one is done
+        let other = Packet::DataPacket(DataPacket::new(6, 0, vec![9], false));
+        assert_eq!(file_manager.process_packet(other).unwrap(), None);
+
+        let last = Packet::DataPacket(DataPacket::new(5, 1, vec![3], true));
+        assert_eq!(
+            file_manager.process_packet(last).unwrap(),
+            Some(FileEvent::Completed {
+                file_id: 5,
+                path: output_dir.join("early.txt"),
+                bytes: packet::MAX_DATA_LEN as u64 + 1,
+            })
+        );
+        assert_eq!(
+            std::fs::read(output_dir.join("early.txt")).unwrap(),
+            [first_chunk.clone(), vec![3]].concat()
+        );
+        assert!(file_manager.packet_groups[5].is_written());
+        assert!(file_manager.packet_groups[5].chunks.as_slice().is_empty());
+        assert!(!file_manager.received_all_packets());
+
+        // A late duplicate doesn't bring the buffers back
+        let duplicate = Packet::DataPacket(DataPacket::new(5, 0, first_chunk, false));
+        assert_eq!(file_manager.process_packet(duplicate).unwrap(), None);
+        assert!(file_manager.packet_groups[5].chunks.as_slice().is_empty());
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_escaping_names_are_rejected_on_completion() {
+        let output_dir = scratch_dir("escape");
+        let mut file_manager = FileManager::new(&output_dir, None);
+
+        let results: Vec<_> = [(1, "../escaped.txt"), (2, "kept.txt")]
+            .into_iter()
+            .map(|(file_id, name)| {
+                file_manager
+                    .process_packet(Packet::HeaderPacket(HeaderPacket::new(
+                        file_id,
+                        OsString::from(name),
+                    )))
+                    .unwrap();
+                file_manager.process_packet(Packet::DataPacket(DataPacket::new(
+                    file_id,
+                    0,
+                    vec![1, 2, 3],
+                    true,
+                )))
+            })
+            .collect();
+
+        match &results[0] {
+            Err(ClientError::InvalidFileName {
+                file_id, reason, ..
+            }) => {
+                assert_eq!(*file_id, 1);
+                assert_eq!(*reason, FileNameError::ParentDirectory);
+            }
+            other => panic!("expected an invalid file name, got {other:?}"),
+        }
+        assert!(matches!(results[1], Ok(Some(FileEvent::Completed { .. }))));
+        assert_eq!(
+            std::fs::read(output_dir.join("kept.txt")).unwrap(),
+            [1, 2, 3]
+        );
+        assert!(!output_dir.join("../escaped.txt").exists());
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_data_packets_are_refused() {
+        for reassembly in [ReassemblyMode::InMemory, ReassemblyMode::Streaming] {
+            let output_dir = scratch_dir("oversized");
+            let mut file_manager = FileManager::new(&output_dir, None).with_reassembly(reassembly);
+
+            // Packet 0 would run 32 bytes into packet 1's place in the file
+            let oversized = [9; packet::MAX_DATA_LEN + 32];
+            assert!(matches!(
+                file_manager.process_packet(data(4, 0, &oversized, false)),
+                Err(ClientError::PacketParseError(
+                    packet::PacketParseError::InvalidDataPacket
+                ))
+            ));
+            assert_eq!(file_manager.stats.stored_bytes, 0);
+
+            let header = HeaderPacket::new(4, OsString::from("intact.bin"));
+            file_manager
+                .process_packet(Packet::HeaderPacket(header))
+                .unwrap();
+            file_manager
+                .process_packet(data(4, 1, &[1; 10], true))
+                .unwrap();
+            assert_eq!(file_manager.packet_groups[4].received_packets(), 1);
+            assert!(!file_manager.received_all_packets());
+
+            let _ = std::fs::remove_dir_all(&output_dir);
+        }
+    }
+
+    #[test]
+    fn test_streaming_transfers_can_share_an_output_directory() {
+        let output_dir = scratch_dir("shared-spool");
+        let streaming =
+            || FileManager::new(&output_dir, None).with_reassembly(ReassemblyMode::Streaming);
+        let mut first = streaming();
+        let mut second = streaming();
+
+        // Both transfers spool a file with the same ID at the same time
+        first.process_packet(data(1, 0, b"first", false)).unwrap();
+        second.process_packet(data(1, 0, b"second", false)).unwrap();
+        for (file_manager, name) in [(&mut first, "first.txt"), (&mut second, "second.txt")] {
+            file_manager.process_packet(data(1, 1, b"!", true)).unwrap();
+            let header = HeaderPacket::new(1, OsString::from(name));
+            file_manager
+                .process_packet(Packet::HeaderPacket(header))
+                .unwrap();
+        }
+
+        for (name, expected) in [("first.txt", &b"first"[..]), ("second.txt", b"second")] {
+            let file = std::fs::read(output_dir.join(name)).unwrap();
+            assert_eq!(&file[..expected.len()], expected, "{name}");
+        }
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_reassembly_writes_chunks_to_disk() {
+        let output_dir = scratch_dir("streaming");
+        let mut file_manager =
+            FileManager::new(&output_dir, None).with_reassembly(ReassemblyMode::Streaming);
+
+        let contents: Vec<u8> = (0..2500u16).map(|i| (i % 251) as u8).collect();
+        let chunks: Vec<_> = contents.chunks(packet::MAX_DATA_LEN).collect();
+
+        // Last chunk first, then the rest backwards, then the header
+        for (packet_number, chunk) in (0..3u16).zip(&chunks).rev() {
+            let is_last = packet_number == 2;
+            let packet = DataPacket::new(3, packet_number, chunk.to_vec(), is_last);
+            assert_eq!(
+                file_manager
+                    .process_packet(Packet::DataPacket(packet))
+                    .unwrap(),
+                None
+            );
+            assert!(file_manager.packet_groups[3].chunks.as_slice().is_empty());
+        }
+        assert_eq!(file_manager.packet_groups[3].received_packets(), 3);
+
+        let header = HeaderPacket::new(3, OsString::from("streamed.bin"));
+        assert_eq!(
+            file_manager
+                .process_packet(Packet::HeaderPacket(header))
+                .unwrap(),
+            Some(FileEvent::Completed {
+                file_id: 3,
+                path: output_dir.join("streamed.bin"),
+                bytes: 2500,
+            })
+        );
+
+        assert_eq!(
+            std::fs::read(output_dir.join("streamed.bin")).unwrap(),
+            contents
+        );
+        // Only the finished file is left behind
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    fn data(file_id: u8, packet_number: u16, data: &[u8], is_last: bool) -> Packet {
+        Packet::DataPacket(DataPacket::new(
+            file_id,
+            packet_number,
+            data.to_vec(),
+            is_last,
+        ))
+    }
+
+    #[test]
+    fn test_duplicate_packets_are_counted_not_stored_twice() {
+        let mut file_manager = FileManager::default();
+
+        file_manager
+            .process_packet(data(1, 0, &[1, 2], false))
+            .unwrap();
+        file_manager
+            .process_packet(data(1, 0, &[1, 2], false))
+            .unwrap();
+        for _ in 0..2 {
+            let header = HeaderPacket::new(1, OsString::from("dup.txt"));
+            file_manager
+                .process_packet(Packet::HeaderPacket(header))
+                .unwrap();
+        }
+
+        assert_eq!(
+            file_manager.stats,
+            PacketStats {
+                packets: 4,
+                duplicate_packets: 2,
+                stored_bytes: 2,
+                anomalies: 0,
+                stray_datagrams: 0,
+                unauthenticated_datagrams: 0,
+                oversized_datagrams: 0,
+                unrecognized_control_messages: 0,
+            }
+        );
+        assert_eq!(file_manager.packet_groups[1].received_packets(), 1);
+        assert!(file_manager.take_anomalies().is_empty());
+    }
+
+    #[test]
+    fn test_conflicting_packets_keep_the_first_version() {
+        let mut file_manager = FileManager::default();
+
+        file_manager
+            .process_packet(data(1, 0, &[1, 2], false))
+            .unwrap();
+        file_manager
+            .process_packet(data(1, 0, &[9, 9], false))
+            .unwrap();
+        let first = HeaderPacket::new(1, OsString::from("first.txt"));
+        let second = HeaderPacket::new(1, OsString::from("second.txt"));
+        file_manager
+            .process_packet(Packet::HeaderPacket(first))
+            .unwrap();
+        file_manager
+            .process_packet(Packet::HeaderPacket(second))
+            .unwrap();
+
+        assert_eq!(
+            file_manager.take_anomalies(),
+            [
+                PacketAnomaly::ConflictingPayload {
+                    file_id: 1,
+                    packet_number: 0,
+                },
+                PacketAnomaly::ConflictingFileName {
+                    file_id: 1,
+                    file_name: OsString::from("first.txt"),
+                    rejected_name: OsString::from("second.txt"),
+                },
+            ]
+        );
+        assert_eq!(file_manager.stats.anomalies, 2);
+
+        let packet_group = &file_manager.packet_groups[1];
+        assert_eq!(packet_group.chunks.get(0), Some(&[1, 2][..]));
+        assert_eq!(packet_group.file_name(), Some(OsStr::new("first.txt")));
+    }
+
+    #[test]
+    fn test_packets_past_the_last_packet_are_dropped() {
+        let mut file_manager = FileManager::default();
+
+        // Packet 3 arrives before the last-packet marker says there are only 2
+        file_manager
+            .process_packet(data(1, 3, &[3], false))
+            .unwrap();
+        file_manager.process_packet(data(1, 1, &[1], true)).unwrap();
+        file_manager
+            .process_packet(data(1, 2, &[2], false))
+            .unwrap();
+        file_manager.process_packet(data(1, 2, &[2], true)).unwrap();
+
+        assert_eq!(
+            file_manager.take_anomalies(),
+            [
+                PacketAnomaly::BeyondLastPacket {
+                    file_id: 1,
+                    expected_number_of_packets: 2,
+                    packet_number: 3,
+                },
+                PacketAnomaly::BeyondLastPacket {
+                    file_id: 1,
+                    expected_number_of_packets: 2,
+                    packet_number: 2,
+                },
+                PacketAnomaly::ConflictingLastPacket {
+                    file_id: 1,
+                    expected_number_of_packets: 2,
+                    packet_number: 2,
+                },
+            ]
+        );
+
+        let packet_group = &file_manager.packet_groups[1];
+        assert_eq!(packet_group.expected_number_of_packets(), Some(2));
+        assert_eq!(packet_group.received_packets(), 1);
+    }
+
+    #[test]
+    fn test_streaming_conflicts_are_detected_on_disk() {
+        let output_dir = scratch_dir("streaming-conflict");
+        let mut file_manager =
+            FileManager::new(&output_dir, None).with_reassembly(ReassemblyMode::Streaming);
+
+        file_manager
+            .process_packet(data(1, 0, &[1, 2], false))
+            .unwrap();
+        file_manager
+            .process_packet(data(1, 0, &[1, 2], false))
+            .unwrap();
+        file_manager
+            .process_packet(data(1, 0, &[7, 7], false))
+            .unwrap();
+
+        assert_eq!(file_manager.stats.duplicate_packets, 1);
+        assert_eq!(
+            file_manager.take_anomalies(),
+            [PacketAnomaly::ConflictingPayload {
+                file_id: 1,
+                packet_number: 0,
+            }]
+        );
+
+        drop(file_manager);
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_strict_mode_fails_on_anomaly() {
+        let mut file_manager = FileManager::default().with_strict(true);
+
+        file_manager
+            .process_packet(data(1, 0, &[1], false))
+            .unwrap();
+        match file_manager.process_packet(data(1, 0, &[2], false)) {
+            Err(ClientError::PacketAnomaly(PacketAnomaly::ConflictingPayload {
+                file_id: 1,
+                packet_number: 0,
+            })) => {}
+            other => panic!("expected a conflicting payload, got {other:?}"),
+        }
+        assert!(file_manager.take_anomalies().is_empty());
+    }
+
+    #[test]
+    fn test_unnamed_files_are_written_under_their_fallback_name() {
+        let output_dir = scratch_dir("fallback-name");
+        let mut file_manager = FileManager::new(&output_dir, Some(2));
+
+        file_manager
+            .process_packet(data(4, 0, &[1, 2], true))
+            .unwrap();
+        file_manager
+            .process_packet(data(5, 0, &[3], false))
+            .unwrap();
+        assert_eq!(file_manager.unnamed_files(), [4, 5]);
+
+        // Without a fallback name there is nothing to call them
+        assert!(file_manager.write_unnamed_files().unwrap().is_empty());
+        file_manager.write_all_files().unwrap();
+        assert!(!output_dir.join("file-4.bin").exists());
+
+        file_manager.fallback_name = Some(String::from("file-{id}.bin"));
+        assert_eq!(
+            file_manager.write_unnamed_files().unwrap(),
+            [FileEvent::CompletedUnnamed {
+                file_id: 4,
+                path: output_dir.join("file-4.bin"),
+                bytes: 2,
+            }]
+        );
+        assert_eq!(
+            std::fs::read(output_dir.join("file-4.bin")).unwrap(),
+            [1, 2]
+        );
+        // File 5 is still missing data, so it has to wait
+        assert_eq!(file_manager.unnamed_files(), [5]);
+        assert!(!file_manager.received_all_packets());
+
+        file_manager.process_packet(data(5, 1, &[4], true)).unwrap();
+        file_manager.write_all_files().unwrap();
+        assert!(file_manager.received_all_packets());
+
+        // A header that turns up afterwards changes nothing
+        let late = Packet::HeaderPacket(HeaderPacket::new(4, OsString::from("late.txt")));
+        assert_eq!(file_manager.process_packet(late).unwrap(), None);
+        assert!(file_manager.take_anomalies().is_empty());
+        assert!(!output_dir.join("late.txt").exists());
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_partial_files_keep_their_holes_and_list_what_is_missing() {
+        let output_dir = scratch_dir("partial");
+        let mut file_manager =
+            FileManager::new(&output_dir, None).with_reassembly(ReassemblyMode::Streaming);
+
+        // Packet 1 of a file whose header and last packet never arrive
+        let chunk = vec![9; packet::MAX_DATA_LEN];
+        file_manager
+            .process_packet(data(7, 1, &chunk, false))
+            .unwrap();
+        let report = file_manager.report();
+        assert_eq!(report.files[0].missing, [0..=0, 2..=u16::MAX]);
+        assert!(!report.is_complete());
+
+        let path = output_dir.join("file-7.bin");
+        let sidecar = output_dir.join("file-7.bin.missing");
+        assert_eq!(
+            file_manager.write_partial_files().unwrap(),
+            [path.clone(), sidecar.clone()]
+        );
+        let partial = std::fs::read(&path).unwrap();
+        assert_eq!(partial.len(), 2 * packet::MAX_DATA_LEN);
+        assert!(partial[..packet::MAX_DATA_LEN]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_eq!(partial[packet::MAX_DATA_LEN..], chunk[..]);
+        assert_eq!(std::fs::read_to_string(&sidecar).unwrap(), "0\n2-\n");
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_resumes_by_name_under_a_new_file_id() {
+        let output_dir = scratch_dir("resume");
+        let journal = || Some(Journal::new(output_dir.join(journal::JOURNAL_DIR)));
+        let contents: Vec<u8> = (0..2100u16).map(|i| (i % 253) as u8).collect();
+        let chunks: Vec<_> = contents.chunks(packet::MAX_DATA_LEN).collect();
+        let header = |file_id| {
+            Packet::HeaderPacket(HeaderPacket::new(file_id, OsString::from("resumed.bin")))
+        };
+
+        // The first run gets the header and two of the three packets, then dies
+        let mut first_run = FileManager::new(&output_dir, None).with_journal(journal());
+        first_run.process_packet(header(2)).unwrap();
+        first_run
+            .process_packet(data(2, 0, chunks[0], false))
+            .unwrap();
+        first_run
+            .process_packet(data(2, 2, chunks[2], true))
+            .unwrap();
+        drop(first_run);
+
+        // The next session numbers the file differently
+        let mut second_run = FileManager::new(&output_dir, None)
+            .with_reassembly(ReassemblyMode::Streaming)
+            .with_journal(journal());
+        second_run.process_packet(header(9)).unwrap();
+        assert_eq!(second_run.packet_groups[9].received_packets(), 2);
+        assert_eq!(
+            second_run
+                .process_packet(data(9, 1, chunks[1], false))
+                .unwrap(),
+            Some(FileEvent::Completed {
+                file_id: 9,
+                path: output_dir.join("resumed.bin"),
+                bytes: 2100,
+            })
+        );
+
+        assert_eq!(
+            std::fs::read(output_dir.join("resumed.bin")).unwrap(),
+            contents
+        );
+        // Nothing is left to resume
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_replay_is_held_to_the_limits() {
+        let output_dir = scratch_dir("resume-limits");
+        let journal = || Some(Journal::new(output_dir.join(journal::JOURNAL_DIR)));
+        let full = [7; packet::MAX_DATA_LEN];
+        let header = || Packet::HeaderPacket(HeaderPacket::new(2, OsString::from("big.bin")));
+
+        let mut first_run = FileManager::new(&output_dir, None).with_journal(journal());
+        first_run.process_packet(header()).unwrap();
+        for packet_number in 0..3 {
+            first_run
+                .process_packet(data(2, packet_number, &full, false))
+                .unwrap();
+        }
+        drop(first_run);
+
+        let limited = |limits| {
+            FileManager::new(&output_dir, None)
+                .with_journal(journal())
+                .with_limits(limits)
+        };
+        let mut second_run = limited(Limits {
+            max_file_bytes: Some(2000),
+            ..Limits::default()
+        });
+        assert!(matches!(
+            second_run.process_packet(header()),
+            Err(ClientError::LimitExceeded(LimitExceeded::FileBytes {
+                file_id: 2,
+                limit: 2000,
+            }))
+        ));
+        drop(second_run);
+
+        let mut third_run = limited(Limits {
+            max_total_bytes: Some(2500),
+            ..Limits::default()
+        });
+        assert!(matches!(
+            third_run.process_packet(header()),
+            Err(ClientError::LimitExceeded(LimitExceeded::TotalBytes {
+                limit: 2500
+            }))
+        ));
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_files_are_not_clobbered_unless_asked() {
+        let output_dir = scratch_dir("existing");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let path = output_dir.join("taken.txt");
+        std::fs::write(&path, "old").unwrap();
+        let header = || Packet::HeaderPacket(HeaderPacket::new(1, OsString::from("taken.txt")));
+
+        let mut file_manager =
+            FileManager::new(&output_dir, None).with_existing_files(ExistingFilePolicy::Fail);
+        file_manager.process_packet(header()).unwrap();
+        match file_manager.process_packet(data(1, 0, b"new", true)) {
+            Err(ClientError::FileExists {
+                file_id: 1,
+                path: existing,
+            }) => {
+                assert_eq!(existing, path);
+            }
+            other => panic!("expected the file to exist already, got {other:?}"),
+        }
+
+        let mut file_manager =
+            FileManager::new(&output_dir, None).with_existing_files(ExistingFilePolicy::Skip);
+        file_manager.process_packet(header()).unwrap();
+        assert_eq!(
+            file_manager
+                .process_packet(data(1, 0, b"new", true))
+                .unwrap(),
+            Some(FileEvent::Skipped {
+                file_id: 1,
+                path: path.clone(),
+            })
+        );
+        assert!(file_manager.received_all_packets());
+
+        // The old file is untouched, with no temporary files left beside it
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_wait_for_their_checksum() {
+        let output_dir = scratch_dir("checksum");
+        let mut file_manager = FileManager::new(&output_dir, Some(1));
+        file_manager.require_checksums = true;
+
+        let header = HeaderPacket::new(6, OsString::from("checked.txt"));
+        file_manager
+            .process_packet(Packet::HeaderPacket(header))
+            .unwrap();
+        assert_eq!(
+            file_manager
+                .process_packet(data(6, 0, b"ok", true))
+                .unwrap(),
+            None
+        );
+        assert!(!file_manager.received_all_packets());
+        assert_eq!(file_manager.awaiting_checksum(), [6]);
+
+        assert_eq!(
+            file_manager
+                .process_checksum(6, checksum::digest(b"ok"))
+                .unwrap(),
+            Some(FileEvent::Completed {
+                file_id: 6,
+                path: output_dir.join("checked.txt"),
+                bytes: 2,
+            })
+        );
+        assert!(file_manager.received_all_packets());
+        assert!(file_manager.awaiting_checksum().is_empty());
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_for_an_unknown_file_is_dropped() {
+        let output_dir = scratch_dir("stray-checksum");
+        let mut file_manager = FileManager::new(&output_dir, None);
+
+        let header = HeaderPacket::new(6, OsString::from("done.txt"));
+        file_manager
+            .process_packet(Packet::HeaderPacket(header))
+            .unwrap();
+        file_manager
+            .process_packet(data(6, 0, b"ok", true))
+            .unwrap();
+        assert!(file_manager.received_all_packets());
+
+        assert_eq!(
+            file_manager
+                .process_checksum(9, checksum::digest(b"never sent"))
+                .unwrap(),
+            None
+        );
+        assert!(file_manager.packet_groups.get(9).is_none());
+        assert_eq!(file_manager.packet_groups.len(), 1);
+        assert!(file_manager.received_all_packets());
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_that_cant_be_written_fail_once() {
+        let output_dir = scratch_dir("failed-once");
+        let mut file_manager = FileManager::new(&output_dir, Some(2));
+        file_manager.require_checksums = true;
+
+        let header = HeaderPacket::new(6, OsString::from("corrupt.txt"));
+        file_manager
+            .process_packet(Packet::HeaderPacket(header))
+            .unwrap();
+        file_manager
+            .process_packet(data(6, 0, b"ok", true))
+            .unwrap();
+        assert!(matches!(
+            file_manager.process_checksum(6, checksum::digest(b"no")),
+            Err(ClientError::ChecksumMismatch { file_id: 6, .. })
+        ));
+
+        let header = HeaderPacket::new(7, OsString::from("../escaped.txt"));
+        file_manager
+            .process_packet(Packet::HeaderPacket(header))
+            .unwrap();
+        file_manager
+            .process_packet(data(7, 0, b"ok", true))
+            .unwrap();
+        assert!(matches!(
+            file_manager.process_checksum(7, checksum::digest(b"ok")),
+            Err(ClientError::InvalidFileName { file_id: 7, .. })
+        ));
+
+        // Later duplicates are dropped rather than failing again
+        assert_eq!(
+            file_manager
+                .process_packet(data(6, 0, b"ok", true))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            file_manager
+                .process_packet(data(7, 0, b"ok", true))
+                .unwrap(),
+            None
+        );
+        assert_eq!(file_manager.stats.duplicate_packets, 2);
+        file_manager.write_all_files().unwrap();
+
+        assert!(file_manager.packet_groups[6].is_failed());
+        assert!(file_manager.received_all_packets());
+        assert!(file_manager.incomplete_files().is_empty());
+        let report = file_manager.report();
+        assert!(report.files.iter().all(|file| file.failed && !file.written));
+        assert!(!report.is_complete());
+        assert!(file_manager.write_partial_files().unwrap().is_empty());
+        assert!(!output_dir.exists());
+    }
+
+    #[test]
+    fn test_unrecognized_control_messages_dont_become_files() {
+        let mut negotiation = extension::Negotiation::new(Features::NACK);
+        let mut file_manager = FileManager::default().with_strict(true);
+
+        // A kind from a newer server, and an accept cut short
+        for datagram in [
+            &[extension::CONTROL_BYTE, 42, b'x'][..],
+            &[extension::CONTROL_BYTE, 1],
+        ] {
+            receive::process_datagram(datagram, &mut negotiation, &mut file_manager, &mut ())
+                .unwrap();
+        }
+
+        assert!(file_manager.packet_groups.is_empty());
+        assert_eq!(file_manager.stats.unrecognized_control_messages, 2);
+        assert_eq!(file_manager.stats.packets, 0);
+    }
+
+    #[test]
+    fn test_packets_past_a_limit_are_refused() {
+        let mut file_manager = FileManager::default().with_limits(Limits {
+            max_files: Some(2),
+            max_file_bytes: Some(3000),
+            max_total_bytes: Some(4000),
+            max_name_len: Some(8),
+            max_duration: None,
+        });
+        let full = [7; packet::MAX_DATA_LEN];
+
+        let header = HeaderPacket::new(1, OsString::from("too-long.txt"));
+        assert!(matches!(
+            file_manager.process_packet(Packet::HeaderPacket(header)),
+            Err(ClientError::LimitExceeded(LimitExceeded::NameLength {
+                file_id: 1,
+                len: 12,
+                limit: 8,
+            }))
+        ));
+
+        // The third full packet would take the file past 3000 bytes
+        file_manager
+            .process_packet(data(1, 0, &full, false))
+            .unwrap();
+        file_manager
+            .process_packet(data(1, 1, &full, false))
+            .unwrap();
+        assert!(matches!(
+            file_manager.process_packet(data(1, 2, &full, false)),
+            Err(ClientError::LimitExceeded(LimitExceeded::FileBytes {
+                file_id: 1,
+                limit: 3000,
+            }))
+        ));
+        file_manager
+            .process_packet(data(1, 2, &[7; 10], true))
+            .unwrap();
+
+        file_manager
+            .process_packet(data(2, 0, &full, false))
+            .unwrap();
+        assert!(matches!(
+            file_manager.process_packet(data(3, 0, &full, false)),
+            Err(ClientError::LimitExceeded(LimitExceeded::Files {
+                file_id: 3,
+                limit: 2,
+            }))
+        ));
+
+        // Duplicates don't count towards the total
+        file_manager
+            .process_packet(data(2, 0, &full, false))
+            .unwrap();
+        assert_eq!(file_manager.stats.stored_bytes, 3082);
+        assert!(matches!(
+            file_manager.process_packet(data(2, 1, &full, false)),
+            Err(ClientError::LimitExceeded(LimitExceeded::TotalBytes {
+                limit: 4000
+            }))
+        ));
+        // The packet that would have gone past the total isn't stored
+        assert_eq!(file_manager.stats.stored_bytes, 3082);
+        assert_eq!(file_manager.packet_groups[2].received_packets(), 1);
+
+        file_manager.limits.max_duration = Some(Duration::from_millis(500));
+        file_manager.started = std::time::Instant::now().checked_sub(Duration::from_secs(1));
+        assert!(matches!(
+            file_manager.process_packet(data(2, 1, &full, false)),
+            Err(ClientError::LimitExceeded(LimitExceeded::Duration { .. }))
+        ));
+    }
+}
@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Caps on what a server can make the `FileManager` hold, so one that
+/// misbehaves can't run the client out of memory or disk. Each limit is off
+/// when it is `None`, as they all are by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// How many files can be in progress at once. Files that have been
+    /// written no longer count.
+    pub max_files: Option<usize>,
+    /// How big a single file can be, judged from the position of each data
+    /// packet as it arrives.
+    pub max_file_bytes: Option<u64>,
+    /// How much file data the whole transfer can store, not counting
+    /// duplicates.
+    pub max_total_bytes: Option<u64>,
+    /// How long a file name can be, in bytes.
+    pub max_name_len: Option<usize>,
+    /// How long the transfer can take, from the hello. It is checked for
+    /// every packet and while waiting for one.
+    pub max_duration: Option<Duration>,
+}
+
+/// Which of the `Limits` a packet broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// A packet for a new file arrived with `limit` files already in
+    /// progress.
+    Files { file_id: u8, limit: usize },
+    /// A data packet reached past `limit` bytes into its file.
+    FileBytes { file_id: u8, limit: u64 },
+    /// More than `limit` bytes of file data arrived in all.
+    TotalBytes { limit: u64 },
+    /// A header packet's file name was `len` bytes long.
+    NameLength {
+        file_id: u8,
+        len: usize,
+        limit: usize,
+    },
+    /// The transfer was still going `limit` after it started.
+    Duration { limit: Duration },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Files { file_id, limit } => write!(
+                f,
+                "file {file_id} would be more than {limit} files in progress at once"
+            ),
+            LimitExceeded::FileBytes { file_id, limit } => {
+                write!(f, "file {file_id} is larger than {limit} bytes")
+            }
+            LimitExceeded::TotalBytes { limit } => {
+                write!(f, "the transfer is larger than {limit} bytes")
+            }
+            LimitExceeded::NameLength {
+                file_id,
+                len,
+                limit,
+            } => write!(
+                f,
+                "the name of file {file_id} is {len} bytes long, more than {limit}"
+            ),
+            LimitExceeded::Duration { limit } => write!(
+                f,
+                "the transfer took longer than {:.1}s",
+                limit.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl Error for LimitExceeded {}
@@ -1,417 +1,493 @@
-// Below is a version of the `main` function and some error types. This assumes
-// the existence of types like `FileManager`, `Packet`, and `PacketParseError`.
-// You can use this code as a starting point for the exercise, or you can
-// delete it and write your own code with the same function signature.
-
 #![warn(clippy::pedantic)]
 #![warn(clippy::style)]
 #![warn(clippy::perf)]
 #![warn(clippy::complexity)]
 #![warn(clippy::correctness)]
 
-mod file_manager;
-
-#[allow(unused_imports)]
 use std::{
-    collections::HashMap,
-    ffi::OsString,
+    env, fs,
     io::{self, Write},
-    net::UdpSocket,
-    str::{self, Bytes, FromStr},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    time::Duration,
 };
 
-use file_manager::FileManager;
-use packet::Packet;
+use segmented_file_system_client::{
+    packet::{auth::PacketKey, PacketRef},
+    receive_files, ClientConfig, ClientError, Completion, ExistingFilePolicy, Features, FileEvent,
+    FileManager, PacketAnomaly, Progress, ReassemblyMode, SourcePolicy,
+};
 
-mod packet;
+const USAGE: &str = "\
+usage: segmented-file-system-client [OPTIONS]
+
+options:
+  --server-host HOST      server to request files from (default 127.0.0.1)
+  --server-port PORT      port the server listens on (default 6014)
+  --bind-host HOST        local address to bind to (default 0.0.0.0)
+  --bind-port PORT        local port to bind to, 0 for any free port (default 7077)
+  --accept-from POLICY    which addresses to accept packets from: server, first (send
+                          the hello to the server address, which may be a broadcast
+                          address, and keep to the first server that answers) or any
+                          (default server)
+  --output-dir DIR        directory to write received files into (default .)
+  --expected-files N      number of files the server will send (default 3)
+  --quiet-period-ms MS    instead of counting files, stop once every file is complete
+                          and nothing new has arrived for this long
+  --streaming             write chunks straight to disk instead of holding files in memory
+  --recv-batch N          datagrams to receive per system call on Linux, 1 for one
+                          at a time (default 32)
+  --strict                fail on duplicate packets that disagree instead of dropping them
+  --nack                  offer to ask the server for lost packets again, if it supports that
+  --resend-headers        offer to ask the server for lost file names again
+  --checksum              ask the server for a SHA-256 of each file, and refuse to
+                          write files that don't match it
+  --nack-interval-ms MS   ask for lost packets or names after this long without a
+                          packet (default 500)
+  --fallback-name PATTERN write files whose name never arrives as PATTERN, with {id}
+                          replaced by the file ID, e.g. file-{id}.bin
+  --header-grace-ms MS    how long to wait for lost names before using the fallback
+                          (default 2000)
+  --read-timeout-ms MS    how long each receive waits before checking timers (default 250)
+  --hello-interval-ms MS  re-send the hello if nothing arrives this quickly (default 1000)
+  --idle-timeout-ms MS    give up after this long without a packet (default 30000)
+  --report-json PATH      when the client stops, write a JSON report of what was
+                          received of each file, and which packets are missing
+  --write-partial         if the transfer fails, write incomplete files with holes
+                          where packets are missing, plus a .missing file listing them
+  --existing POLICY       what to do when a received file already exists: overwrite,
+                          skip, rename (to name-1.ext, ...) or fail (default overwrite)
+  --resume                journal received packets in the output directory, and pick
+                          up where an earlier run that was stopped left off
+  --key-file PATH         authenticate every datagram with the key in PATH, which the
+                          server must share; without it, a key in $SFS_KEY is used
+  --encrypt               encrypt the transfer, file names included; with a key, the
+                          key goes into the encryption instead
+  --max-files N           fail if the server sends more than N files at once
+  --max-file-bytes N      fail if the server sends a file larger than N bytes
+  --max-total-bytes N     fail if the server sends more than N bytes in all
+  --max-name-len N        fail if the server sends a file name longer than N bytes
+  --max-duration-ms MS    fail if the transfer is still going this long after the
+                          hello
+  -h, --help              print this message";
+
+fn main() {
+    let mut options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(message) => {
+            eprintln!("{message}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
 
-pub struct PacketGroup {
-    file_name: Option<OsString>,
-    file_id: u8,
-    expected_number_of_packets: Option<usize>,
-    packets: HashMap<u16, Vec<u8>>,
-}
+    match load_key(options.key_file.as_deref()) {
+        Ok(key) => options.config.key = key,
+        Err(e) => {
+            eprintln!("Could not read the key: {e}");
+            process::exit(2);
+        }
+    }
 
-#[derive(Debug)]
-pub enum ClientError {
-    IoError(std::io::Error),
-    PacketParseError(packet::PacketParseError),
-}
+    interrupt::install();
+    let mut file_manager = options.config.file_manager();
+    let result = run(&options.config, &mut file_manager);
 
-impl From<std::io::Error> for ClientError {
-    fn from(e: std::io::Error) -> Self {
-        ClientError::IoError(e)
+    if let Some(path) = &options.report_json {
+        let json = file_manager.report().to_json() + "\n";
+        if let Err(e) = fs::write(path, json) {
+            eprintln!("Could not write the report to {}: {e}", path.display());
+        }
     }
-}
 
-impl From<packet::PacketParseError> for ClientError {
-    fn from(e: packet::PacketParseError) -> Self {
-        Self::PacketParseError(e)
+    let stray_datagrams = file_manager.stats.stray_datagrams;
+    if stray_datagrams > 0 {
+        eprintln!("Ignored {stray_datagrams} datagrams from unexpected addresses");
+    }
+    let unauthenticated_datagrams = file_manager.stats.unauthenticated_datagrams;
+    if unauthenticated_datagrams > 0 {
+        eprintln!("Ignored {unauthenticated_datagrams} datagrams that failed authentication");
+    }
+    let oversized_datagrams = file_manager.stats.oversized_datagrams;
+    if oversized_datagrams > 0 {
+        eprintln!("Ignored {oversized_datagrams} datagrams too long to be packets");
+    }
+    let unrecognized = file_manager.stats.unrecognized_control_messages;
+    if unrecognized > 0 {
+        eprintln!("Ignored {unrecognized} control messages of an unknown kind or cut short");
     }
-}
-
-fn main() -> Result<(), ClientError> {
-    let sock = UdpSocket::bind("0.0.0.0:7077")?;
 
-    let remote_addr = "127.0.0.1:6014";
-    sock.connect(remote_addr)?;
-    let mut buf = [0; 1028];
+    let Err(e) = result else {
+        return;
+    };
+    eprintln!("\n{e}\n\nTransfer report:\n{}", file_manager.report());
+    if options.write_partial {
+        match file_manager.write_partial_files() {
+            Ok(paths) => {
+                for path in paths {
+                    eprintln!("Wrote {}", path.display());
+                }
+            }
+            Err(e) => eprintln!("Could not write the partial files: {e}"),
+        }
+    }
+    process::exit(if interrupt::interrupted() { 130 } else { 1 });
+}
 
-    // Send an empty packet to initiate communication with the server
-    // Fixed: Adding ? to handle errors and only sending 1 byte
-    sock.send(&buf[..1])?;
+/// The key from `key_file` if there is one, or else from the environment.
+fn load_key(key_file: Option<&Path>) -> io::Result<Option<PacketKey>> {
+    match key_file {
+        Some(path) => PacketKey::from_file(path).map(Some),
+        None => PacketKey::from_env(),
+    }
+}
 
-    let mut file_manager = FileManager::default();
+fn run(config: &ClientConfig, file_manager: &mut FileManager) -> Result<(), ClientError> {
+    let sock = config.connect()?;
 
     println!("Receiving packets...");
-    while !file_manager.received_all_packets() {
-        let len = sock.recv(&mut buf)?;
-        let packet: Packet = buf[..len].try_into()?;
-        print!(".");
-        io::stdout().flush()?;
-        file_manager.process_packet(packet);
-    }
-
-    println!("\nAll packets received. Writing files...");
-    file_manager.write_all_files()?;
-    println!("Files successfully written!");
+    receive_files(&sock, file_manager, config, &mut ConsoleProgress)?;
+    println!("\nAll files received!");
 
     Ok(())
 }
-// Don't fully delete. This is for testing purposes
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        file_manager::FileManager,
-        packet::{data_packet::DataPacket, header_packet::HeaderPacket, Packet},
-        *,
-    };
+/// Turns Ctrl-C into an orderly stop, so the client can still report what it
+/// had received.
+mod interrupt {
+    use std::sync::atomic::{AtomicBool, Ordering};
 
-    #[test]
-    fn test_try_into_header_packet() {
-        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
-        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-        assert_eq!(
-            packet,
-            HeaderPacket {
-                status_byte: 0,
-                file_id: 1,
-                file_name: OsString::from("test")
-            }
-        );
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
     }
 
-    #[test]
-    fn test_try_into_data_packet() {
-        let data_packet_bytes: [u8; 6] = [1, 1, 2, 2, 3, 3];
-        let packet = DataPacket::try_from(&data_packet_bytes[..]).unwrap();
+    pub fn install() {
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        // SAFETY: the handler only stores to an atomic, which is
+        // async-signal-safe. A receive it interrupts fails with `EINTR`,
+        // since the socket has a read timeout, so the client notices
+        // promptly.
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
 
-        assert_eq!(
-            packet,
-            DataPacket {
-                status_byte: 1,
-                file_id: 1,
-                packet_number: 514,
-                data: vec![3, 3]
-            }
-        );
+    pub fn interrupted() -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
     }
+}
 
-    #[test]
-    fn test_process_header_packet() {
-        let packet_group1: PacketGroup = PacketGroup {
-            file_name: Some(OsString::from("test")),
-            file_id: 4,
-            expected_number_of_packets: None,
-            packets: HashMap::new(),
-        };
-        let mut file_manager: FileManager = FileManager {
-            packet_groups: vec![packet_group1],
-        };
-
-        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
-        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
-
-        file_manager.process_packet(Packet::HeaderPacket(packet));
+/// Prints a dot per packet, a line per completed file and a warning per
+/// dropped packet, and stops the transfer on Ctrl-C.
+struct ConsoleProgress;
 
-        assert_eq!(
-            file_manager.packet_groups[0].file_name,
-            Some(OsString::from("test"))
-        );
+impl ConsoleProgress {
+    fn check_interrupted() -> io::Result<()> {
+        if interrupt::interrupted() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_empty_process_header_packet() {
-        let mut file_manager: FileManager = FileManager {
-            packet_groups: vec![],
-        };
+impl Progress for ConsoleProgress {
+    fn packet_received(&mut self, _packet: &PacketRef<'_>) -> io::Result<()> {
+        print!(".");
+        Ok(())
+    }
 
-        let header_packet_bytes: [u8; 6] = [0, 1, b't', b'e', b's', b't'];
-        let packet = HeaderPacket::try_from(&header_packet_bytes[..]).unwrap();
+    fn file_event(&mut self, event: &FileEvent) -> io::Result<()> {
+        match event {
+            FileEvent::Completed { path, bytes, .. } => {
+                println!("\nWrote {} ({bytes} bytes)", path.display());
+            }
+            FileEvent::CompletedUnnamed {
+                file_id,
+                path,
+                bytes,
+            } => {
+                println!(
+                    "\nWrote {} ({bytes} bytes); the name of file {file_id} never arrived",
+                    path.display()
+                );
+            }
+            FileEvent::Skipped { path, .. } => {
+                println!("\nSkipped {}, which already exists", path.display());
+            }
+        }
+        Ok(())
+    }
 
-        assert!(file_manager.packet_groups.is_empty());
-        file_manager.process_packet(Packet::HeaderPacket(packet));
-        assert_eq!(file_manager.packet_groups.len(), 1);
-        assert_eq!(
-            file_manager.packet_groups[0].file_name,
-            Some(OsString::from("test"))
-        );
-        assert_eq!(file_manager.packet_groups[0].file_id, 1);
+    fn anomaly(&mut self, anomaly: &PacketAnomaly) -> io::Result<()> {
+        eprintln!("\nwarning: dropped packet: {anomaly}");
+        Ok(())
     }
 
-    #[test]
-    fn test_process_data_packet() {
-        let packet_group1: PacketGroup = PacketGroup {
-            file_name: Some(OsString::from("test")),
-            file_id: 4,
-            expected_number_of_packets: None,
-            packets: HashMap::new(),
-        };
-        let mut file_manager: FileManager = FileManager {
-            packet_groups: vec![packet_group1],
-        };
-
-        let data_packet_bytes: [u8; 6] = [1, 1, 2, 2, 3, 3];
-        let packet = DataPacket::try_from(&data_packet_bytes[..]).unwrap();
-
-        file_manager.process_packet(Packet::DataPacket(packet));
-        assert!(file_manager.packet_groups[1].packets.contains_key(&514));
-        assert_eq!(
-            file_manager.packet_groups[1].packets.get(&514),
-            Some(&vec![3, 3])
-        );
+    fn idle(&mut self) -> io::Result<()> {
+        Self::check_interrupted()
     }
 
-    #[test]
-    fn test_empty_process_data_packet() {
-        let mut file_manager: FileManager = FileManager {
-            packet_groups: vec![],
-        };
-
-        let data_packet_bytes: [u8; 6] = [1, 1, 2, 2, 3, 3];
-        let packet = DataPacket::try_from(&data_packet_bytes[..]).unwrap();
-
-        assert!(file_manager.packet_groups.is_empty());
-        file_manager.process_packet(Packet::DataPacket(packet));
-        assert_eq!(file_manager.packet_groups.len(), 1);
-        assert!(file_manager.packet_groups[0].packets.contains_key(&514));
-        assert_eq!(
-            file_manager.packet_groups[0].packets.get(&514),
-            Some(&vec![3, 3])
-        );
+    fn batch_processed(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+        Self::check_interrupted()
     }
+}
 
-    #[test]
-    fn test_is_last_data_packet() {
-        // Regular data packet (status byte 1)
-        let regular_packet = DataPacket {
-            status_byte: 1,
-            file_id: 1,
-            packet_number: 0,
-            data: vec![1, 2, 3],
-        };
-        assert!(!regular_packet.is_last_data_packet());
-
-        // Last data packet (status byte 3 - both bits set)
-        let last_packet = DataPacket {
-            status_byte: 3,
-            file_id: 1,
-            packet_number: 5,
-            data: vec![1, 2, 3],
-        };
-        assert!(last_packet.is_last_data_packet());
+/// Everything the command line controls.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Options {
+    config: ClientConfig,
+    report_json: Option<PathBuf>,
+    write_partial: bool,
+    key_file: Option<PathBuf>,
+}
+
+/// Builds the options from the command line, or returns `None` if the user
+/// asked for help.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut config = ClientConfig::default();
+    let mut report_json = None;
+    let mut write_partial = false;
+    let mut key_file = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--server-host" => config.server_host = value()?,
+            "--server-port" => config.server_port = parse_value(&arg, &value()?)?,
+            "--bind-host" => config.bind_host = value()?,
+            "--bind-port" => config.bind_port = parse_value(&arg, &value()?)?,
+            "--accept-from" => config.accept_from = parse_accept_from(&arg, &value()?)?,
+            "--output-dir" => config.output_dir = PathBuf::from(value()?),
+            "--expected-files" => {
+                config.completion = Completion::FileCount(parse_value(&arg, &value()?)?);
+            }
+            "--quiet-period-ms" => {
+                config.completion = Completion::QuietPeriod(parse_millis(&arg, &value()?)?);
+            }
+            "--read-timeout-ms" => config.read_timeout = parse_millis(&arg, &value()?)?,
+            "--hello-interval-ms" => config.hello_interval = parse_millis(&arg, &value()?)?,
+            "--idle-timeout-ms" => config.idle_timeout = parse_millis(&arg, &value()?)?,
+            "--streaming" => config.reassembly = ReassemblyMode::Streaming,
+            "--strict" => config.strict = true,
+            "--nack" => config.extensions = config.extensions | Features::NACK,
+            "--resend-headers" => {
+                config.extensions = config.extensions | Features::RESEND_HEADER;
+            }
+            "--checksum" => config.extensions = config.extensions | Features::CHECKSUM,
+            "--nack-interval-ms" => config.nack_interval = parse_millis(&arg, &value()?)?,
+            "--fallback-name" => config.fallback_name = Some(value()?),
+            "--header-grace-ms" => config.header_grace = parse_millis(&arg, &value()?)?,
+            "--report-json" => report_json = Some(PathBuf::from(value()?)),
+            "--write-partial" => write_partial = true,
+            "--resume" => config.resume = true,
+            "--key-file" => key_file = Some(PathBuf::from(value()?)),
+            "--encrypt" => config.encrypt = true,
+            "--max-files" => config.limits.max_files = Some(parse_value(&arg, &value()?)?),
+            "--max-file-bytes" => {
+                config.limits.max_file_bytes = Some(parse_value(&arg, &value()?)?);
+            }
+            "--max-total-bytes" => {
+                config.limits.max_total_bytes = Some(parse_value(&arg, &value()?)?);
+            }
+            "--max-name-len" => config.limits.max_name_len = Some(parse_value(&arg, &value()?)?),
+            "--max-duration-ms" => {
+                config.limits.max_duration = Some(parse_millis(&arg, &value()?)?);
+            }
+            "--existing" => config.existing_files = parse_existing_files(&arg, &value()?)?,
+            "--recv-batch" => match parse_value(&arg, &value()?)? {
+                0 => return Err(format!("{arg} must be greater than zero")),
+                recv_batch => config.recv_batch = recv_batch,
+            },
+            "-h" | "--help" => return Ok(None),
+            _ => return Err(format!("unrecognized argument {arg}")),
+        }
     }
 
-    #[test]
-    fn test_process_last_data_packet() {
-        let mut file_manager = FileManager {
-            packet_groups: vec![],
-        };
+    Ok(Some(Options {
+        config,
+        report_json,
+        write_partial,
+        key_file,
+    }))
+}
 
-        // Create a packet with status byte 3 (last packet)
-        let last_data_packet_bytes: [u8; 6] = [3, 1, 0, 5, 3, 3]; // Status byte 3, packet #5
-        let packet = DataPacket::try_from(&last_data_packet_bytes[..]).unwrap();
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {value:?} for {flag}"))
+}
 
-        file_manager.process_packet(Packet::DataPacket(packet));
+fn parse_existing_files(flag: &str, value: &str) -> Result<ExistingFilePolicy, String> {
+    match value {
+        "overwrite" => Ok(ExistingFilePolicy::Overwrite),
+        "skip" => Ok(ExistingFilePolicy::Skip),
+        "rename" => Ok(ExistingFilePolicy::Rename),
+        "fail" => Ok(ExistingFilePolicy::Fail),
+        _ => Err(format!(
+            "invalid value {value:?} for {flag}; expected overwrite, skip, rename or fail"
+        )),
+    }
+}
 
-        // Check if expected_number_of_packets was set correctly
-        assert_eq!(
-            file_manager.packet_groups[0].expected_number_of_packets,
-            Some(6)
-        ); // Packet #5 + 1
+fn parse_accept_from(flag: &str, value: &str) -> Result<SourcePolicy, String> {
+    match value {
+        "server" => Ok(SourcePolicy::Server),
+        "first" => Ok(SourcePolicy::FirstResponder),
+        "any" => Ok(SourcePolicy::Any),
+        _ => Err(format!(
+            "invalid value {value:?} for {flag}; expected server, first or any"
+        )),
     }
+}
 
-    #[test]
-    fn test_received_all_packets() {
-        // Test with incomplete file
-        let incomplete_group = PacketGroup {
-            file_name: Some(OsString::from("test")),
-            file_id: 1,
-            expected_number_of_packets: Some(3),
-            packets: {
-                let mut packets = HashMap::new();
-                packets.insert(0, vec![1, 2, 3]);
-                packets.insert(1, vec![4, 5, 6]);
-                // Missing packet #2
-                packets
-            },
-        };
-
-        let file_manager = FileManager {
-            packet_groups: vec![incomplete_group],
-        };
-
-        assert!(!file_manager.received_all_packets());
-
-        // Test with complete file
-        let complete_group = PacketGroup {
-            file_name: Some(OsString::from("test")),
-            file_id: 1,
-            expected_number_of_packets: Some(3),
-            packets: {
-                let mut packets = HashMap::new();
-                packets.insert(0, vec![1, 2, 3]);
-                packets.insert(1, vec![4, 5, 6]);
-                packets.insert(2, vec![7, 8, 9]);
-                packets
-            },
-        };
+fn parse_millis(flag: &str, value: &str) -> Result<Duration, String> {
+    match parse_value(flag, value)? {
+        0 => Err(format!("{flag} must be greater than zero")),
+        millis => Ok(Duration::from_millis(millis)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use segmented_file_system_client::Limits;
 
-        let file_manager = FileManager {
-            packet_groups: vec![complete_group],
-        };
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
 
-        assert!(file_manager.received_all_packets());
+    #[test]
+    fn test_no_arguments_uses_defaults() {
+        assert_eq!(parse_args(args(&[])), Ok(Some(Options::default())));
     }
 
     #[test]
-    fn test_out_of_order_packet_processing() {
-        let mut file_manager = FileManager::default();
-
-        // Process data packets before header
-        let data_packet1 = DataPacket {
-            status_byte: 1,
-            file_id: 1,
-            packet_number: 0,
-            data: vec![1, 2, 3],
-        };
-
-        let data_packet2 = DataPacket {
-            status_byte: 3, // Last packet
-            file_id: 1,
-            packet_number: 1,
-            data: vec![4, 5, 6],
-        };
-
-        file_manager.process_packet(Packet::DataPacket(data_packet1));
-        file_manager.process_packet(Packet::DataPacket(data_packet2));
-
-        // Now process header
-        let header_packet = HeaderPacket {
-            status_byte: 0,
-            file_id: 1,
-            file_name: OsString::from("test.txt"),
-        };
-
-        file_manager.process_packet(Packet::HeaderPacket(header_packet));
-
-        // Verify everything is set correctly
-        assert_eq!(
-            file_manager.packet_groups[0].file_name,
-            Some(OsString::from("test.txt"))
-        );
+    fn test_parse_all_arguments() {
+        let options = parse_args(args(&[
+            "--server-host",
+            "10.0.0.5",
+            "--server-port",
+            "7000",
+            "--bind-host",
+            "127.0.0.1",
+            "--bind-port",
+            "0",
+            "--accept-from",
+            "first",
+            "--output-dir",
+            "downloads",
+            "--expected-files",
+            "2",
+            "--streaming",
+            "--strict",
+            "--nack",
+            "--resend-headers",
+            "--checksum",
+            "--nack-interval-ms",
+            "200",
+            "--fallback-name",
+            "file-{id}.bin",
+            "--header-grace-ms",
+            "1000",
+            "--recv-batch",
+            "1",
+            "--read-timeout-ms",
+            "100",
+            "--hello-interval-ms",
+            "500",
+            "--idle-timeout-ms",
+            "5000",
+            "--report-json",
+            "report.json",
+            "--write-partial",
+            "--resume",
+            "--existing",
+            "rename",
+            "--key-file",
+            "sfs.key",
+            "--encrypt",
+            "--max-files",
+            "4",
+            "--max-file-bytes",
+            "1000000",
+            "--max-total-bytes",
+            "3000000",
+            "--max-name-len",
+            "255",
+            "--max-duration-ms",
+            "90000",
+        ]))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(options.report_json, Some(PathBuf::from("report.json")));
+        assert!(options.write_partial);
+        assert_eq!(options.key_file, Some(PathBuf::from("sfs.key")));
         assert_eq!(
-            file_manager.packet_groups[0].expected_number_of_packets,
-            Some(2)
+            options.config,
+            ClientConfig {
+                server_host: String::from("10.0.0.5"),
+                server_port: 7000,
+                bind_host: String::from("127.0.0.1"),
+                bind_port: 0,
+                output_dir: PathBuf::from("downloads"),
+                completion: Completion::FileCount(2),
+                reassembly: ReassemblyMode::Streaming,
+                strict: true,
+                recv_batch: 1,
+                read_timeout: Duration::from_millis(100),
+                hello_interval: Duration::from_millis(500),
+                idle_timeout: Duration::from_secs(5),
+                extensions: Features::ALL,
+                nack_interval: Duration::from_millis(200),
+                fallback_name: Some(String::from("file-{id}.bin")),
+                header_grace: Duration::from_secs(1),
+                resume: true,
+                existing_files: ExistingFilePolicy::Rename,
+                accept_from: SourcePolicy::FirstResponder,
+                key: None,
+                encrypt: true,
+                limits: Limits {
+                    max_files: Some(4),
+                    max_file_bytes: Some(1_000_000),
+                    max_total_bytes: Some(3_000_000),
+                    max_name_len: Some(255),
+                    max_duration: Some(Duration::from_secs(90)),
+                },
+            }
         );
-        assert_eq!(file_manager.packet_groups[0].packets.len(), 2);
-        assert!(file_manager.received_all_packets());
     }
 
     #[test]
-    fn test_multiple_files_interleaved() {
-        let mut file_manager = FileManager::default();
-
-        // Process packets from two different files interleaved
-        let header1 = HeaderPacket {
-            status_byte: 0,
-            file_id: 1,
-            file_name: OsString::from("file1.txt"),
-        };
-
-        let data1_file1 = DataPacket {
-            status_byte: 1,
-            file_id: 1,
-            packet_number: 0,
-            data: vec![1, 2, 3],
-        };
-
-        let header2 = HeaderPacket {
-            status_byte: 0,
-            file_id: 2,
-            file_name: OsString::from("file2.txt"),
-        };
-
-        let data1_file2 = DataPacket {
-            status_byte: 1,
-            file_id: 2,
-            packet_number: 0,
-            data: vec![7, 8, 9],
-        };
-
-        let data2_file1 = DataPacket {
-            status_byte: 3, // Last packet
-            file_id: 1,
-            packet_number: 1,
-            data: vec![4, 5, 6],
-        };
-
-        let data2_file2 = DataPacket {
-            status_byte: 3, // Last packet
-            file_id: 2,
-            packet_number: 1,
-            data: vec![10, 11, 12],
-        };
-
-        // Process in interleaved order
-        file_manager.process_packet(Packet::HeaderPacket(header1));
-        file_manager.process_packet(Packet::DataPacket(data1_file2));
-        file_manager.process_packet(Packet::HeaderPacket(header2));
-        file_manager.process_packet(Packet::DataPacket(data1_file1));
-        file_manager.process_packet(Packet::DataPacket(data2_file2));
-        file_manager.process_packet(Packet::DataPacket(data2_file1));
-
-        assert!(file_manager.received_all_packets());
-        assert_eq!(file_manager.packet_groups.len(), 2);
+    fn test_parse_quiet_period() {
+        let config = parse_args(args(&["--quiet-period-ms", "1500"]))
+            .unwrap()
+            .unwrap()
+            .config;
+        assert_eq!(
+            config.completion,
+            Completion::QuietPeriod(Duration::from_millis(1500))
+        );
     }
 
     #[test]
-    fn test_edge_case_single_packet_file() {
-        let mut file_manager = FileManager::default();
-
-        // File with a single packet
-        let header = HeaderPacket {
-            status_byte: 0,
-            file_id: 1,
-            file_name: OsString::from("single.txt"),
-        };
-
-        let data = DataPacket {
-            status_byte: 3, // Last packet
-            file_id: 1,
-            packet_number: 0,
-            data: vec![1, 2, 3],
-        };
-
-        file_manager.process_packet(Packet::HeaderPacket(header));
-        file_manager.process_packet(Packet::DataPacket(data));
-
-        assert!(file_manager.received_all_packets());
-        assert_eq!(
-            file_manager.packet_groups[0].expected_number_of_packets,
-            Some(1)
-        );
+    fn test_parse_errors() {
+        assert!(parse_args(args(&["--server-port"])).is_err());
+        assert!(parse_args(args(&["--bind-port", "70000"])).is_err());
+        assert!(parse_args(args(&["--frobnicate"])).is_err());
+        assert!(parse_args(args(&["--read-timeout-ms", "0"])).is_err());
+        assert!(parse_args(args(&["--recv-batch", "0"])).is_err());
+        assert!(parse_args(args(&["--expected-files", "-1"])).is_err());
+        assert!(parse_args(args(&["--existing", "clobber"])).is_err());
+        assert!(parse_args(args(&["--accept-from", "anyone"])).is_err());
+        assert_eq!(parse_args(args(&["--help"])), Ok(None));
     }
 }
@@ -0,0 +1,189 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The environment variable the key is read from when no key file is given.
+pub const KEY_ENV: &str = "SFS_KEY";
+/// The length of the MAC at the end of every authenticated datagram.
+pub const TAG_LEN: usize = 32;
+/// The length of a session nonce.
+pub const NONCE_LEN: usize = 16;
+
+/// Picked by the client for each transfer and sent in its hello, so datagrams
+/// recorded from another transfer don't verify in this one.
+pub type Nonce = [u8; NONCE_LEN];
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A secret shared by the client and the server, with which every datagram
+/// between them is authenticated.
+#[derive(Clone)]
+pub struct PacketKey {
+    bytes: Vec<u8>,
+    mac: HmacSha256,
+}
+
+impl PacketKey {
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if `key` is empty.
+    pub fn new(key: impl Into<Vec<u8>>) -> io::Result<Self> {
+        let bytes = key.into();
+        if bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the key is empty",
+            ));
+        }
+        let mac = HmacSha256::new_from_slice(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(PacketKey { bytes, mac })
+    }
+
+    /// Reads the key from the file at `path`. A trailing newline isn't part
+    /// of the key, so `echo secret > key` works.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or holds nothing else.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let mut bytes = fs::read(path)?;
+        while bytes
+            .last()
+            .is_some_and(|&byte| byte == b'\n' || byte == b'\r')
+        {
+            bytes.pop();
+        }
+        PacketKey::new(bytes)
+    }
+
+    /// The key in the `KEY_ENV` environment variable, if it is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if the variable is set but empty.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        env::var_os(KEY_ENV)
+            .map(|key| PacketKey::new(key.into_encoded_bytes()))
+            .transpose()
+    }
+}
+
+impl PacketKey {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl PartialEq for PacketKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for PacketKey {}
+
+impl fmt::Debug for PacketKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacketKey(..)")
+    }
+}
+
+/// Signs and verifies the datagrams of one transfer.
+///
+/// An authenticated datagram is the usual packet or control message followed
+/// by an HMAC-SHA256 over the session's nonce and then those contents, so
+/// only a host that knows the key can produce one, and only for this
+/// session.
+#[derive(Clone)]
+pub struct Authenticator {
+    mac: HmacSha256,
+    nonce: Nonce,
+}
+
+impl Authenticator {
+    #[must_use]
+    pub fn new(key: &PacketKey, nonce: Nonce) -> Self {
+        Authenticator {
+            mac: key.mac.clone(),
+            nonce,
+        }
+    }
+
+    /// A new session with a random nonce.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the operating system has no randomness to give.
+    pub fn random(key: &PacketKey) -> io::Result<Self> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Authenticator::new(key, nonce))
+    }
+
+    #[must_use]
+    pub fn nonce(&self) -> &Nonce {
+        &self.nonce
+    }
+
+    /// `contents` with its tag appended.
+    #[must_use]
+    pub fn sign(&self, contents: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(contents.len() + TAG_LEN);
+        datagram.extend(contents);
+        datagram.extend(self.mac_of(contents).finalize().into_bytes());
+        datagram
+    }
+
+    /// The contents of `datagram` if its tag is right, or `None` for a
+    /// datagram that was forged, altered, cut short or sent in another
+    /// session. The tag is compared in constant time.
+    #[must_use]
+    pub fn verify<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let (contents, tag) = datagram.split_at_checked(datagram.len().checked_sub(TAG_LEN)?)?;
+        self.mac_of(contents).verify_slice(tag).ok()?;
+        Some(contents)
+    }
+
+    fn mac_of(&self, contents: &[u8]) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        mac.update(&self.nonce);
+        mac.update(contents);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_signed_datagrams_from_the_session_verify() {
+        let key = PacketKey::new("secret").unwrap();
+        let session = Authenticator::new(&key, [1; NONCE_LEN]);
+        let datagram = session.sign(&[3, 0, 0, 0, 42]);
+
+        assert_eq!(datagram.len(), 5 + TAG_LEN);
+        assert_eq!(session.verify(&datagram), Some(&[3, 0, 0, 0, 42][..]));
+
+        let mut altered = datagram.clone();
+        altered[4] = 43;
+        assert_eq!(session.verify(&altered), None);
+        assert_eq!(session.verify(&datagram[..TAG_LEN - 1]), None);
+        assert_eq!(session.verify(&[3, 0, 0, 0, 42]), None);
+
+        let other_session = Authenticator::new(&key, [2; NONCE_LEN]);
+        assert_eq!(other_session.verify(&datagram), None);
+        let other_key = PacketKey::new("guess").unwrap();
+        assert_eq!(
+            Authenticator::new(&other_key, [1; NONCE_LEN]).verify(&datagram),
+            None
+        );
+
+        assert!(PacketKey::new("").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::packet::PacketParseError;
+use crate::packet::{PacketParseError, DATA_PACKET_BIT, LAST_PACKET_BIT};
 use std::convert::TryFrom;
 
 #[derive(Debug, PartialEq)]
@@ -9,42 +9,132 @@ pub struct DataPacket {
     pub data: Vec<u8>,
 }
 
+/// A data packet that borrows its data from the buffer it was parsed from,
+/// so parsing it doesn't allocate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataPacketRef<'a> {
+    pub status_byte: u8,
+    pub file_id: u8,
+    pub packet_number: u16,
+    pub data: &'a [u8],
+}
+
+/// Status byte, file ID and the two packet number bytes.
+pub const DATA_PACKET_HEADER_LEN: usize = 4;
+
 impl DataPacket {
+    /// Builds a data packet, setting the status byte's last-packet bit when
+    /// `is_last` is true.
+    #[must_use]
+    pub fn new(file_id: u8, packet_number: u16, data: Vec<u8>, is_last: bool) -> Self {
+        let status_byte = if is_last {
+            DATA_PACKET_BIT | LAST_PACKET_BIT
+        } else {
+            DATA_PACKET_BIT
+        };
+
+        DataPacket {
+            status_byte,
+            file_id,
+            packet_number,
+            data,
+        }
+    }
+
+    #[must_use]
     pub fn is_last_data_packet(&self) -> bool {
         // If the second bit is 1 (status byte % 4 == 3), it's the last packet
-        self.status_byte & 0b10 != 0
+        self.status_byte & LAST_PACKET_BIT != 0
+    }
+
+    #[must_use]
+    pub fn encoded_len(&self) -> usize {
+        DATA_PACKET_HEADER_LEN + self.data.len()
+    }
+
+    /// Appends the wire representation of this packet to `buffer`.
+    pub fn write_to(&self, buffer: &mut Vec<u8>) {
+        buffer.reserve(self.encoded_len());
+        buffer.push(self.status_byte);
+        buffer.push(self.file_id);
+        // Packet numbers are big endian on the wire
+        buffer.extend_from_slice(&self.packet_number.to_be_bytes());
+        buffer.extend_from_slice(&self.data);
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut buffer);
+        buffer
+    }
+
+    #[must_use]
+    pub fn as_ref(&self) -> DataPacketRef<'_> {
+        DataPacketRef {
+            status_byte: self.status_byte,
+            file_id: self.file_id,
+            packet_number: self.packet_number,
+            data: &self.data,
+        }
     }
 }
 
-impl TryFrom<&[u8]> for DataPacket {
+impl DataPacketRef<'_> {
+    #[must_use]
+    pub fn is_last_data_packet(&self) -> bool {
+        self.status_byte & LAST_PACKET_BIT != 0
+    }
+
+    /// Copies the data into an owned `DataPacket`.
+    #[must_use]
+    pub fn into_owned(self) -> DataPacket {
+        DataPacket {
+            status_byte: self.status_byte,
+            file_id: self.file_id,
+            packet_number: self.packet_number,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for DataPacketRef<'a> {
     type Error = PacketParseError;
 
-    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+    fn try_from(buffer: &'a [u8]) -> Result<Self, Self::Error> {
         // Data packet needs at least 4 bytes: status byte, file ID, and 2 bytes for packet number
-        if buffer.len() < 4 {
+        if buffer.len() < DATA_PACKET_HEADER_LEN {
             return Err(PacketParseError::InvalidPacketLength);
         }
 
         let status_byte = buffer[0];
-        
+
         // Status byte must be odd for data packets
-        if status_byte & 1 == 0 {
+        if status_byte & DATA_PACKET_BIT == 0 {
             return Err(PacketParseError::InvalidDataPacket);
         }
-        
+
         let file_id = buffer[1];
-        
+
         // Construct packet number using big endian (first byte is most significant)
         let packet_number = u16::from_be_bytes([buffer[2], buffer[3]]);
-        
+
         // The rest of the buffer is the data
-        let data = buffer[4..].to_vec();
-        
-        Ok(DataPacket {
+        let data = &buffer[DATA_PACKET_HEADER_LEN..];
+
+        Ok(DataPacketRef {
             status_byte,
             file_id,
             packet_number,
             data,
         })
     }
-}
\ No newline at end of file
+}
+
+impl TryFrom<&[u8]> for DataPacket {
+    type Error = PacketParseError;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        DataPacketRef::try_from(buffer).map(DataPacketRef::into_owned)
+    }
+}
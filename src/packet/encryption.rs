@@ -0,0 +1,223 @@
+use crate::packet::auth::{Authenticator, PacketKey, NONCE_LEN};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use x25519_dalek::StaticSecret;
+
+/// The length of an X25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// The length of the sequence number in front of every sealed datagram.
+pub const SEQUENCE_LEN: usize = 8;
+/// The length of the Poly1305 tag at the end of every sealed datagram.
+pub const AEAD_TAG_LEN: usize = 16;
+/// How much longer a datagram is once it is sealed.
+pub const OVERHEAD: usize = SEQUENCE_LEN + AEAD_TAG_LEN;
+
+/// One side's half of a key exchange, as sent in the clear.
+pub type PublicKey = [u8; PUBLIC_KEY_LEN];
+
+/// Signs and verifies the encrypted hello of a client with a pre-shared
+/// key, the same way as a signed hello. The nonce is the start of the
+/// client's public key, which is new for every transfer.
+pub(crate) fn hello_authenticator(key: &PacketKey, public_key: &PublicKey) -> Authenticator {
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&public_key[..NONCE_LEN]);
+    Authenticator::new(key, nonce)
+}
+
+/// Which end of the transfer a `KeyExchange` is for, so the two ends derive
+/// the same pair of keys but use them in opposite directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// A fresh X25519 key pair for one transfer. The client sends its public key
+/// in its hello and the server answers with its own, after which each side
+/// can `finish` the exchange and start sealing datagrams.
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    /// # Errors
+    ///
+    /// Returns an error if the operating system has no randomness to give.
+    pub fn new() -> io::Result<Self> {
+        let mut secret = [0; 32];
+        getrandom::getrandom(&mut secret).map_err(|e| io::Error::other(e.to_string()))?;
+        let secret = StaticSecret::from(secret);
+        let public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        Ok(KeyExchange { secret, public_key })
+    }
+
+    #[must_use]
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// The cipher for the transfer, given the other side's public key.
+    ///
+    /// Both public keys and, if there is one, the pre-shared `key` go into
+    /// the derivation. Without a pre-shared key the exchange keeps the
+    /// transfer from eavesdroppers but not from a host that can intercept
+    /// and answer the hello itself; with one, such a host can't derive the
+    /// keys either. Returns `None` for a public key that would leave the
+    /// shared secret predictable.
+    #[must_use]
+    pub fn finish(
+        &self,
+        role: Role,
+        their_key: &PublicKey,
+        key: Option<&PacketKey>,
+    ) -> Option<Cipher> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(*their_key));
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        let (client_key, server_key) = match role {
+            Role::Client => (&self.public_key, their_key),
+            Role::Server => (their_key, &self.public_key),
+        };
+        let mut salt = [0; 2 * PUBLIC_KEY_LEN];
+        salt[..PUBLIC_KEY_LEN].copy_from_slice(client_key);
+        salt[PUBLIC_KEY_LEN..].copy_from_slice(server_key);
+        let mut secret = shared.as_bytes().to_vec();
+        if let Some(key) = key {
+            secret.extend(key.as_bytes());
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &secret);
+        let mut client_to_server = [0; 32];
+        let mut server_to_client = [0; 32];
+        hkdf.expand(b"sfs client to server", &mut client_to_server)
+            .ok()?;
+        hkdf.expand(b"sfs server to client", &mut server_to_client)
+            .ok()?;
+        let (sealing, opening) = match role {
+            Role::Client => (client_to_server, server_to_client),
+            Role::Server => (server_to_client, client_to_server),
+        };
+        Some(Cipher {
+            sealing: ChaCha20Poly1305::new(&sealing.into()),
+            opening: ChaCha20Poly1305::new(&opening.into()),
+            next_sequence: AtomicU64::new(0),
+        })
+    }
+}
+
+/// Seals and opens the datagrams of one encrypted transfer with
+/// ChaCha20-Poly1305, using one key for each direction.
+///
+/// A sealed datagram is a sequence number followed by the encrypted packet
+/// or control message and its tag. The sequence number makes the nonce, and
+/// goes up with every datagram sealed, so no nonce is ever used twice with
+/// a key even though datagrams can be lost or arrive out of order.
+pub struct Cipher {
+    sealing: ChaCha20Poly1305,
+    opening: ChaCha20Poly1305,
+    next_sequence: AtomicU64,
+}
+
+impl Cipher {
+    /// `contents` encrypted, as they go on the wire.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if `contents` is far too long to encrypt.
+    pub fn seal(&self, contents: &[u8]) -> io::Result<Vec<u8>> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let sealed = self
+            .sealing
+            .encrypt(&nonce(sequence).into(), contents)
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "datagram too long to encrypt")
+            })?;
+        let mut datagram = Vec::with_capacity(SEQUENCE_LEN + sealed.len());
+        datagram.extend(sequence.to_be_bytes());
+        datagram.extend(sealed);
+        Ok(datagram)
+    }
+
+    /// The contents of `datagram`, or `None` if it wasn't sealed by the
+    /// other end of this transfer or was altered on the way.
+    #[must_use]
+    pub fn open(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let (sequence, sealed) = datagram.split_first_chunk::<SEQUENCE_LEN>()?;
+        let sequence = u64::from_be_bytes(*sequence);
+        self.opening.decrypt(&nonce(sequence).into(), sealed).ok()
+    }
+}
+
+/// The 96-bit nonce for the datagram with `sequence`.
+fn nonce(sequence: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ciphers(client_key: Option<&PacketKey>, server_key: Option<&PacketKey>) -> (Cipher, Cipher) {
+        let client = KeyExchange::new().unwrap();
+        let server = KeyExchange::new().unwrap();
+        (
+            client
+                .finish(Role::Client, server.public_key(), client_key)
+                .unwrap(),
+            server
+                .finish(Role::Server, client.public_key(), server_key)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_each_side_opens_what_the_other_sealed() {
+        let (client, server) = ciphers(None, None);
+        let header = [0, 7, b'a', b'.', b't', b'x', b't'];
+        let first = server.seal(&header).unwrap();
+        let second = server.seal(&header).unwrap();
+
+        assert_eq!(first.len(), header.len() + OVERHEAD);
+        assert!(!first.windows(5).any(|window| window == b"a.txt"));
+        assert_ne!(first, second);
+        assert_eq!(client.open(&second).unwrap(), header);
+        assert_eq!(client.open(&first).unwrap(), header);
+        assert_eq!(server.open(&client.seal(&[1]).unwrap()).unwrap(), [1]);
+
+        // Each key only works in one direction
+        assert_eq!(server.open(&first), None);
+        let mut altered = first.clone();
+        altered[SEQUENCE_LEN] ^= 1;
+        assert_eq!(client.open(&altered), None);
+        assert_eq!(client.open(&first[..SEQUENCE_LEN]), None);
+    }
+
+    #[test]
+    fn test_pre_shared_keys_must_match() {
+        let key = PacketKey::new("secret").unwrap();
+        let (client, server) = ciphers(Some(&key), Some(&key));
+        assert!(client.open(&server.seal(&[1]).unwrap()).is_some());
+
+        let (client, server) = ciphers(Some(&key), None);
+        assert!(client.open(&server.seal(&[1]).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_predictable_shared_secrets_are_refused() {
+        let exchange = KeyExchange::new().unwrap();
+        assert!(exchange
+            .finish(Role::Server, &[0; PUBLIC_KEY_LEN], None)
+            .is_none());
+    }
+}
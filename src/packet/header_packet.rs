@@ -1,7 +1,7 @@
-use crate::packet::PacketParseError;
+use crate::packet::{PacketParseError, DATA_PACKET_BIT};
 use std::convert::TryFrom;
-use std::ffi::OsString;
-use std::os::unix::ffi::OsStringExt;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
 
 #[derive(Debug, PartialEq)]
 pub struct HeaderPacket {
@@ -10,34 +10,106 @@ pub struct HeaderPacket {
     pub file_name: OsString,
 }
 
-impl TryFrom<&[u8]> for HeaderPacket {
+/// A header packet that borrows its file name from the buffer it was parsed
+/// from, so parsing it doesn't allocate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeaderPacketRef<'a> {
+    pub status_byte: u8,
+    pub file_id: u8,
+    pub file_name: &'a OsStr,
+}
+
+/// Status byte and file ID.
+pub const HEADER_PACKET_HEADER_LEN: usize = 2;
+
+impl HeaderPacket {
+    #[must_use]
+    pub fn new(file_id: u8, file_name: OsString) -> Self {
+        HeaderPacket {
+            status_byte: 0,
+            file_id,
+            file_name,
+        }
+    }
+
+    #[must_use]
+    pub fn encoded_len(&self) -> usize {
+        HEADER_PACKET_HEADER_LEN + self.file_name.as_bytes().len()
+    }
+
+    /// Appends the wire representation of this packet to `buffer`.
+    pub fn write_to(&self, buffer: &mut Vec<u8>) {
+        buffer.reserve(self.encoded_len());
+        buffer.push(self.status_byte);
+        buffer.push(self.file_id);
+        buffer.extend_from_slice(self.file_name.as_bytes());
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut buffer);
+        buffer
+    }
+
+    #[must_use]
+    pub fn as_ref(&self) -> HeaderPacketRef<'_> {
+        HeaderPacketRef {
+            status_byte: self.status_byte,
+            file_id: self.file_id,
+            file_name: &self.file_name,
+        }
+    }
+}
+
+impl HeaderPacketRef<'_> {
+    /// Copies the file name into an owned `HeaderPacket`.
+    #[must_use]
+    pub fn into_owned(self) -> HeaderPacket {
+        HeaderPacket {
+            status_byte: self.status_byte,
+            file_id: self.file_id,
+            file_name: self.file_name.to_os_string(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for HeaderPacketRef<'a> {
     type Error = PacketParseError;
 
-    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+    fn try_from(buffer: &'a [u8]) -> Result<Self, Self::Error> {
         // Header packet needs at least 2 bytes: status byte and file ID
-        if buffer.len() < 2 {
+        if buffer.len() < HEADER_PACKET_HEADER_LEN {
             return Err(PacketParseError::InvalidPacketLength);
         }
 
         let status_byte = buffer[0];
-        
+
         // Status byte must be even for header packets
-        if status_byte & 1 != 0 {
+        if status_byte & DATA_PACKET_BIT != 0 {
             return Err(PacketParseError::InvalidHeaderPacket);
         }
-        
+
         let file_id = buffer[1];
-        
+
         // The rest of the buffer is the filename
-        let file_name_bytes = &buffer[2..];
-        
-        // Convert to OsString - handles non-UTF8 filenames
-        let file_name = OsString::from_vec(file_name_bytes.to_vec());
-        
-        Ok(HeaderPacket {
+        let file_name_bytes = &buffer[HEADER_PACKET_HEADER_LEN..];
+
+        // Borrow as an OsStr - handles non-UTF8 filenames
+        let file_name = OsStr::from_bytes(file_name_bytes);
+
+        Ok(HeaderPacketRef {
             status_byte,
             file_id,
             file_name,
         })
     }
-}
\ No newline at end of file
+}
+
+impl TryFrom<&[u8]> for HeaderPacket {
+    type Error = PacketParseError;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        HeaderPacketRef::try_from(buffer).map(HeaderPacketRef::into_owned)
+    }
+}
@@ -1,9 +1,34 @@
+pub mod auth;
 pub mod data_packet;
+pub mod encryption;
 pub mod header_packet;
 
-use data_packet::DataPacket;
-use header_packet::HeaderPacket;
+use auth::Authenticator;
+use data_packet::{DataPacket, DataPacketRef};
+use encryption::Cipher;
+use header_packet::{HeaderPacket, HeaderPacketRef};
+use std::borrow::Cow;
 use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Set in the status byte of every data packet (and clear for header packets).
+pub const DATA_PACKET_BIT: u8 = 0b01;
+/// Set in the status byte of the data packet holding the final chunk of a file.
+pub const LAST_PACKET_BIT: u8 = 0b10;
+/// Files are split into chunks of this many bytes, one per data packet.
+pub const MAX_DATA_LEN: usize = 1024;
+/// A full data packet: 4 bytes of bookkeeping plus a full chunk of data.
+pub const MAX_PACKET_LEN: usize = data_packet::DATA_PACKET_HEADER_LEN + MAX_DATA_LEN;
+/// The longest datagram either end sends: a full data packet, plus its MAC
+/// or encryption overhead when the transfer is protected.
+pub const MAX_DATAGRAM_LEN: usize = MAX_PACKET_LEN
+    + if auth::TAG_LEN > encryption::OVERHEAD {
+        auth::TAG_LEN
+    } else {
+        encryption::OVERHEAD
+    };
 
 #[derive(Debug, PartialEq)]
 pub enum Packet {
@@ -11,7 +36,50 @@ pub enum Packet {
     DataPacket(DataPacket),
 }
 
+/// A `Packet` that borrows from the buffer it was parsed from, so the receive
+/// loop can handle a datagram without allocating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketRef<'a> {
+    HeaderPacket(HeaderPacketRef<'a>),
+    DataPacket(DataPacketRef<'a>),
+}
+
+/// How the datagrams of a transfer are protected once it has started. Either
+/// end wraps everything it sends with `seal`, and unwraps everything it
+/// receives with `open` before parsing it.
+pub enum Protection {
+    /// Each datagram carries a MAC made with a pre-shared key.
+    Signed(Authenticator),
+    /// Each datagram is encrypted with keys from a key exchange.
+    Sealed(Cipher),
+}
+
+impl Protection {
+    /// `contents` as they go on the wire.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if `contents` is far too long to encrypt.
+    pub fn seal(&self, contents: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Protection::Signed(authenticator) => Ok(authenticator.sign(contents)),
+            Protection::Sealed(cipher) => cipher.seal(contents),
+        }
+    }
+
+    /// The contents of `datagram`, or `None` if it doesn't come from the
+    /// other end of this transfer.
+    #[must_use]
+    pub fn open<'a>(&self, datagram: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        match self {
+            Protection::Signed(authenticator) => authenticator.verify(datagram).map(Cow::Borrowed),
+            Protection::Sealed(cipher) => cipher.open(datagram).map(Cow::Owned),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum PacketParseError {
     InvalidPacketType,
     InvalidPacketLength,
@@ -19,25 +87,105 @@ pub enum PacketParseError {
     InvalidDataPacket,
 }
 
-impl TryFrom<&[u8]> for Packet {
+impl fmt::Display for PacketParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            PacketParseError::InvalidPacketType => "unknown packet type",
+            PacketParseError::InvalidPacketLength => "packet is too short",
+            PacketParseError::InvalidHeaderPacket => "malformed header packet",
+            PacketParseError::InvalidDataPacket => "malformed data packet",
+        };
+        f.write_str(message)
+    }
+}
+
+impl Error for PacketParseError {}
+
+impl Packet {
+    /// The number of bytes this packet occupies on the wire.
+    #[must_use]
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Packet::HeaderPacket(header_packet) => header_packet.encoded_len(),
+            Packet::DataPacket(data_packet) => data_packet.encoded_len(),
+        }
+    }
+
+    /// Appends the wire representation of this packet to `buffer`.
+    ///
+    /// This is the exact inverse of `Packet::try_from`, so a caller can reuse
+    /// one buffer (clearing it between packets) when sending many packets.
+    pub fn write_to(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Packet::HeaderPacket(header_packet) => header_packet.write_to(buffer),
+            Packet::DataPacket(data_packet) => data_packet.write_to(buffer),
+        }
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut buffer);
+        buffer
+    }
+
+    #[must_use]
+    pub fn as_ref(&self) -> PacketRef<'_> {
+        match self {
+            Packet::HeaderPacket(header_packet) => PacketRef::HeaderPacket(header_packet.as_ref()),
+            Packet::DataPacket(data_packet) => PacketRef::DataPacket(data_packet.as_ref()),
+        }
+    }
+}
+
+impl PacketRef<'_> {
+    #[must_use]
+    pub fn file_id(&self) -> u8 {
+        match self {
+            PacketRef::HeaderPacket(header_packet) => header_packet.file_id,
+            PacketRef::DataPacket(data_packet) => data_packet.file_id,
+        }
+    }
+
+    /// Copies the packet into an owned `Packet`.
+    #[must_use]
+    pub fn into_owned(self) -> Packet {
+        match self {
+            PacketRef::HeaderPacket(header_packet) => {
+                Packet::HeaderPacket(header_packet.into_owned())
+            }
+            PacketRef::DataPacket(data_packet) => Packet::DataPacket(data_packet.into_owned()),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for PacketRef<'a> {
     type Error = PacketParseError;
 
-    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+    fn try_from(buffer: &'a [u8]) -> Result<Self, Self::Error> {
         if buffer.is_empty() {
             return Err(PacketParseError::InvalidPacketLength);
         }
 
         let status_byte = buffer[0];
-        
+
         // Even status byte (least significant bit is 0) means header packet
-        if status_byte & 1 == 0 {
-            let header_packet = HeaderPacket::try_from(buffer)?;
-            Ok(Packet::HeaderPacket(header_packet))
-        } 
+        if status_byte & DATA_PACKET_BIT == 0 {
+            let header_packet = HeaderPacketRef::try_from(buffer)?;
+            Ok(PacketRef::HeaderPacket(header_packet))
+        }
         // Odd status byte (least significant bit is 1) means data packet
         else {
-            let data_packet = DataPacket::try_from(buffer)?;
-            Ok(Packet::DataPacket(data_packet))
+            let data_packet = DataPacketRef::try_from(buffer)?;
+            Ok(PacketRef::DataPacket(data_packet))
         }
     }
-}
\ No newline at end of file
+}
+
+impl TryFrom<&[u8]> for Packet {
+    type Error = PacketParseError;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        PacketRef::try_from(buffer).map(PacketRef::into_owned)
+    }
+}
@@ -0,0 +1,208 @@
+use crate::chunk_buffer::ChunkBuffer;
+use crate::PacketGroup;
+use std::ops::Index;
+
+const SLOTS: usize = u8::MAX as usize + 1;
+
+/// The `PacketGroup`s of a transfer, one slot per possible file ID.
+///
+/// Looking a group up by ID is a single index, and the number of groups, of
+/// complete groups and of groups still in progress are kept as running
+/// counts, so no per-packet work has to walk the other files in flight.
+pub struct PacketGroups {
+    slots: Box<[Option<PacketGroup>]>,
+    len: usize,
+    complete: usize,
+    in_progress: usize,
+}
+
+impl Default for PacketGroups {
+    fn default() -> Self {
+        PacketGroups {
+            slots: (0..SLOTS).map(|_| None).collect(),
+            len: 0,
+            complete: 0,
+            in_progress: 0,
+        }
+    }
+}
+
+impl PacketGroups {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn get(&self, file_id: u8) -> Option<&PacketGroup> {
+        self.slots[usize::from(file_id)].as_ref()
+    }
+
+    /// Adds `packet_group`, replacing any group with the same file ID.
+    pub fn insert(&mut self, packet_group: PacketGroup) {
+        let file_id = packet_group.file_id;
+        self.remove(file_id);
+
+        self.len += 1;
+        if packet_group.is_complete() {
+            self.complete += 1;
+        }
+        if is_in_progress(&packet_group) {
+            self.in_progress += 1;
+        }
+        self.slots[usize::from(file_id)] = Some(packet_group);
+    }
+
+    pub fn remove(&mut self, file_id: u8) -> Option<PacketGroup> {
+        let packet_group = self.slots[usize::from(file_id)].take()?;
+        self.len -= 1;
+        if packet_group.is_complete() {
+            self.complete -= 1;
+        }
+        if is_in_progress(&packet_group) {
+            self.in_progress -= 1;
+        }
+        Some(packet_group)
+    }
+
+    /// Runs `update` on the group for `file_id`, creating an empty one first
+    /// if needed, and keeps the counts up to date.
+    pub(crate) fn update<T>(
+        &mut self,
+        file_id: u8,
+        update: impl FnOnce(&mut PacketGroup) -> T,
+    ) -> T {
+        let slot = &mut self.slots[usize::from(file_id)];
+        let packet_group = if let Some(packet_group) = slot {
+            packet_group
+        } else {
+            self.len += 1;
+            self.in_progress += 1;
+            slot.insert(PacketGroup {
+                file_name: None,
+                file_id,
+                expected_number_of_packets: None,
+                chunks: ChunkBuffer::default(),
+                spool: None,
+                written: false,
+                digest: None,
+                needs_digest: false,
+                failed: false,
+            })
+        };
+
+        let was_complete = packet_group.is_complete();
+        let was_in_progress = is_in_progress(packet_group);
+        let result = update(packet_group);
+        match (was_complete, packet_group.is_complete()) {
+            (false, true) => self.complete += 1,
+            (true, false) => self.complete -= 1,
+            _ => {}
+        }
+        match (was_in_progress, is_in_progress(packet_group)) {
+            (false, true) => self.in_progress += 1,
+            (true, false) => self.in_progress -= 1,
+            _ => {}
+        }
+        result
+    }
+
+    /// The number of files seen so far.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether every file seen so far has its name and all of its data packets.
+    #[must_use]
+    pub fn all_complete(&self) -> bool {
+        self.complete == self.len
+    }
+
+    /// The number of files neither written nor given up on yet.
+    #[must_use]
+    pub fn in_progress(&self) -> usize {
+        self.in_progress
+    }
+
+    /// The groups in file ID order.
+    pub fn iter(&self) -> impl Iterator<Item = &PacketGroup> {
+        self.slots.iter().flatten()
+    }
+
+    /// The IDs of the files seen so far, in order.
+    pub fn file_ids(&self) -> impl Iterator<Item = u8> + '_ {
+        self.iter().map(PacketGroup::file_id)
+    }
+}
+
+fn is_in_progress(packet_group: &PacketGroup) -> bool {
+    !packet_group.written && !packet_group.failed
+}
+
+/// Panics if there is no group for `file_id`, like indexing a `HashMap`.
+impl Index<u8> for PacketGroups {
+    type Output = PacketGroup;
+
+    fn index(&self, file_id: u8) -> &PacketGroup {
+        self.get(file_id).expect("no packet group for this file ID")
+    }
+}
+
+impl FromIterator<PacketGroup> for PacketGroups {
+    fn from_iter<I: IntoIterator<Item = PacketGroup>>(iter: I) -> Self {
+        let mut packet_groups = PacketGroups::new();
+        for packet_group in iter {
+            packet_groups.insert(packet_group);
+        }
+        packet_groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    #[test]
+    fn test_update_tracks_completion() {
+        let mut packet_groups = PacketGroups::new();
+        assert!(packet_groups.is_empty());
+
+        packet_groups.update(7, |packet_group| {
+            packet_group.file_name = Some(OsString::from("seven"));
+            packet_group.chunks.write_chunk(0, &[1], false);
+        });
+        packet_groups.update(200, |packet_group| {
+            packet_group.file_name = Some(OsString::from("empty"));
+        });
+        assert_eq!(packet_groups.len(), 2);
+        assert!(!packet_groups.all_complete());
+
+        packet_groups.update(7, |packet_group| {
+            packet_group.expected_number_of_packets = Some(1);
+        });
+        assert!(!packet_groups.all_complete());
+        packet_groups.update(200, |packet_group| {
+            packet_group.expected_number_of_packets = Some(0);
+        });
+        assert!(packet_groups.all_complete());
+        assert_eq!(packet_groups.in_progress(), 2);
+
+        packet_groups.update(200, |packet_group| packet_group.written = true);
+        packet_groups.update(3, |packet_group| packet_group.failed = true);
+        assert_eq!(packet_groups.in_progress(), 1);
+
+        assert_eq!(packet_groups.file_ids().collect::<Vec<_>>(), [3, 7, 200]);
+        assert!(packet_groups.remove(7).is_some());
+        assert_eq!(packet_groups.len(), 2);
+        assert_eq!(packet_groups.in_progress(), 0);
+        assert!(packet_groups.all_complete());
+        assert!(packet_groups.get(7).is_none());
+    }
+}
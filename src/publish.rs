@@ -0,0 +1,201 @@
+use crate::file_manager::ExistingFilePolicy;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tells apart the temporary files of publishes running at the same time.
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Where a file ended up once it was published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Published {
+    /// The file is now at this path, which is the one asked for unless
+    /// `ExistingFilePolicy::Rename` had to pick another.
+    At(PathBuf),
+    /// A file was already there and `ExistingFilePolicy::Skip` kept it.
+    Skipped,
+}
+
+/// Writes a file at `path` without ever leaving a half-written file there:
+/// `write` fills a temporary file in the same directory, which is synced to
+/// disk and then moved into place as `policy` says.
+pub(crate) fn publish_with(
+    path: &Path,
+    policy: ExistingFilePolicy,
+    write: impl FnOnce(&File) -> io::Result<()>,
+) -> io::Result<Published> {
+    let temp_path = temp_path_for(path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)?;
+
+    let result = write(&file)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| place(&temp_path, path, policy));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Moves the finished, synced file at `temp_path` to `path` as `policy`
+/// says, removing `temp_path` whatever happens to it.
+///
+/// Overwriting is a plain rename, which replaces any existing file in one
+/// step. The other policies hard link the file into place instead, which
+/// fails rather than replacing a file that is already there, so one that
+/// appears at the last moment is never clobbered either.
+pub(crate) fn place(
+    temp_path: &Path,
+    path: &Path,
+    policy: ExistingFilePolicy,
+) -> io::Result<Published> {
+    let published = match policy {
+        ExistingFilePolicy::Overwrite => {
+            fs::rename(temp_path, path).map(|()| Published::At(path.to_path_buf()))
+        }
+        ExistingFilePolicy::Skip => match fs::hard_link(temp_path, path) {
+            Ok(()) => Ok(Published::At(path.to_path_buf())),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(Published::Skipped),
+            Err(e) => Err(e),
+        },
+        ExistingFilePolicy::Fail => {
+            fs::hard_link(temp_path, path).map(|()| Published::At(path.to_path_buf()))
+        }
+        ExistingFilePolicy::Rename => link_with_suffix(temp_path, path).map(Published::At),
+    };
+    let _ = fs::remove_file(temp_path);
+
+    if let (Ok(_), Some(parent)) = (&published, path.parent()) {
+        // Makes the new directory entry durable too; not every platform
+        // can sync a directory, and the file itself is already safe
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    published
+}
+
+/// Hard links `temp_path` at `path`, or at the first of `name-1.ext`,
+/// `name-2.ext`, ... that doesn't exist yet, returning where it went.
+fn link_with_suffix(temp_path: &Path, path: &Path) -> io::Result<PathBuf> {
+    for n in 0..=u32::MAX {
+        let candidate = if n == 0 {
+            path.to_path_buf()
+        } else {
+            with_suffix(path, n)
+        };
+        match fs::hard_link(temp_path, &candidate) {
+            Ok(()) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "no free name for the file",
+    ))
+}
+
+/// `path` with `-n` added to the end of its file stem.
+fn with_suffix(path: &Path, n: u32) -> PathBuf {
+    let mut file_name = path.file_stem().map(OsString::from).unwrap_or_default();
+    file_name.push(format!("-{n}"));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// A hidden temporary file next to `path`, so it can be renamed into place
+/// without crossing file systems. Its name is new on every call, so
+/// transfers publishing the same path at once don't share one.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(format!(
+        ".sfs-{}-{}.part",
+        process::id(),
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn publish(path: &Path, policy: ExistingFilePolicy, contents: &[u8]) -> io::Result<Published> {
+        publish_with(path, policy, |mut file| file.write_all(contents))
+    }
+
+    #[test]
+    fn test_existing_file_policies() {
+        let dir = std::env::temp_dir().join(format!("sfs-publish-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+
+        assert_eq!(
+            publish(&path, ExistingFilePolicy::Fail, b"one").unwrap(),
+            Published::At(path.clone())
+        );
+        assert_eq!(
+            publish(&path, ExistingFilePolicy::Fail, b"two")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            publish(&path, ExistingFilePolicy::Skip, b"two").unwrap(),
+            Published::Skipped
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"one");
+
+        assert_eq!(
+            publish(&path, ExistingFilePolicy::Rename, b"two").unwrap(),
+            Published::At(dir.join("a-1.txt"))
+        );
+        assert_eq!(
+            publish(&path, ExistingFilePolicy::Rename, b"three").unwrap(),
+            Published::At(dir.join("a-2.txt"))
+        );
+        assert_eq!(fs::read(dir.join("a-2.txt")).unwrap(), b"three");
+
+        assert_eq!(
+            publish(&path, ExistingFilePolicy::Overwrite, b"four").unwrap(),
+            Published::At(path.clone())
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"four");
+
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_publishes_of_one_path_dont_share_a_temporary_file() {
+        let dir = std::env::temp_dir().join(format!("sfs-publish-overlap-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+
+        // The second publish starts while the first is still writing
+        let outer = publish_with(&path, ExistingFilePolicy::Rename, |mut file| {
+            let inner = publish(&path, ExistingFilePolicy::Rename, b"inner").unwrap();
+            assert_eq!(inner, Published::At(path.clone()));
+            file.write_all(b"outer")
+        });
+        assert_eq!(outer.unwrap(), Published::At(dir.join("a-1.txt")));
+        assert_eq!(fs::read(dir.join("a-1.txt")).unwrap(), b"outer");
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+use crate::batch::DatagramBatch;
+use crate::config::{Completion, SourcePolicy};
+use crate::extension::{ControlMessage, Features, Negotiation};
+use crate::packet::auth::{Authenticator, PacketKey};
+use crate::packet::encryption::{hello_authenticator, KeyExchange, Role};
+use crate::packet::{PacketRef, Protection, MAX_DATAGRAM_LEN, MAX_PACKET_LEN};
+use crate::{ClientConfig, ClientError, FileEvent, FileManager, PacketAnomaly};
+use std::borrow::Cow;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+
+/// Any datagram asks the server to start sending; the original server
+/// ignores its contents.
+pub(crate) const HELLO: [u8; 1] = [0];
+
+/// Hooks for reporting what `receive_files` is doing, e.g. to show progress.
+///
+/// All methods do nothing by default, and `()` implements the trait for
+/// callers that don't care.
+pub trait Progress {
+    /// Called with each packet before it is processed.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn packet_received(&mut self, _packet: &PacketRef<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called whenever processing a packet causes a `FileEvent`, such as a
+    /// file being completed and written.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn file_event(&mut self, _event: &FileEvent) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called for each packet that was dropped because it contradicts an
+    /// earlier one.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn anomaly(&mut self, _anomaly: &PacketAnomaly) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called for each datagram dropped unread because it came from an
+    /// address `config.accept_from` doesn't accept. The address is `None`
+    /// if it wasn't an IP address.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn stray_datagram(&mut self, _source: Option<SocketAddr>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called for each datagram dropped unread because, with `config.key`
+    /// set, its MAC didn't verify.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn unauthenticated_datagram(&mut self, _source: Option<SocketAddr>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called whenever a receive times out with nothing to read, e.g. to
+    /// stop a transfer that has gone quiet.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn idle(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called after each batch of packets received together has been
+    /// processed, e.g. to flush output buffered by the other hooks.
+    ///
+    /// # Errors
+    ///
+    /// Any error stops the transfer and is returned from `receive_files`.
+    fn batch_processed(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Progress for () {}
+
+/// Says hello to the server `socket` is connected to, then feeds every packet
+/// it sends into `file_manager` until the transfer is complete, as decided
+/// by `config.completion`.
+///
+/// The hello is re-sent every `config.hello_interval` until the first packet
+/// arrives, in case it (or the server's first reply) was lost.
+///
+/// The hello offers `config.extensions`. If the server accepts
+/// `Features::NACK` or `Features::RESEND_HEADER`, the client asks for the
+/// packets or headers it is missing whenever nothing has arrived for
+/// `config.nack_interval`, and says when it is done. Files whose header still
+/// hasn't arrived after `config.header_grace` are written under
+/// `config.fallback_name`, if there is one.
+///
+/// Packets are received up to `config.recv_batch` at a time (see
+/// `DatagramBatch`) and processed in the order they arrived. Datagrams from
+/// addresses `config.accept_from` doesn't accept are dropped and counted
+/// instead, and don't count as the server being active. If `socket` isn't
+/// connected, the hello and requests are sent to the server address in
+/// `config`, or to the server that answered.
+///
+/// With `config.key` set, the hello starts a new session (see
+/// `packet::auth`): everything sent is signed, and datagrams whose MAC
+/// doesn't verify are dropped and counted like those from other addresses.
+/// With `config.encrypt`, the hello starts a key exchange instead (see
+/// `packet::encryption`), and once the server has answered it, everything is
+/// encrypted both ways and datagrams that don't decrypt are dropped the same
+/// way.
+///
+/// Files are written as soon as they are complete; `progress` hears about
+/// every packet, every dropped packet and every completed file.
+///
+/// # Errors
+///
+/// Returns `ClientError::Timeout` if nothing arrives for `config.idle_timeout`,
+/// or `ClientError::MissingChecksums` if by then the only files left are
+/// waiting for checksums the server never sent, or another error if the
+/// socket fails or the server sends a malformed packet.
+pub fn receive_files(
+    socket: &UdpSocket,
+    file_manager: &mut FileManager,
+    config: &ClientConfig,
+    progress: &mut impl Progress,
+) -> Result<(), ClientError> {
+    let mut batch = DatagramBatch::new(config.recv_batch);
+    socket.set_read_timeout(Some(config.read_timeout))?;
+    let mut negotiation = Negotiation::new(config.extensions);
+    // Files wait for their checksums until the server says it won't send them
+    file_manager.require_checksums |= config.extensions.contains(Features::CHECKSUM);
+    let mut peer = if let Ok(server) = socket.peer_addr() {
+        Peer::new(config, server, true)?
+    } else {
+        let server = config.server_addr(socket.local_addr()?)?;
+        Peer::new(config, server, false)?
+    };
+    let hello = peer.hello(&negotiation);
+
+    // Send a hello to initiate communication with the server
+    peer.send(socket, &hello)?;
+    let mut clock = TransferClock::new();
+    file_manager.start_clock();
+
+    while !clock.transfer_done(file_manager, config) {
+        match batch.recv(socket) {
+            Ok(_) => {
+                file_manager.stats.oversized_datagrams += batch.oversized();
+                let mut admitted = false;
+                for (source, datagram) in batch.iter_with_sources() {
+                    admitted |= receive_datagram(
+                        &mut peer,
+                        source,
+                        datagram,
+                        &mut negotiation,
+                        file_manager,
+                        progress,
+                    )?;
+                }
+                progress.batch_processed()?;
+                if admitted {
+                    clock.packet_received();
+                    continue;
+                }
+            }
+            Err(e) if is_nothing_received(&e) => progress.idle()?,
+            Err(e) => return Err(e.into()),
+        }
+
+        match clock.nothing_received(file_manager, config)? {
+            Idle::Wait => {}
+            Idle::ResendHello => {
+                peer.send(socket, &hello)?;
+            }
+            Idle::RequestMissing => {
+                for request in negotiation.requests(file_manager) {
+                    peer.send(socket, &request)?;
+                }
+            }
+            Idle::WriteUnnamed => {
+                for event in file_manager.write_unnamed_files()? {
+                    progress.file_event(&event)?;
+                }
+            }
+        }
+    }
+
+    if let Some(done) = negotiation.done() {
+        // Only saves the server some waiting, so a failure doesn't matter
+        let _ = peer.send(socket, &done);
+    }
+    Ok(())
+}
+
+/// The server end of a transfer: where to send to, which datagrams to
+/// accept as a `SourcePolicy` says, and how datagrams are protected, if they
+/// are.
+pub(crate) struct Peer {
+    policy: SourcePolicy,
+    key: Option<PacketKey>,
+    /// Our half of a key exchange the server hasn't answered yet.
+    exchange: Option<KeyExchange>,
+    protection: Option<Protection>,
+    /// The server address, or under `SourcePolicy::FirstResponder` the
+    /// server that answered, once one has.
+    address: SocketAddr,
+    /// Whether the socket is connected to `address`, so sends don't need it.
+    connected: bool,
+    /// Whether a server has answered yet.
+    answered: bool,
+}
+
+impl Peer {
+    /// A peer at `address` as `config` says, starting a key exchange if the
+    /// transfer is encrypted, or a new session if it has a key.
+    pub(crate) fn new(
+        config: &ClientConfig,
+        address: SocketAddr,
+        connected: bool,
+    ) -> io::Result<Self> {
+        let (exchange, protection) = if config.encrypt {
+            (Some(KeyExchange::new()?), None)
+        } else if let Some(key) = &config.key {
+            (None, Some(Protection::Signed(Authenticator::random(key)?)))
+        } else {
+            (None, None)
+        };
+        Ok(Peer {
+            policy: config.accept_from,
+            key: config.key.clone(),
+            exchange,
+            protection,
+            address,
+            connected,
+            answered: false,
+        })
+    }
+
+    /// The hello offering what `negotiation` offers: one that starts the key
+    /// exchange, signed if there is a key, or one that carries the session
+    /// nonce, if there is one.
+    pub(crate) fn hello(&self, negotiation: &Negotiation) -> Vec<u8> {
+        let offered = negotiation.offered();
+        if let Some(exchange) = &self.exchange {
+            let hello = ControlMessage::EncryptedHello {
+                offered,
+                public_key: *exchange.public_key(),
+            }
+            .to_bytes();
+            match &self.key {
+                Some(key) => hello_authenticator(key, exchange.public_key()).sign(&hello),
+                None => hello,
+            }
+        } else if let Some(Protection::Signed(auth)) = &self.protection {
+            ControlMessage::SignedHello {
+                offered,
+                nonce: *auth.nonce(),
+            }
+            .to_bytes()
+        } else {
+            negotiation.hello()
+        }
+    }
+
+    /// `datagram` as it goes on the wire: signed or encrypted, if the
+    /// transfer is protected yet.
+    pub(crate) fn seal<'a>(&self, datagram: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        match &self.protection {
+            Some(protection) => protection.seal(datagram).map(Cow::Owned),
+            None => Ok(Cow::Borrowed(datagram)),
+        }
+    }
+
+    /// The longest datagram the server can send: a full packet, plus room
+    /// for a MAC or encryption overhead only if the transfer is protected.
+    pub(crate) fn max_datagram_len(&self) -> usize {
+        if self.exchange.is_some() || self.protection.is_some() {
+            MAX_DATAGRAM_LEN
+        } else {
+            MAX_PACKET_LEN
+        }
+    }
+
+    /// Where to send to, or `None` if the socket is connected.
+    pub(crate) fn target(&self) -> Option<SocketAddr> {
+        (!self.connected).then_some(self.address)
+    }
+
+    pub(crate) fn send(&self, socket: &UdpSocket, datagram: &[u8]) -> io::Result<usize> {
+        let datagram = self.seal(datagram)?;
+        match self.target() {
+            Some(address) => socket.send_to(&datagram, address),
+            None => socket.send(&datagram),
+        }
+    }
+
+    /// What to make of a datagram from `source`: its contents, with the MAC
+    /// checked and removed or decrypted if the transfer is protected, or the
+    /// server's answer to our key exchange. Under
+    /// `SourcePolicy::FirstResponder` the first of those binds the session
+    /// to its source. Other datagrams are counted in `file_manager.stats`
+    /// and reported to `progress`.
+    pub(crate) fn open<'a>(
+        &mut self,
+        source: Option<SocketAddr>,
+        datagram: &'a [u8],
+        file_manager: &mut FileManager,
+        progress: &mut impl Progress,
+    ) -> io::Result<Opened<'a>> {
+        let from_server = match (self.policy, source) {
+            (SourcePolicy::Any, _) => true,
+            (_, None) => false,
+            (SourcePolicy::FirstResponder, Some(_)) if !self.answered => true,
+            (_, Some(source)) => source == self.address,
+        };
+        if !from_server {
+            file_manager.stats.stray_datagrams += 1;
+            progress.stray_datagram(source)?;
+            return Ok(Opened::Dropped);
+        }
+
+        let opened = if let Some(exchange) = &self.exchange {
+            let cipher = match ControlMessage::parse(datagram) {
+                Some(ControlMessage::KeyExchange { public_key }) => {
+                    exchange.finish(Role::Client, &public_key, self.key.as_ref())
+                }
+                _ => None,
+            };
+            cipher.map(|cipher| {
+                self.exchange = None;
+                self.protection = Some(Protection::Sealed(cipher));
+                Opened::KeyExchange
+            })
+        } else {
+            match &self.protection {
+                Some(protection) => protection.open(datagram).map(Opened::Contents),
+                None => Some(Opened::Contents(Cow::Borrowed(datagram))),
+            }
+        };
+        let Some(opened) = opened else {
+            file_manager.stats.unauthenticated_datagrams += 1;
+            progress.unauthenticated_datagram(source)?;
+            return Ok(Opened::Dropped);
+        };
+
+        if let (SourcePolicy::FirstResponder, Some(source), false) =
+            (self.policy, source, self.answered)
+        {
+            self.address = source;
+        }
+        self.answered = true;
+        Ok(opened)
+    }
+}
+
+/// A datagram after `Peer::open`.
+pub(crate) enum Opened<'a> {
+    /// A packet or control message from the server, to be processed.
+    Contents(Cow<'a, [u8]>),
+    /// The server's answer to our key exchange, which has been dealt with.
+    KeyExchange,
+    /// Something that isn't from the server, which has been counted.
+    Dropped,
+}
+
+/// Opens a datagram from `source` and, if it holds anything, processes it.
+/// Returns whether it came from the server. A datagram longer than
+/// `Peer::max_datagram_len` can't be a packet, and is only counted.
+pub(crate) fn receive_datagram(
+    peer: &mut Peer,
+    source: Option<SocketAddr>,
+    datagram: &[u8],
+    negotiation: &mut Negotiation,
+    file_manager: &mut FileManager,
+    progress: &mut impl Progress,
+) -> Result<bool, ClientError> {
+    if datagram.len() > peer.max_datagram_len() {
+        file_manager.stats.oversized_datagrams += 1;
+        return Ok(false);
+    }
+    match peer.open(source, datagram, file_manager, progress)? {
+        Opened::Contents(contents) => {
+            process_datagram(&contents, negotiation, file_manager, progress)?;
+            Ok(true)
+        }
+        Opened::KeyExchange => Ok(true),
+        Opened::Dropped => Ok(false),
+    }
+}
+
+/// Parses one datagram, hands it to `file_manager`, and tells `progress`
+/// about everything that happened as a result. Control messages go to
+/// `negotiation` instead, and checksums on to `file_manager`; ones it
+/// doesn't recognize are only counted.
+pub(crate) fn process_datagram(
+    datagram: &[u8],
+    negotiation: &mut Negotiation,
+    file_manager: &mut FileManager,
+    progress: &mut impl Progress,
+) -> Result<(), ClientError> {
+    let event = if let Some(message) = negotiation.handle(datagram) {
+        match message {
+            ControlMessage::Accept(_) => {
+                if negotiation.offered().contains(Features::CHECKSUM)
+                    && !negotiation.accepted().contains(Features::CHECKSUM)
+                {
+                    for event in file_manager.stop_requiring_checksums()? {
+                        progress.file_event(&event)?;
+                    }
+                }
+                None
+            }
+            ControlMessage::Checksum { file_id, digest } => {
+                file_manager.process_checksum(file_id, digest)?
+            }
+            _ => None,
+        }
+    } else if negotiation.is_control_message(datagram) {
+        file_manager.stats.unrecognized_control_messages += 1;
+        None
+    } else {
+        let packet = PacketRef::try_from(datagram)?;
+        progress.packet_received(&packet)?;
+        file_manager.process_packet_ref(packet)?
+    };
+
+    for anomaly in file_manager.take_anomalies() {
+        progress.anomaly(&anomaly)?;
+    }
+    if let Some(event) = event {
+        progress.file_event(&event)?;
+    }
+    Ok(())
+}
+
+/// What to do after a receive timed out.
+pub(crate) enum Idle {
+    Wait,
+    ResendHello,
+    /// Ask for missing packets and headers again, if the server accepted
+    /// such requests.
+    RequestMissing,
+    /// Give up on missing headers and use fallback names instead.
+    WriteUnnamed,
+}
+
+/// The timers behind the hello retry, the NACKs, the idle deadline and the
+/// quiet period.
+pub(crate) struct TransferClock {
+    hello_sent_at: Instant,
+    nack_sent_at: Instant,
+    last_packet_at: Instant,
+    received_any: bool,
+}
+
+impl TransferClock {
+    /// Starts the clock just after the first hello was sent.
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        TransferClock {
+            hello_sent_at: now,
+            nack_sent_at: now,
+            last_packet_at: now,
+            received_any: false,
+        }
+    }
+
+    pub(crate) fn packet_received(&mut self) {
+        self.received_any = true;
+        self.last_packet_at = Instant::now();
+    }
+
+    pub(crate) fn transfer_done(&self, file_manager: &FileManager, config: &ClientConfig) -> bool {
+        if !file_manager.received_all_packets() {
+            return false;
+        }
+
+        match config.completion {
+            Completion::FileCount(_) => true,
+            Completion::QuietPeriod(quiet_period) => self.last_packet_at.elapsed() >= quiet_period,
+        }
+    }
+
+    /// Called when a receive timed out. Fails once nothing has arrived for
+    /// `config.idle_timeout` or the transfer has run out of
+    /// `Limits::max_duration`, and otherwise says whether it's time to send
+    /// the hello again or to ask for missing packets.
+    ///
+    /// A transfer with every file complete that is only waiting out its
+    /// quiet period doesn't time out, however long the period is.
+    pub(crate) fn nothing_received(
+        &mut self,
+        file_manager: &FileManager,
+        config: &ClientConfig,
+    ) -> Result<Idle, ClientError> {
+        file_manager.check_duration()?;
+        let waiting_out_quiet_period = matches!(config.completion, Completion::QuietPeriod(_))
+            && file_manager.received_all_packets();
+        if !waiting_out_quiet_period && self.last_packet_at.elapsed() >= config.idle_timeout {
+            // Files held back for their checksums are incomplete too, but
+            // that is the only thing wrong if nothing else is
+            let unverified = file_manager.awaiting_checksum();
+            if !unverified.is_empty() && unverified.len() == file_manager.incomplete_files().len() {
+                return Err(ClientError::MissingChecksums {
+                    idle_timeout: config.idle_timeout,
+                    file_ids: unverified,
+                });
+            }
+            return Err(ClientError::Timeout {
+                idle_timeout: config.idle_timeout,
+                incomplete: file_manager.incomplete_files(),
+            });
+        }
+
+        if !self.received_any {
+            if self.hello_sent_at.elapsed() >= config.hello_interval {
+                self.hello_sent_at = Instant::now();
+                return Ok(Idle::ResendHello);
+            }
+            return Ok(Idle::Wait);
+        }
+
+        if self.last_packet_at.elapsed() >= config.nack_interval
+            && self.nack_sent_at.elapsed() >= config.nack_interval
+        {
+            self.nack_sent_at = Instant::now();
+            return Ok(Idle::RequestMissing);
+        }
+
+        if self.last_packet_at.elapsed() >= config.header_grace {
+            return Ok(Idle::WriteUnnamed);
+        }
+        Ok(Idle::Wait)
+    }
+}
+
+/// Whether a failed `recv` just means there was nothing to read yet.
+///
+/// A connected UDP socket reports `ConnectionRefused` when an earlier hello
+/// bounced because the server isn't up yet, which is worth waiting out too.
+/// A receive interrupted by a signal is simply retried, after
+/// `Progress::idle` has had a chance to stop the transfer.
+pub(crate) fn is_nothing_received(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
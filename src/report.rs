@@ -0,0 +1,261 @@
+use std::ffi::OsString;
+use std::fmt::{self, Write};
+use std::ops::RangeInclusive;
+
+/// What was received of every file seen so far, for explaining a transfer
+/// that stopped early.
+///
+/// `Display` gives one line per file for people; `to_json` gives the same
+/// information for scripts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferReport {
+    pub files: Vec<FileReport>,
+}
+
+/// One file's entry in a `TransferReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReport {
+    pub file_id: u8,
+    /// The name from the header packet, if it arrived.
+    pub file_name: Option<OsString>,
+    /// The number of data packets in the file, once the last one arrived.
+    pub expected_number_of_packets: Option<usize>,
+    pub received_packets: usize,
+    /// The runs of packet numbers still missing. Until the last packet has
+    /// arrived the final range runs to `u16::MAX`, standing for "to the end
+    /// of the file".
+    pub missing: Vec<RangeInclusive<u16>>,
+    /// Whether the file was complete and written to disk.
+    pub written: bool,
+    /// Whether the file was given up on because it couldn't be written,
+    /// e.g. after a checksum mismatch or with an unsafe name.
+    pub failed: bool,
+}
+
+impl TransferReport {
+    /// Whether every file in the report was written.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.files.iter().all(|file| file.written)
+    }
+
+    /// The report as a JSON object with a `files` array. Missing ranges are
+    /// `[first, last]` pairs, with `null` for a last packet that isn't known.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_json(&mut json);
+        json
+    }
+
+    fn write_json(&self, json: &mut String) -> fmt::Result {
+        json.push_str("{\"files\":[");
+        for (i, file) in self.files.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            file.write_json(json)?;
+        }
+        json.push_str("]}");
+        Ok(())
+    }
+}
+
+impl FileReport {
+    /// The missing ranges as text, e.g. `5, 17-18, 40-` with a `separator`
+    /// of `", "`. A range with no end runs to the end of the file.
+    #[must_use]
+    pub fn missing_ranges_text(&self, separator: &str) -> String {
+        let mut text = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_missing_ranges(&mut text, separator);
+        text
+    }
+
+    fn write_missing_ranges(&self, out: &mut impl Write, separator: &str) -> fmt::Result {
+        for (i, range) in self.missing.iter().enumerate() {
+            if i > 0 {
+                out.write_str(separator)?;
+            }
+            match self.range_end(range) {
+                None => write!(out, "{}-", range.start())?,
+                Some(end) if end == *range.start() => write!(out, "{end}")?,
+                Some(end) => write!(out, "{}-{end}", range.start())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The last packet number of `range`, or `None` if it runs to an end
+    /// that isn't known yet.
+    fn range_end(&self, range: &RangeInclusive<u16>) -> Option<u16> {
+        if self.expected_number_of_packets.is_none() && *range.end() == u16::MAX {
+            None
+        } else {
+            Some(*range.end())
+        }
+    }
+
+    fn write_json(&self, json: &mut String) -> fmt::Result {
+        write!(json, "{{\"file_id\":{},\"file_name\":", self.file_id)?;
+        match &self.file_name {
+            Some(file_name) => write_json_string(json, &file_name.to_string_lossy())?,
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"expected_packets\":");
+        match self.expected_number_of_packets {
+            Some(expected) => write!(json, "{expected}")?,
+            None => json.push_str("null"),
+        }
+        write!(
+            json,
+            ",\"received_packets\":{},\"written\":{},\"failed\":{},\"missing\":[",
+            self.received_packets, self.written, self.failed
+        )?;
+        for (i, range) in self.missing.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            match self.range_end(range) {
+                Some(end) => write!(json, "[{},{end}]", range.start())?,
+                None => write!(json, "[{},null]", range.start())?,
+            }
+        }
+        json.push_str("]}");
+        Ok(())
+    }
+}
+
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.files.is_empty() {
+            return writeln!(f, "no files seen");
+        }
+        for file in &self.files {
+            writeln!(f, "{file}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file {}", self.file_id)?;
+        match &self.file_name {
+            Some(file_name) => write!(f, " ({})", file_name.to_string_lossy())?,
+            None => write!(f, " (name not received)")?,
+        }
+        if self.written {
+            return write!(f, ": complete");
+        }
+        if self.failed {
+            return write!(f, ": failed, not written");
+        }
+        match self.expected_number_of_packets {
+            Some(expected) => write!(f, ": {} of {expected} packets", self.received_packets)?,
+            None => write!(
+                f,
+                ": {} packets, last packet not seen",
+                self.received_packets
+            )?,
+        }
+        if !self.missing.is_empty() {
+            write!(f, "; missing ")?;
+            self.write_missing_ranges(f, ", ")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_json_string(json: &mut String, s: &str) -> fmt::Result {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", u32::from(c))?,
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> TransferReport {
+        TransferReport {
+            files: vec![
+                FileReport {
+                    file_id: 0,
+                    file_name: Some(OsString::from("small.txt")),
+                    expected_number_of_packets: Some(1),
+                    received_packets: 1,
+                    missing: vec![],
+                    written: true,
+                    failed: false,
+                },
+                FileReport {
+                    file_id: 1,
+                    file_name: Some(OsString::from("say \"hi\".txt")),
+                    expected_number_of_packets: Some(20),
+                    received_packets: 17,
+                    missing: vec![5..=5, 17..=18],
+                    written: false,
+                    failed: false,
+                },
+                FileReport {
+                    file_id: 2,
+                    file_name: None,
+                    expected_number_of_packets: None,
+                    received_packets: 3,
+                    missing: vec![0..=0, 4..=u16::MAX],
+                    written: false,
+                    failed: false,
+                },
+                FileReport {
+                    file_id: 3,
+                    file_name: Some(OsString::from("corrupt.txt")),
+                    expected_number_of_packets: Some(2),
+                    received_packets: 2,
+                    missing: vec![],
+                    written: false,
+                    failed: true,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_text_report() {
+        assert_eq!(
+            report().to_string(),
+            "file 0 (small.txt): complete\n\
+             file 1 (say \"hi\".txt): 17 of 20 packets; missing 5, 17-18\n\
+             file 2 (name not received): 3 packets, last packet not seen; missing 0, 4-\n\
+             file 3 (corrupt.txt): failed, not written\n"
+        );
+    }
+
+    #[test]
+    fn test_json_report() {
+        assert_eq!(
+            report().to_json(),
+            "{\"files\":[\
+             {\"file_id\":0,\"file_name\":\"small.txt\",\"expected_packets\":1,\
+             \"received_packets\":1,\"written\":true,\"failed\":false,\"missing\":[]},\
+             {\"file_id\":1,\"file_name\":\"say \\\"hi\\\".txt\",\"expected_packets\":20,\
+             \"received_packets\":17,\"written\":false,\"failed\":false,\"missing\":[[5,5],[17,18]]},\
+             {\"file_id\":2,\"file_name\":null,\"expected_packets\":null,\
+             \"received_packets\":3,\"written\":false,\"failed\":false,\"missing\":[[0,0],[4,null]]},\
+             {\"file_id\":3,\"file_name\":\"corrupt.txt\",\"expected_packets\":2,\
+             \"received_packets\":2,\"written\":false,\"failed\":true,\"missing\":[]}]}"
+        );
+    }
+}
@@ -0,0 +1,739 @@
+use crate::checksum::{self, Digest};
+use crate::extension::{ControlMessage, Features, MissingPackets};
+use crate::packet::{
+    auth::{Authenticator, PacketKey, TAG_LEN},
+    data_packet::DataPacket,
+    encryption::{hello_authenticator, KeyExchange, PublicKey, Role},
+    header_packet::HeaderPacket,
+    Packet, Protection, MAX_DATAGRAM_LEN, MAX_DATA_LEN,
+};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The port the original OutOfMoney.com server listens on.
+pub const DEFAULT_SERVER_PORT: u16 = 6014;
+
+/// The largest file the protocol can carry: packet numbers are 16 bits, so
+/// a file has at most 65536 data packets.
+pub const MAX_FILE_LEN: usize = (1 << 16) * MAX_DATA_LEN;
+
+/// The most files one transfer can hold, since file IDs are a single byte.
+pub const MAX_FILES: usize = 1 << 8;
+
+/// How long the server waits for NACKs from a client that negotiated them.
+pub const DEFAULT_LINGER: Duration = Duration::from_secs(5);
+
+/// How many transfers in a row can fail before `serve_forever` gives up,
+/// e.g. because the socket keeps failing the same way.
+pub const MAX_FAILURES_IN_A_ROW: u32 = 10;
+
+/// A file the server hands out to every client that says hello.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServedFile {
+    pub file_name: OsString,
+    pub contents: Vec<u8>,
+}
+
+impl ServedFile {
+    pub fn new(file_name: impl Into<OsString>, contents: Vec<u8>) -> Self {
+        ServedFile {
+            file_name: file_name.into(),
+            contents,
+        }
+    }
+
+    /// Reads `path` from disk, serving it under its final path component.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or `path` has no file name.
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        let file_name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has no file name", path.display()),
+            )
+        })?;
+
+        Ok(ServedFile::new(file_name, fs::read(path)?))
+    }
+
+    /// Splits this file into its header packet and `MAX_DATA_LEN`-byte data
+    /// packets, with the last-packet bit set on the final chunk.
+    ///
+    /// An empty file is still sent as a single (empty) last data packet so
+    /// the client can tell it is complete.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if the file is longer than `MAX_FILE_LEN`.
+    pub fn packets(&self, file_id: u8) -> io::Result<Vec<Packet>> {
+        self.check_len()?;
+        let mut packets = vec![Packet::HeaderPacket(HeaderPacket::new(
+            file_id,
+            self.file_name.clone(),
+        ))];
+
+        for packet_number in (0..=u16::MAX).take(self.number_of_data_packets()) {
+            if let Some(data_packet) = self.data_packet(file_id, packet_number) {
+                packets.push(Packet::DataPacket(data_packet));
+            }
+        }
+
+        Ok(packets)
+    }
+
+    /// Fails for a file too long for its packet numbers to fit in 16 bits.
+    fn check_len(&self) -> io::Result<()> {
+        if self.contents.len() <= MAX_FILE_LEN {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is {} bytes long, but the protocol can only carry {MAX_FILE_LEN}",
+                self.file_name.to_string_lossy(),
+                self.contents.len()
+            ),
+        ))
+    }
+
+    /// The SHA-256 digest of the whole file, sent with the checksum
+    /// extension.
+    #[must_use]
+    pub fn digest(&self) -> Digest {
+        checksum::digest(&self.contents)
+    }
+
+    /// How many data packets the file is split into.
+    #[must_use]
+    pub fn number_of_data_packets(&self) -> usize {
+        self.contents.chunks(MAX_DATA_LEN).len().max(1)
+    }
+
+    /// The data packet holding chunk `packet_number`, or `None` past the end
+    /// of the file.
+    #[must_use]
+    pub fn data_packet(&self, file_id: u8, packet_number: u16) -> Option<DataPacket> {
+        let number_of_packets = self.number_of_data_packets();
+        if usize::from(packet_number) >= number_of_packets {
+            return None;
+        }
+
+        let start = usize::from(packet_number) * MAX_DATA_LEN;
+        let end = self.contents.len().min(start + MAX_DATA_LEN);
+        let is_last = usize::from(packet_number) + 1 == number_of_packets;
+        Some(DataPacket::new(
+            file_id,
+            packet_number,
+            self.contents[start..end].to_vec(),
+            is_last,
+        ))
+    }
+}
+
+/// Loads every regular file directly inside `dir`, sorted by name.
+///
+/// # Errors
+///
+/// Returns an error if the directory or any file in it can't be read.
+pub fn files_in_directory(dir: &Path) -> io::Result<Vec<ServedFile>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| ServedFile::from_path(path))
+        .collect()
+}
+
+/// A native stand-in for `Segmented-File-System-server.jar`.
+///
+/// Every datagram it receives is treated as a hello: it answers by sending
+/// all of its files to the sender, each under a fresh file ID, with the
+/// packets of all files shuffled together.
+///
+/// A hello that offers extensions is answered with an accept first. After
+/// sending the files the server then stays with that client, resending
+/// whatever packets or headers it asks for, until the client is done or has
+/// been quiet for the linger time. Hellos from other clients are ignored
+/// meanwhile.
+///
+/// With a key, only a signed hello that verifies starts a transfer, and the
+/// session it starts is signed throughout, as `packet::auth` describes.
+///
+/// A hello that starts a key exchange is answered, and the transfer it
+/// starts is encrypted throughout, as `packet::encryption` describes. With
+/// a key, that hello has to be signed with it too, and the key goes into
+/// the exchange, so a client without it gets no answer at all.
+pub struct Server {
+    socket: UdpSocket,
+    files: Vec<ServedFile>,
+    packet_delay: Duration,
+    packet_loss: u8,
+    header_loss: u8,
+    features: Features,
+    linger: Duration,
+    next_file_id: u8,
+    rng: XorShift,
+    key: Option<PacketKey>,
+    /// How datagrams to and from the current client are protected, if they
+    /// are.
+    session: Option<Protection>,
+}
+
+impl Server {
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if there are more than `MAX_FILES` files or any
+    /// of them is longer than `MAX_FILE_LEN`, or an error if the socket can't
+    /// be bound.
+    pub fn bind(addr: impl ToSocketAddrs, files: Vec<ServedFile>) -> io::Result<Self> {
+        if files.len() > MAX_FILES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} files to serve, but a transfer can only hold {MAX_FILES}",
+                    files.len()
+                ),
+            ));
+        }
+        for file in &files {
+            file.check_len()?;
+        }
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| {
+                elapsed.as_secs() ^ u64::from(elapsed.subsec_nanos())
+            });
+
+        Ok(Server {
+            socket: UdpSocket::bind(addr)?,
+            files,
+            packet_delay: Duration::ZERO,
+            packet_loss: 0,
+            header_loss: 0,
+            features: Features::ALL,
+            linger: DEFAULT_LINGER,
+            next_file_id: 0,
+            rng: XorShift::new(seed),
+            key: None,
+            session: None,
+        })
+    }
+
+    /// Pauses between datagrams so a client on the same machine doesn't have
+    /// its receive buffer overrun.
+    #[must_use]
+    pub fn with_packet_delay(mut self, packet_delay: Duration) -> Self {
+        self.packet_delay = packet_delay;
+        self
+    }
+
+    /// Silently drops roughly `percent` percent of the data packets it would
+    /// send, to exercise a client's handling of packet loss.
+    #[must_use]
+    pub fn with_packet_loss(mut self, percent: u8) -> Self {
+        self.packet_loss = percent.min(100);
+        self
+    }
+
+    /// Silently drops roughly `percent` percent of the header packets it
+    /// would send, to exercise a client's handling of lost file names.
+    #[must_use]
+    pub fn with_header_loss(mut self, percent: u8) -> Self {
+        self.header_loss = percent.min(100);
+        self
+    }
+
+    /// Limits the extensions the server accepts; `Features::NONE` makes it
+    /// behave like the original server.
+    #[must_use]
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// How long to wait for NACKs before giving up on a client.
+    /// `Duration::ZERO` means not waiting for them at all.
+    #[must_use]
+    pub fn with_linger(mut self, linger: Duration) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    /// Only serves clients that share `key`, authenticating every datagram.
+    #[must_use]
+    pub fn with_key(mut self, key: Option<PacketKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Uses a fixed shuffle seed (and packet loss) so transfers are
+    /// reproducible.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = XorShift::new(seed);
+        self
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the socket's address can't be read.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Waits for a single hello and sends every file to whoever sent it,
+    /// then answers its requests if it negotiated any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if receiving the hello or sending any packet fails.
+    pub fn serve_one(&mut self) -> io::Result<SocketAddr> {
+        let mut buf = [0; MAX_DATAGRAM_LEN];
+        let (client, offered) = loop {
+            let (len, client) = self.socket.recv_from(&mut buf)?;
+            if let Some(offered) = self.open_hello(&buf[..len], client)? {
+                break (client, offered);
+            }
+        };
+        let accepted = offered & self.features;
+
+        if !accepted.is_empty() {
+            self.send_to(&ControlMessage::Accept(accepted).to_bytes(), client)?;
+        }
+        let file_ids = self.send_transfer(client, accepted)?;
+        if !accepted.is_empty() {
+            self.answer_requests(client, &file_ids, accepted)?;
+        }
+        Ok(client)
+    }
+
+    /// Answers hellos, carrying on past a transfer that fails, until
+    /// `MAX_FAILURES_IN_A_ROW` fail one after another.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error the last of those transfers failed with.
+    pub fn serve_forever(&mut self) -> io::Result<()> {
+        let mut failures = 0;
+        loop {
+            match self.serve_one() {
+                Ok(_) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    if failures >= MAX_FAILURES_IN_A_ROW {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The extensions offered by `datagram` if it is a hello this server
+    /// answers, after setting up the session it starts. A key exchange is
+    /// answered right away, in the clear.
+    fn open_hello(&mut self, datagram: &[u8], client: SocketAddr) -> io::Result<Option<Features>> {
+        if let Some((offered, public_key)) = open_encrypted_hello(self.key.as_ref(), datagram) {
+            let exchange = KeyExchange::new()?;
+            let Some(cipher) = exchange.finish(Role::Server, &public_key, self.key.as_ref()) else {
+                return Ok(None);
+            };
+            self.session = None;
+            let answer = ControlMessage::KeyExchange {
+                public_key: *exchange.public_key(),
+            };
+            self.send_to(&answer.to_bytes(), client)?;
+            self.session = Some(Protection::Sealed(cipher));
+            return Ok(Some(offered));
+        }
+
+        let Some(key) = &self.key else {
+            self.session = None;
+            return Ok(Some(match ControlMessage::parse(datagram) {
+                Some(ControlMessage::Hello(offered)) => offered,
+                _ => Features::NONE,
+            }));
+        };
+        let Some((offered, session)) = open_signed_hello(key, datagram) else {
+            return Ok(None);
+        };
+        self.session = Some(Protection::Signed(session));
+        Ok(Some(offered))
+    }
+
+    /// Sends every file to `client` in a shuffled order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if sending any packet fails.
+    pub fn send_files_to(&mut self, client: SocketAddr) -> io::Result<()> {
+        self.send_transfer(client, Features::NONE).map(drop)
+    }
+
+    /// Sends every file, followed by their checksums if `accepted` includes
+    /// them, returning the ID each one was sent under.
+    fn send_transfer(&mut self, client: SocketAddr, accepted: Features) -> io::Result<Vec<u8>> {
+        let mut file_ids = Vec::new();
+        let mut packets = Vec::new();
+        for file in &self.files {
+            // There are at most `MAX_FILES`, so IDs only repeat across
+            // transfers
+            let file_id = self.next_file_id;
+            self.next_file_id = self.next_file_id.wrapping_add(1);
+            file_ids.push(file_id);
+            packets.extend(file.packets(file_id)?);
+        }
+        self.rng.shuffle(&mut packets);
+
+        let mut buffer = Vec::new();
+        for packet in &packets {
+            if self.drops_packet(packet) {
+                continue;
+            }
+            buffer.clear();
+            packet.write_to(&mut buffer);
+            self.send_to(&buffer, client)?;
+        }
+
+        if accepted.contains(Features::CHECKSUM) {
+            self.send_checksums(client, &file_ids, &file_ids)?;
+        }
+        Ok(file_ids)
+    }
+
+    /// Resends whatever `client` asks for, as far as `accepted` allows, until
+    /// it says it is done or goes quiet for the linger time.
+    fn answer_requests(
+        &mut self,
+        client: SocketAddr,
+        file_ids: &[u8],
+        accepted: Features,
+    ) -> io::Result<()> {
+        // A zero read timeout would be refused, and means not waiting anyway
+        if self.linger.is_zero() {
+            return Ok(());
+        }
+        self.socket.set_read_timeout(Some(self.linger))?;
+        let mut buf = [0; MAX_DATAGRAM_LEN];
+
+        let result = loop {
+            let (len, sender) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if is_timeout(&e) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            if sender != client {
+                continue;
+            }
+            let contents = match &self.session {
+                Some(session) => session.open(&buf[..len]),
+                None => Some(Cow::Borrowed(&buf[..len])),
+            };
+            let Some(contents) = contents else {
+                continue;
+            };
+
+            match ControlMessage::parse(&contents) {
+                Some(ControlMessage::Nack(missing)) if accepted.contains(Features::NACK) => {
+                    if let Err(e) = self.resend(client, file_ids, &missing) {
+                        break Err(e);
+                    }
+                }
+                Some(ControlMessage::ResendHeader(unnamed))
+                    if accepted.contains(Features::RESEND_HEADER) =>
+                {
+                    if let Err(e) = self.resend_headers(client, file_ids, &unnamed) {
+                        break Err(e);
+                    }
+                }
+                Some(ControlMessage::ResendChecksum(unverified))
+                    if accepted.contains(Features::CHECKSUM) =>
+                {
+                    if let Err(e) = self.send_checksums(client, file_ids, &unverified) {
+                        break Err(e);
+                    }
+                }
+                Some(ControlMessage::Done) => break Ok(()),
+                _ => {}
+            }
+        };
+
+        self.socket.set_read_timeout(None)?;
+        result
+    }
+
+    fn resend(
+        &mut self,
+        client: SocketAddr,
+        file_ids: &[u8],
+        missing: &[MissingPackets],
+    ) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        for range in missing {
+            let Some(index) = file_ids.iter().position(|&id| id == range.file_id) else {
+                continue;
+            };
+
+            for packet_number in range.packets.clone() {
+                let Some(packet) = self.files[index].data_packet(range.file_id, packet_number)
+                else {
+                    // Open-ended ranges run past the end of the file
+                    break;
+                };
+                let packet = Packet::DataPacket(packet);
+                if self.drops_packet(&packet) {
+                    continue;
+                }
+                buffer.clear();
+                packet.write_to(&mut buffer);
+                self.send_to(&buffer, client)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resend_headers(
+        &mut self,
+        client: SocketAddr,
+        file_ids: &[u8],
+        unnamed: &[u8],
+    ) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        for &file_id in unnamed {
+            let Some(index) = file_ids.iter().position(|&id| id == file_id) else {
+                continue;
+            };
+
+            let packet = Packet::HeaderPacket(HeaderPacket::new(
+                file_id,
+                self.files[index].file_name.clone(),
+            ));
+            if self.drops_packet(&packet) {
+                continue;
+            }
+            buffer.clear();
+            packet.write_to(&mut buffer);
+            self.send_to(&buffer, client)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the checksums of the files in `wanted`.
+    fn send_checksums(&self, client: SocketAddr, file_ids: &[u8], wanted: &[u8]) -> io::Result<()> {
+        for &file_id in wanted {
+            let Some(index) = file_ids.iter().position(|&id| id == file_id) else {
+                continue;
+            };
+            let checksum = ControlMessage::Checksum {
+                file_id,
+                digest: self.files[index].digest(),
+            };
+            self.send_to(&checksum.to_bytes(), client)?;
+        }
+        Ok(())
+    }
+
+    /// Whether simulated packet loss claims `packet`.
+    fn drops_packet(&mut self, packet: &Packet) -> bool {
+        let loss = match packet {
+            Packet::HeaderPacket(_) => self.header_loss,
+            Packet::DataPacket(_) => self.packet_loss,
+        };
+        loss > 0 && self.rng.next() % 100 < u64::from(loss)
+    }
+
+    fn send_to(&self, datagram: &[u8], client: SocketAddr) -> io::Result<()> {
+        match &self.session {
+            Some(session) => self.socket.send_to(&session.seal(datagram)?, client)?,
+            None => self.socket.send_to(datagram, client)?,
+        };
+        if !self.packet_delay.is_zero() {
+            thread::sleep(self.packet_delay);
+        }
+        Ok(())
+    }
+}
+
+/// The extensions offered by an encrypted hello, and the client's half of
+/// the key exchange, if `datagram` is one. With a key it has to verify with
+/// it, as `hello_authenticator` describes.
+fn open_encrypted_hello(key: Option<&PacketKey>, datagram: &[u8]) -> Option<(Features, PublicKey)> {
+    let contents = match key {
+        Some(key) => {
+            let contents = &datagram[..datagram.len().checked_sub(TAG_LEN)?];
+            let Some(ControlMessage::EncryptedHello { public_key, .. }) =
+                ControlMessage::parse(contents)
+            else {
+                return None;
+            };
+            hello_authenticator(key, &public_key).verify(datagram)?
+        }
+        None => datagram,
+    };
+    match ControlMessage::parse(contents)? {
+        ControlMessage::EncryptedHello {
+            offered,
+            public_key,
+        } => Some((offered, public_key)),
+        _ => None,
+    }
+}
+
+/// The extensions offered by a signed hello, and the session it starts, if
+/// `datagram` is one that verifies with `key`. The nonce the tag covers is
+/// inside the hello, so it is read before the tag is checked.
+fn open_signed_hello(key: &PacketKey, datagram: &[u8]) -> Option<(Features, Authenticator)> {
+    let contents = &datagram[..datagram.len().checked_sub(TAG_LEN)?];
+    let Some(ControlMessage::SignedHello { offered, nonce }) = ControlMessage::parse(contents)
+    else {
+        return None;
+    };
+    let session = Authenticator::new(key, nonce);
+    session.verify(datagram)?;
+    Some((offered, session))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// A tiny xorshift generator; good enough to scramble packet order.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero, so never start there.
+        XorShift(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Fisher-Yates shuffle.
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            // The modulus keeps the value below `i + 1`, so it fits in a usize.
+            #[allow(clippy::cast_possible_truncation)]
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::data_packet::DataPacket;
+
+    fn data_packets(packets: &[Packet]) -> Vec<&DataPacket> {
+        packets
+            .iter()
+            .filter_map(|packet| match packet {
+                Packet::DataPacket(data_packet) => Some(data_packet),
+                Packet::HeaderPacket(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_packets_chunks_file_and_marks_last() {
+        let file = ServedFile::new("big.bin", vec![7; MAX_DATA_LEN * 2 + 5]);
+        let packets = file.packets(9).unwrap();
+
+        assert_eq!(
+            packets[0],
+            Packet::HeaderPacket(HeaderPacket::new(9, OsString::from("big.bin")))
+        );
+
+        let data = data_packets(&packets);
+        assert_eq!(data.len(), 3);
+        assert_eq!(data[0].data.len(), MAX_DATA_LEN);
+        assert_eq!(data[2].data.len(), 5);
+        assert_eq!(data[2].packet_number, 2);
+        assert!(!data[1].is_last_data_packet());
+        assert!(data[2].is_last_data_packet());
+    }
+
+    #[test]
+    fn test_files_the_protocol_cant_carry_are_refused() {
+        let largest = ServedFile::new("largest.bin", vec![0; MAX_FILE_LEN]);
+        let data = largest.packets(0).unwrap();
+        assert_eq!(data.len(), 1 + (1 << 16));
+
+        let too_large = ServedFile::new("too-large.bin", vec![0; MAX_FILE_LEN + 1]);
+        assert!(too_large.packets(0).is_err());
+        assert!(Server::bind("127.0.0.1:0", vec![too_large]).is_err());
+
+        let too_many = vec![ServedFile::new("small.txt", vec![1]); MAX_FILES + 1];
+        assert!(Server::bind("127.0.0.1:0", too_many).is_err());
+        let most = vec![ServedFile::new("small.txt", vec![1]); MAX_FILES];
+        assert!(Server::bind("127.0.0.1:0", most).is_ok());
+    }
+
+    #[test]
+    fn test_packets_for_empty_file() {
+        let packets = ServedFile::new("empty", vec![]).packets(0).unwrap();
+        let data = data_packets(&packets);
+
+        assert_eq!(data.len(), 1);
+        assert!(data[0].data.is_empty());
+        assert!(data[0].is_last_data_packet());
+    }
+
+    #[test]
+    fn test_encrypted_hellos_are_signed_with_the_key() {
+        let exchange = KeyExchange::new().unwrap();
+        let public_key = *exchange.public_key();
+        let hello = ControlMessage::EncryptedHello {
+            offered: Features::NACK,
+            public_key,
+        }
+        .to_bytes();
+        let key = PacketKey::new("correct horse").unwrap();
+        let signed = hello_authenticator(&key, &public_key).sign(&hello);
+
+        assert_eq!(
+            open_encrypted_hello(None, &hello),
+            Some((Features::NACK, public_key))
+        );
+        assert_eq!(
+            open_encrypted_hello(Some(&key), &signed),
+            Some((Features::NACK, public_key))
+        );
+        assert_eq!(open_encrypted_hello(Some(&key), &hello), None);
+        let other_key = PacketKey::new("battery staple").unwrap();
+        assert_eq!(open_encrypted_hello(Some(&other_key), &signed), None);
+    }
+
+    #[test]
+    fn test_shuffle_keeps_every_item() {
+        let mut items: Vec<u32> = (0..100).collect();
+        XorShift::new(42).shuffle(&mut items);
+
+        assert_ne!(items, (0..100).collect::<Vec<_>>());
+        items.sort_unstable();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::bitmap::ReceivedBitmap;
+use crate::checksum::{file_digest, Digest};
+use crate::chunk_buffer::ChunkWrite;
+use crate::file_manager::ExistingFilePolicy;
+use crate::packet::MAX_DATA_LEN;
+use crate::publish::{place, Published};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A partially received file that lives on disk rather than in memory.
+///
+/// Each chunk is written at `packet_number * MAX_DATA_LEN` in a temporary
+/// file in the output directory as soon as it arrives, so only the bitmap of
+/// received packet numbers is kept in memory. Once the file is complete it is
+/// renamed to its real name; a spool that is dropped before then deletes its
+/// temporary file.
+pub(crate) struct SpoolFile {
+    file: File,
+    temp_path: PathBuf,
+    received: ReceivedBitmap,
+    published: bool,
+}
+
+impl SpoolFile {
+    /// Creates the temporary file for `file_id`, named after the process and
+    /// `tag` so no other transfer can be using it. It is never opened if it
+    /// already exists.
+    pub(crate) fn create(output_dir: &Path, tag: u64, file_id: u8) -> io::Result<Self> {
+        fs::create_dir_all(output_dir)?;
+        let temp_path = output_dir.join(format!(".sfs-{}-{tag}-{file_id}.part", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+
+        Ok(SpoolFile {
+            file,
+            temp_path,
+            received: ReceivedBitmap::new(),
+            published: false,
+        })
+    }
+
+    /// Writes one chunk at its place in the file.
+    ///
+    /// A repeated packet number is compared against what is already on disk
+    /// rather than overwriting it. The last chunk fixes the file's length,
+    /// which leaves any chunks that haven't arrived yet as holes in a sparse
+    /// file.
+    pub(crate) fn write_chunk(
+        &mut self,
+        packet_number: u16,
+        data: &[u8],
+        is_last: bool,
+    ) -> io::Result<ChunkWrite> {
+        let offset = u64::from(packet_number) * MAX_DATA_LEN as u64;
+
+        if !self.received.insert(packet_number) {
+            let mut existing = vec![0; data.len()];
+            let matches = self.file.read_exact_at(&mut existing, offset).is_ok()
+                && existing == data
+                && (!is_last || self.file.metadata()?.len() == offset + data.len() as u64);
+            return Ok(if matches {
+                ChunkWrite::Duplicate
+            } else {
+                ChunkWrite::Conflict
+            });
+        }
+
+        self.file.write_all_at(data, offset)?;
+        if is_last {
+            self.file.set_len(offset + data.len() as u64)?;
+        }
+
+        Ok(ChunkWrite::Stored)
+    }
+
+    /// Forgets every chunk from `first` on, returning their packet numbers.
+    pub(crate) fn remove_from(&mut self, first: u16) -> Vec<u16> {
+        self.received.remove_from(first)
+    }
+
+    pub(crate) fn received(&self) -> &ReceivedBitmap {
+        &self.received
+    }
+
+    pub(crate) fn received_packets(&self) -> usize {
+        self.received.count()
+    }
+
+    /// The digest of everything written so far.
+    pub(crate) fn digest(&self) -> io::Result<Digest> {
+        file_digest(&self.file)
+    }
+
+    /// Flushes the file to disk and moves it to `path` as `policy` says,
+    /// returning where it went and its length.
+    pub(crate) fn publish(
+        mut self,
+        path: &Path,
+        policy: ExistingFilePolicy,
+    ) -> io::Result<(Published, u64)> {
+        self.file.sync_all()?;
+        let bytes = self.file.metadata()?.len();
+        // `place` removes the temporary file whether or not it succeeds
+        self.published = true;
+        let published = place(&self.temp_path, path, policy)?;
+        Ok((published, bytes))
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if !self.published {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
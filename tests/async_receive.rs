@@ -0,0 +1,221 @@
+// Drives the async client against the native server. Only built with
+// `--features async`.
+#![cfg(feature = "async")]
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use segmented_file_system_client::async_receive::receive_files;
+use segmented_file_system_client::packet::data_packet::DataPacket;
+use segmented_file_system_client::packet::header_packet::HeaderPacket;
+use segmented_file_system_client::packet::PacketRef;
+use segmented_file_system_client::server::{files_in_directory, Server};
+use segmented_file_system_client::{ClientConfig, Completion, Progress, ReassemblyMode};
+
+const TARGET_FILES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/target-files");
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Starts a native server for one transfer, returning its port.
+fn start_server() -> (u16, thread::JoinHandle<()>) {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || {
+        server.serve_one().unwrap();
+    });
+    (server_port, server_thread)
+}
+
+fn local_config(server_port: u16, output_dir: &Path) -> ClientConfig {
+    ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.to_path_buf(),
+        ..ClientConfig::default()
+    }
+}
+
+async fn transfer(config: ClientConfig) {
+    let socket = config.connect_async().await.unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn concurrent_transfers_on_one_runtime() {
+    let (first_port, first_server) = start_server();
+    let (second_port, second_server) = start_server();
+    let first_dir = scratch_dir("sfs-async-first");
+    let second_dir = scratch_dir("sfs-async-second");
+
+    let streaming = ClientConfig {
+        reassembly: ReassemblyMode::Streaming,
+        ..local_config(second_port, &second_dir)
+    };
+    tokio::join!(
+        transfer(local_config(first_port, &first_dir)),
+        transfer(streaming)
+    );
+    first_server.join().unwrap();
+    second_server.join().unwrap();
+
+    for output_dir in [&first_dir, &second_dir] {
+        for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+            assert_eq!(
+                fs::read(Path::new(TARGET_FILES).join(name)).unwrap(),
+                fs::read(output_dir.join(name)).unwrap(),
+                "{name} differs in {}",
+                output_dir.display()
+            );
+        }
+        fs::remove_dir_all(output_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn cancelled_transfer_leaves_no_partial_files() {
+    // A server that sends part of a file and then goes quiet
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let packet = DataPacket::new(1, 0, vec![1; 1024], false);
+        server.send_to(&packet.to_bytes(), client).unwrap();
+    });
+
+    let output_dir = scratch_dir("sfs-async-cancelled");
+    let config = ClientConfig {
+        reassembly: ReassemblyMode::Streaming,
+        ..local_config(server_port, &output_dir)
+    };
+    let socket = config.connect_async().await.unwrap();
+    let mut file_manager = config.file_manager();
+
+    let mut progress = ();
+    let transfer = receive_files(&socket, &mut file_manager, &config, &mut progress);
+    let result = tokio::time::timeout(Duration::from_millis(300), transfer).await;
+    assert!(
+        result.is_err(),
+        "the transfer should still have been running"
+    );
+    server_thread.join().unwrap();
+
+    // What arrived before cancelling is still there...
+    assert_eq!(file_manager.packet_groups[1].received_packets(), 1);
+    // ...and dropping the file manager cleans up its temporary file
+    drop(file_manager);
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[tokio::test]
+async fn transfers_can_be_spawned() {
+    let (server_port, server_thread) = start_server();
+    let output_dir = scratch_dir("sfs-async-spawned");
+
+    tokio::spawn(transfer(local_config(server_port, &output_dir)))
+        .await
+        .unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 3);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn streaming_transfer_on_a_multi_threaded_runtime() {
+    // Writing to disk happens under `block_in_place` here
+    let (server_port, server_thread) = start_server();
+    let output_dir = scratch_dir("sfs-async-multi-thread");
+    let config = ClientConfig {
+        reassembly: ReassemblyMode::Streaming,
+        resume: true,
+        ..local_config(server_port, &output_dir)
+    };
+
+    tokio::spawn(transfer(config)).await.unwrap();
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_eq!(
+            fs::read(Path::new(TARGET_FILES).join(name)).unwrap(),
+            fs::read(output_dir.join(name)).unwrap()
+        );
+    }
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+/// Counts the packets in each batch.
+#[derive(Default)]
+struct BatchSizes {
+    current: usize,
+    largest: usize,
+}
+
+impl Progress for BatchSizes {
+    fn packet_received(&mut self, _packet: &PacketRef<'_>) -> io::Result<()> {
+        self.current += 1;
+        Ok(())
+    }
+
+    fn batch_processed(&mut self) -> io::Result<()> {
+        self.largest = self.largest.max(self.current);
+        self.current = 0;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn each_wakeup_takes_at_most_a_batch() {
+    // A server that sends a whole file at once, without pausing
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let header = HeaderPacket::new(0, OsString::from("burst.bin"));
+        server.send_to(&header.to_bytes(), client).unwrap();
+        for packet_number in 0..32 {
+            let packet = DataPacket::new(0, packet_number, vec![7; 1024], packet_number == 31);
+            server.send_to(&packet.to_bytes(), client).unwrap();
+        }
+    });
+
+    let output_dir = scratch_dir("sfs-async-batch");
+    let config = ClientConfig {
+        recv_batch: 4,
+        completion: Completion::FileCount(1),
+        ..local_config(server_port, &output_dir)
+    };
+    let socket = config.connect_async().await.unwrap();
+    let mut file_manager = config.file_manager();
+    let mut batches = BatchSizes::default();
+    receive_files(&socket, &mut file_manager, &config, &mut batches)
+        .await
+        .unwrap();
+    server_thread.join().unwrap();
+
+    assert!(batches.largest <= 4, "a batch of {}", batches.largest);
+    assert_eq!(
+        fs::read(output_dir.join("burst.bin")).unwrap(),
+        vec![7; 32 * 1024]
+    );
+    fs::remove_dir_all(&output_dir).unwrap();
+}
@@ -0,0 +1,106 @@
+// Exercises datagram authentication against a minimal signing server and
+// the forgeries it is asked to let through.
+
+use std::ffi::OsString;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+
+use segmented_file_system_client::extension::ControlMessage;
+use segmented_file_system_client::packet::auth::{Authenticator, PacketKey, TAG_LEN};
+use segmented_file_system_client::packet::{
+    data_packet::DataPacket, header_packet::HeaderPacket, Packet, MAX_DATA_LEN,
+};
+use segmented_file_system_client::{receive_files, ClientConfig};
+
+mod common;
+
+use common::{quick_config, scratch_dir, send_file};
+
+#[test]
+fn packets_that_fail_authentication_are_dropped() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("authenticated");
+    let key = PacketKey::new("shared secret").unwrap();
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        key: Some(key.clone()),
+        ..quick_config(&server)
+    };
+
+    // A minimal signing sender: it takes the nonce from the signed hello
+    // and signs the file with it. Before that, datagrams from the same
+    // address arrive unsigned, signed with the wrong key, or signed for
+    // another session.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (len, client) = server.recv_from(&mut buf).unwrap();
+        let Some(ControlMessage::SignedHello { nonce, .. }) =
+            ControlMessage::parse(&buf[..len - TAG_LEN])
+        else {
+            panic!("expected a signed hello");
+        };
+        let session = Authenticator::new(&key, nonce);
+        assert!(session.verify(&buf[..len]).is_some());
+
+        let header = Packet::HeaderPacket(HeaderPacket::new(5, OsString::from("file-5")));
+        let forged = Packet::DataPacket(DataPacket::new(5, 0, vec![6, 6, 6], true));
+        let wrong_key = PacketKey::new("guess").unwrap();
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 1;
+        for signed in [
+            forged.to_bytes(),
+            Authenticator::new(&wrong_key, nonce).sign(&forged.to_bytes()),
+            Authenticator::new(&key, other_nonce).sign(&forged.to_bytes()),
+        ] {
+            server.send_to(&signed, client).unwrap();
+        }
+
+        let packet = Packet::DataPacket(DataPacket::new(5, 0, vec![1, 2, 3], true));
+        for packet in [header, packet] {
+            server
+                .send_to(&session.sign(&packet.to_bytes()), client)
+                .unwrap();
+        }
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(fs::read(output_dir.join("file-5")).unwrap(), [1, 2, 3]);
+    assert_eq!(file_manager.stats.unauthenticated_datagrams, 3);
+    assert_eq!(file_manager.stats.packets, 2);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn without_a_key_there_is_no_room_for_a_mac() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("no-room-for-a-mac");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        ..quick_config(&server)
+    };
+
+    // A packet only as much longer than a full one as a MAC would make it
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let long = DataPacket::new(4, 0, vec![9; MAX_DATA_LEN + TAG_LEN], false);
+        server
+            .send_to(&Packet::DataPacket(long).to_bytes(), client)
+            .unwrap();
+        send_file(&server, client, 4, vec![1, 2, 3]);
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(fs::read(output_dir.join("file-4")).unwrap(), [1, 2, 3]);
+    assert_eq!(file_manager.stats.oversized_datagrams, 1);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
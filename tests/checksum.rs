@@ -0,0 +1,153 @@
+// Exercises the checksum extension against fake servers that send digests
+// a file doesn't match.
+
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+
+use segmented_file_system_client::extension::ControlMessage;
+use segmented_file_system_client::{checksum, receive_files, ClientConfig, ClientError, Features};
+
+mod common;
+
+use common::{quick_config, scratch_dir, send_file};
+
+#[test]
+fn file_that_fails_its_checksum_is_not_written() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("bad-checksum");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        extensions: Features::CHECKSUM,
+        ..quick_config(&server)
+    };
+
+    // Accept the checksum extension, then send a file with the wrong digest,
+    // as if a packet had been corrupted on the way.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let accept = ControlMessage::Accept(Features::CHECKSUM);
+        server.send_to(&accept.to_bytes(), client).unwrap();
+        send_file(&server, client, 2, vec![1, 2, 3]);
+        let checksum = ControlMessage::Checksum {
+            file_id: 2,
+            digest: checksum::digest(&[1, 2, 4]),
+        };
+        server.send_to(&checksum.to_bytes(), client).unwrap();
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    let result = receive_files(&socket, &mut file_manager, &config, &mut ());
+    server_thread.join().unwrap();
+
+    match result {
+        Err(ClientError::ChecksumMismatch {
+            file_id: 2,
+            expected,
+            actual,
+            ..
+        }) => {
+            assert_eq!(expected, checksum::digest(&[1, 2, 4]));
+            assert_eq!(actual, checksum::digest(&[1, 2, 3]));
+        }
+        other => panic!("expected a checksum mismatch, got {other:?}"),
+    }
+    assert!(!output_dir.join("file-2").exists());
+}
+
+#[test]
+fn files_wait_for_their_checksum_even_if_the_accept_is_lost() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("lost-accept");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        extensions: Features::CHECKSUM,
+        ..quick_config(&server)
+    };
+
+    // The accept never arrives, but the file and its (wrong) checksum do
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        send_file(&server, client, 2, vec![1, 2, 3]);
+        let checksum = ControlMessage::Checksum {
+            file_id: 2,
+            digest: checksum::digest(&[1, 2, 4]),
+        };
+        server.send_to(&checksum.to_bytes(), client).unwrap();
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    let result = receive_files(&socket, &mut file_manager, &config, &mut ());
+    server_thread.join().unwrap();
+
+    assert!(
+        matches!(
+            result,
+            Err(ClientError::ChecksumMismatch { file_id: 2, .. })
+        ),
+        "{result:?}"
+    );
+    assert!(!output_dir.join("file-2").exists());
+}
+
+#[test]
+fn files_without_a_checksum_are_not_written_unverified() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("no-checksum");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        extensions: Features::CHECKSUM,
+        ..quick_config(&server)
+    };
+
+    // Neither the accept nor the checksum arrives
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        send_file(&server, client, 2, vec![1, 2, 3]);
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    let result = receive_files(&socket, &mut file_manager, &config, &mut ());
+    server_thread.join().unwrap();
+
+    match result {
+        Err(ClientError::MissingChecksums { file_ids, .. }) => assert_eq!(file_ids, [2]),
+        other => panic!("expected missing checksums, got {other:?}"),
+    }
+    assert!(!output_dir.join("file-2").exists());
+}
+
+#[test]
+fn declined_checksums_release_held_files() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("declined-checksum");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        extensions: Features::CHECKSUM | Features::NACK,
+        ..quick_config(&server)
+    };
+
+    // The accept, leaving checksums out, is overtaken by the file
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        send_file(&server, client, 2, vec![1, 2, 3]);
+        let accept = ControlMessage::Accept(Features::NACK);
+        server.send_to(&accept.to_bytes(), client).unwrap();
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert!(!file_manager.require_checksums);
+    assert_eq!(fs::read(output_dir.join("file-2")).unwrap(), [1, 2, 3]);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
@@ -10,12 +10,22 @@ set -eou pipefail
 # We only want to run the client once at the start because
 # it takes quite a while, so the `setup` work only happens
 # one time.
+#
+# By default this uses the native Rust server, so no Java is needed. Set
+# USE_JAR_SERVER=1 to test against tests/lib/Segmented-File-System-server.jar
+# instead (it serves files from ../testFiles/ relative to tests/lib).
 setup(){
     if [ "$BATS_TEST_NUMBER" -eq 1 ]; then
-      pushd tests/lib || exit
-      java -jar Segmented-File-System-server.jar &
+      cargo build --bins
+
+      if [ "${USE_JAR_SERVER:-0}" -eq 1 ]; then
+        pushd tests/lib || exit
+        java -jar Segmented-File-System-server.jar &
+        popd || exit
+      else
+        target/debug/segmented-file-system-server tests/target-files &
+      fi
       sleep 1
-      popd || exit
 
       # Clean out any previously downloaded files.
       rm -f small.txt
@@ -23,7 +33,7 @@ setup(){
       rm -f binary.jpg
 
       # Run the client
-      cargo run
+      target/debug/segmented-file-system-client
 
       kill %1
     fi
@@ -34,20 +44,20 @@ setup(){
   # Uncomment this line if you want to see the result of
   # the diff if this test is failing. Similar lines can
   # help with the other tests.
-  # diff test/target-files/small.txt src/small.txt
-  run diff test/target-files/small.txt src/small.txt
+  # diff tests/target-files/small.txt small.txt
+  run diff tests/target-files/small.txt small.txt
 
   [ "$status" -eq 0 ]
 }
 
 @test "Your client correctly assembled AsYouLikeIt.txt" {
-  run diff test/target-files/AsYouLikeIt.txt src/AsYouLikeIt.txt
+  run diff tests/target-files/AsYouLikeIt.txt AsYouLikeIt.txt
 
   [ "$status" -eq 0 ]
 }
 
 @test "Your client correctly assembled binary.jpg" {
-  run diff test/target-files/binary.jpg src/binary.jpg
+  run diff tests/target-files/binary.jpg binary.jpg
 
   [ "$status" -eq 0 ]
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+// Helpers shared by the tests that run the client against fake servers.
+// Each test binary uses its own subset of them.
+#![allow(dead_code)]
+
+use std::ffi::OsString;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use segmented_file_system_client::packet::{
+    data_packet::DataPacket, header_packet::HeaderPacket, Packet,
+};
+use segmented_file_system_client::{ClientConfig, Completion};
+
+/// An empty directory for tests whose files are complete, and so get written.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sfs-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Sends a whole one-packet file, header first.
+pub fn send_file(server: &UdpSocket, client: SocketAddr, file_id: u8, data: Vec<u8>) {
+    let header = HeaderPacket::new(file_id, OsString::from(format!("file-{file_id}")));
+    let packet = Packet::DataPacket(DataPacket::new(file_id, 0, data, true));
+    for packet in [Packet::HeaderPacket(header), packet] {
+        server.send_to(&packet.to_bytes(), client).unwrap();
+    }
+}
+
+/// A client config for `server` with timeouts short enough for tests,
+/// expecting a single file.
+pub fn quick_config(server: &UdpSocket) -> ClientConfig {
+    ClientConfig {
+        server_port: server.local_addr().unwrap().port(),
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        read_timeout: Duration::from_millis(20),
+        hello_interval: Duration::from_millis(100),
+        idle_timeout: Duration::from_millis(500),
+        completion: Completion::FileCount(1),
+        ..ClientConfig::default()
+    }
+}
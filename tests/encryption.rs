@@ -0,0 +1,73 @@
+// Exercises the encrypted transfer mode against a minimal encrypting server
+// and datagrams sent in the clear or altered on the way.
+
+use std::ffi::OsString;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+
+use segmented_file_system_client::extension::ControlMessage;
+use segmented_file_system_client::packet::encryption::{KeyExchange, Role};
+use segmented_file_system_client::packet::{
+    data_packet::DataPacket, header_packet::HeaderPacket, Packet,
+};
+use segmented_file_system_client::{receive_files, ClientConfig};
+
+mod common;
+
+use common::{quick_config, scratch_dir};
+
+#[test]
+fn encrypted_packets_are_decrypted_before_parsing() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("encrypted");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        encrypt: true,
+        ..quick_config(&server)
+    };
+
+    // A minimal encrypting sender: it answers the key exchange in the hello
+    // and encrypts the file. Datagrams in the clear, from before or after
+    // the exchange, and one altered on the way are all dropped.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (len, client) = server.recv_from(&mut buf).unwrap();
+        let Some(ControlMessage::EncryptedHello { public_key, .. }) =
+            ControlMessage::parse(&buf[..len])
+        else {
+            panic!("expected an encrypted hello");
+        };
+        let exchange = KeyExchange::new().unwrap();
+        let cipher = exchange.finish(Role::Server, &public_key, None).unwrap();
+
+        let header = Packet::HeaderPacket(HeaderPacket::new(5, OsString::from("secret-name")));
+        let forged = Packet::DataPacket(DataPacket::new(5, 0, vec![6, 6, 6], true));
+        server.send_to(&forged.to_bytes(), client).unwrap();
+        let answer = ControlMessage::KeyExchange {
+            public_key: *exchange.public_key(),
+        };
+        server.send_to(&answer.to_bytes(), client).unwrap();
+        server.send_to(&forged.to_bytes(), client).unwrap();
+        let mut altered = cipher.seal(&forged.to_bytes()).unwrap();
+        *altered.last_mut().unwrap() ^= 1;
+        server.send_to(&altered, client).unwrap();
+
+        let packet = Packet::DataPacket(DataPacket::new(5, 0, vec![1, 2, 3], true));
+        for packet in [header, packet] {
+            let sealed = cipher.seal(&packet.to_bytes()).unwrap();
+            assert!(!sealed.windows(11).any(|window| window == b"secret-name"));
+            server.send_to(&sealed, client).unwrap();
+        }
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(fs::read(output_dir.join("secret-name")).unwrap(), [1, 2, 3]);
+    assert_eq!(file_manager.stats.unauthenticated_datagrams, 3);
+    assert_eq!(file_manager.stats.packets, 2);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
@@ -0,0 +1,97 @@
+// Exercises the resource limits against fake servers that send more than
+// the client agreed to hold.
+
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use segmented_file_system_client::packet::{data_packet::DataPacket, Packet, MAX_DATA_LEN};
+use segmented_file_system_client::{
+    receive_files, ClientConfig, ClientError, Completion, LimitExceeded, Limits,
+};
+
+mod common;
+
+use common::{quick_config, scratch_dir};
+
+#[test]
+fn a_server_that_breaks_a_limit_stops_the_transfer() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("limits");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        completion: Completion::FileCount(3),
+        limits: Limits {
+            max_files: Some(2),
+            ..Limits::default()
+        },
+        ..quick_config(&server)
+    };
+
+    // A server that opens a third file while two are still in progress
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        for file_id in 0..3 {
+            let packet =
+                Packet::DataPacket(DataPacket::new(file_id, 0, vec![1; MAX_DATA_LEN], false));
+            server.send_to(&packet.to_bytes(), client).unwrap();
+        }
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    let result = receive_files(&socket, &mut file_manager, &config, &mut ());
+    server_thread.join().unwrap();
+
+    assert!(
+        matches!(
+            result,
+            Err(ClientError::LimitExceeded(LimitExceeded::Files {
+                file_id: 2,
+                limit: 2
+            }))
+        ),
+        "{result:?}"
+    );
+    assert_eq!(file_manager.packet_groups.len(), 2);
+    assert!(!output_dir.exists());
+}
+
+#[test]
+fn a_server_that_goes_quiet_still_runs_out_of_time() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = ClientConfig {
+        output_dir: scratch_dir("duration"),
+        idle_timeout: Duration::from_secs(5),
+        limits: Limits {
+            max_duration: Some(Duration::from_millis(300)),
+            ..Limits::default()
+        },
+        ..quick_config(&server)
+    };
+
+    // A server that sends the start of a file and then nothing more, well
+    // short of the idle timeout
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let packet = Packet::DataPacket(DataPacket::new(0, 0, vec![1; MAX_DATA_LEN], false));
+        server.send_to(&packet.to_bytes(), client).unwrap();
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    let started = Instant::now();
+    let result = receive_files(&socket, &mut file_manager, &config, &mut ());
+    server_thread.join().unwrap();
+
+    assert!(
+        matches!(
+            result,
+            Err(ClientError::LimitExceeded(LimitExceeded::Duration { .. }))
+        ),
+        "{result:?}"
+    );
+    assert!(started.elapsed() < config.idle_timeout);
+}
@@ -0,0 +1,417 @@
+// Runs the real client binary against the native server, so the whole
+// protocol can be exercised without a JVM.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use segmented_file_system_client::packet::auth::PacketKey;
+use segmented_file_system_client::server::{files_in_directory, Server};
+use segmented_file_system_client::{
+    checksum, receive_files, ClientConfig, Features, ReassemblyMode,
+};
+
+const TARGET_FILES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/target-files");
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn assert_same_file(expected: &Path, actual: &Path) {
+    assert_eq!(
+        fs::read(expected).unwrap(),
+        fs::read(actual).unwrap(),
+        "{} differs from {}",
+        actual.display(),
+        expected.display()
+    );
+}
+
+#[test]
+fn client_reassembles_files_from_native_server() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-round-trip");
+    let status = Command::new(env!("CARGO_BIN_EXE_segmented-file-system-client"))
+        .args(["--server-port", &server_port.to_string()])
+        .args(["--bind-host", "127.0.0.1", "--bind-port", "0"])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn streaming_reassembly_from_native_server() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-streaming");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        reassembly: ReassemblyMode::Streaming,
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn lost_packets_are_requested_again() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_packet_loss(20)
+        .with_seed(7);
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-nack");
+    let status = Command::new(env!("CARGO_BIN_EXE_segmented-file-system-client"))
+        .args(["--server-port", &server_port.to_string()])
+        .args(["--bind-host", "127.0.0.1", "--bind-port", "0"])
+        .args([
+            "--nack",
+            "--nack-interval-ms",
+            "100",
+            "--idle-timeout-ms",
+            "5000",
+        ])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // The client says when it's done, so the server doesn't linger
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn nack_offer_is_ignored_by_a_legacy_server() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_features(Features::NONE);
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-nack-legacy");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        extensions: Features::NACK,
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 3);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn lost_headers_are_requested_again() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_header_loss(50)
+        .with_seed(3);
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-resend-header");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        extensions: Features::RESEND_HEADER,
+        nack_interval: Duration::from_millis(100),
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn files_whose_header_never_arrives_get_fallback_names() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_header_loss(100);
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-fallback-name");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        fallback_name: Some(String::from("file-{id}.bin")),
+        header_grace: Duration::from_millis(300),
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    // The server hands out IDs in the order of the sorted file names
+    for (file_id, name) in ["AsYouLikeIt.txt", "binary.jpg", "small.txt"]
+        .into_iter()
+        .enumerate()
+    {
+        assert_same_file(
+            &Path::new(TARGET_FILES).join(name),
+            &output_dir.join(format!("file-{file_id}.bin")),
+        );
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn checksums_are_checked_before_files_are_written() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-checksum");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        reassembly: ReassemblyMode::Streaming,
+        extensions: Features::CHECKSUM,
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert!(file_manager.require_checksums);
+    for packet_group in file_manager.packet_groups.iter() {
+        let name = packet_group.file_name().unwrap();
+        let expected = fs::read(Path::new(TARGET_FILES).join(name)).unwrap();
+        assert_eq!(packet_group.digest(), Some(&checksum::digest(&expected)));
+        assert_eq!(fs::read(output_dir.join(name)).unwrap(), expected);
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn authenticated_transfer_with_a_shared_key() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_packet_loss(5)
+        .with_seed(23)
+        .with_linger(Duration::from_secs(2))
+        .with_key(Some(PacketKey::new("correct horse").unwrap()));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    // The NACKs for the lost packets have to be signed too
+    let output_dir = scratch_dir("sfs-authenticated");
+    let key_file = output_dir.join("sfs.key");
+    fs::write(&key_file, "correct horse\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_segmented-file-system-client"))
+        .args(["--server-port", &server_port.to_string()])
+        .args(["--bind-host", "127.0.0.1", "--bind-port", "0"])
+        .args(["--nack", "--nack-interval-ms", "100"])
+        .arg("--key-file")
+        .arg(&key_file)
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .env_remove("SFS_KEY")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("failed authentication"));
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn encrypted_transfer_with_lost_packets() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_packet_loss(5)
+        .with_seed(24)
+        .with_features(Features::ALL)
+        .with_linger(Duration::from_secs(2));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    // The accept, NACKs, resent packets and checksums are all encrypted
+    let output_dir = scratch_dir("sfs-encrypted");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        extensions: Features::ALL,
+        nack_interval: Duration::from_millis(100),
+        encrypt: true,
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+    assert_eq!(file_manager.stats.unauthenticated_datagrams, 0);
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn encrypted_transfer_needs_the_servers_key() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_key(Some(PacketKey::new("correct horse").unwrap()));
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-encrypted-wrong-key");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        idle_timeout: Duration::from_secs(1),
+        key: Some(PacketKey::new("battery staple").unwrap()),
+        encrypt: true,
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    assert!(receive_files(&socket, &mut file_manager, &config, &mut ()).is_err());
+
+    // A hello that isn't signed with the key isn't answered at all
+    assert_eq!(file_manager.stats.unauthenticated_datagrams, 0);
+    assert_eq!(file_manager.stats.packets, 0);
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    // The server is still waiting for a client with the key
+    let config = ClientConfig {
+        key: Some(PacketKey::new("correct horse").unwrap()),
+        ..config
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    assert_eq!(server_thread.join().unwrap(), socket.local_addr().unwrap());
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn server_without_linger_stops_after_sending() {
+    let files = files_in_directory(Path::new(TARGET_FILES)).unwrap();
+    let mut server = Server::bind("127.0.0.1:0", files)
+        .unwrap()
+        .with_packet_delay(Duration::from_micros(50))
+        .with_features(Features::ALL)
+        .with_linger(Duration::ZERO);
+    let server_port = server.local_addr().unwrap().port();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let output_dir = scratch_dir("sfs-no-linger");
+    let config = ClientConfig {
+        server_port,
+        bind_host: String::from("127.0.0.1"),
+        bind_port: 0,
+        output_dir: output_dir.clone(),
+        extensions: Features::ALL,
+        ..ClientConfig::default()
+    };
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    for name in ["small.txt", "AsYouLikeIt.txt", "binary.jpg"] {
+        assert_same_file(&Path::new(TARGET_FILES).join(name), &output_dir.join(name));
+    }
+
+    fs::remove_dir_all(&output_dir).unwrap();
+}
@@ -0,0 +1,86 @@
+// Exercises source filtering against fake servers that share the client's
+// port with other senders.
+
+use std::ffi::OsString;
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+
+use segmented_file_system_client::packet::{
+    data_packet::DataPacket, header_packet::HeaderPacket, Packet, MAX_DATA_LEN,
+};
+use segmented_file_system_client::{receive_files, ClientConfig, SourcePolicy};
+
+mod common;
+
+use common::{quick_config, scratch_dir, send_file};
+
+#[test]
+fn datagrams_from_other_addresses_are_dropped_and_counted() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("stray");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        ..quick_config(&server)
+    };
+
+    // Another host tries to slip its own version of the file in first.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_file(&spoofer, client, 4, vec![6, 6, 6]);
+        send_file(&server, client, 4, vec![1, 2, 3]);
+    });
+
+    // Left unconnected, so the client's own filter is what drops them
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(fs::read(output_dir.join("file-4")).unwrap(), [1, 2, 3]);
+    assert_eq!(file_manager.stats.stray_datagrams, 2);
+    assert_eq!(file_manager.stats.packets, 2);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn session_is_bound_to_the_first_server_that_answers() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("first-responder");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        accept_from: SourcePolicy::FirstResponder,
+        ..quick_config(&server)
+    };
+
+    // As if the hello had been broadcast: another server answers first, and
+    // the one it was sent to only starts once the session is taken.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let header = HeaderPacket::new(1, OsString::from("file-1"));
+        first
+            .send_to(&Packet::HeaderPacket(header).to_bytes(), client)
+            .unwrap();
+        let packet = DataPacket::new(1, 0, vec![1; MAX_DATA_LEN], false);
+        first.send_to(&packet.to_bytes(), client).unwrap();
+        send_file(&server, client, 2, vec![9]);
+        let packet = DataPacket::new(1, 1, vec![2], true);
+        first.send_to(&packet.to_bytes(), client).unwrap();
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+    server_thread.join().unwrap();
+
+    let file = fs::read(output_dir.join("file-1")).unwrap();
+    assert_eq!(file.len(), MAX_DATA_LEN + 1);
+    assert_eq!(file[MAX_DATA_LEN - 1..], [1, 2]);
+    assert!(!output_dir.join("file-2").exists());
+    assert_eq!(file_manager.stats.stray_datagrams, 2);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
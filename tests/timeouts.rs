@@ -0,0 +1,198 @@
+// Exercises the hello retry and idle deadline against fake servers that
+// misbehave in controlled ways.
+
+use std::ffi::OsString;
+use std::fs;
+use std::net::UdpSocket;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use segmented_file_system_client::packet::{
+    data_packet::DataPacket, header_packet::HeaderPacket, Packet, MAX_DATA_LEN,
+};
+use segmented_file_system_client::{receive_files, ClientConfig, ClientError, Completion};
+
+mod common;
+
+use common::{quick_config, scratch_dir, send_file};
+
+#[test]
+fn hello_is_resent_until_server_answers() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("hello-resent");
+    let config = ClientConfig {
+        output_dir: output_dir.clone(),
+        ..quick_config(&server)
+    };
+
+    // Ignore the first hello, as if it had been lost, then send a whole file.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        server.recv_from(&mut buf).unwrap();
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        send_file(&server, client, 4, vec![1, 2, 3]);
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+
+    server_thread.join().unwrap();
+    assert!(file_manager.received_all_packets());
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn idle_server_times_out_with_incomplete_files() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = quick_config(&server);
+
+    // Send two packets of a three packet file, then go quiet.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        for packet_number in [0, 2] {
+            let is_last = packet_number == 2;
+            let packet = DataPacket::new(9, packet_number, vec![0; 8], is_last);
+            server.send_to(&packet.to_bytes(), client).unwrap();
+        }
+        // Keep the socket open so the client sees silence, not a refusal.
+        thread::sleep(Duration::from_secs(1));
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    let result = receive_files(&socket, &mut file_manager, &config, &mut ());
+
+    match result {
+        Err(ClientError::Timeout { incomplete, .. }) => {
+            assert_eq!(incomplete.len(), 1);
+            assert_eq!(incomplete[0].file_id, 9);
+            assert_eq!(incomplete[0].expected_number_of_packets, Some(3));
+            assert_eq!(incomplete[0].received_packets, 2);
+        }
+        other => panic!("expected a timeout, got {other:?}"),
+    }
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn quiet_period_waits_for_late_files() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("quiet-period");
+    let config = ClientConfig {
+        completion: Completion::QuietPeriod(Duration::from_millis(300)),
+        output_dir: output_dir.clone(),
+        ..quick_config(&server)
+    };
+
+    // One complete file, a pause shorter than the quiet period, then another.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        for file_id in [1, 2] {
+            send_file(&server, client, file_id, vec![file_id]);
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+
+    server_thread.join().unwrap();
+    assert_eq!(file_manager.packet_groups.len(), 2);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn quiet_period_longer_than_the_idle_timeout_still_completes() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output_dir = scratch_dir("long-quiet-period");
+    let config = ClientConfig {
+        completion: Completion::QuietPeriod(Duration::from_millis(800)),
+        output_dir: output_dir.clone(),
+        ..quick_config(&server)
+    };
+    assert!(config.idle_timeout < Duration::from_millis(800));
+
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        send_file(&server, client, 1, vec![1]);
+    });
+
+    let socket = config.connect().unwrap();
+    let mut file_manager = config.file_manager();
+    receive_files(&socket, &mut file_manager, &config, &mut ()).unwrap();
+
+    server_thread.join().unwrap();
+    assert_eq!(fs::read(output_dir.join("file-1")).unwrap(), [1]);
+    fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn stopped_transfer_reports_gaps_and_writes_partial_files() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    let output_dir = scratch_dir("partial");
+    let report_path = output_dir.with_extension("json");
+
+    // Send all of a four packet file but its second packet, then go quiet.
+    let server_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let header = HeaderPacket::new(3, OsString::from("gappy.bin"));
+        server
+            .send_to(&Packet::HeaderPacket(header).to_bytes(), client)
+            .unwrap();
+        for packet_number in [0, 2, 3] {
+            let is_last = packet_number == 3;
+            let data = vec![7; if is_last { 5 } else { MAX_DATA_LEN }];
+            let packet = DataPacket::new(3, packet_number, data, is_last);
+            server.send_to(&packet.to_bytes(), client).unwrap();
+        }
+        thread::sleep(Duration::from_secs(1));
+    });
+
+    let output = Command::new(env!("CARGO_BIN_EXE_segmented-file-system-client"))
+        .args(["--server-port", &server_port.to_string()])
+        .args(["--bind-host", "127.0.0.1", "--bind-port", "0"])
+        .args(["--expected-files", "1", "--read-timeout-ms", "20"])
+        .args(["--idle-timeout-ms", "500", "--write-partial"])
+        .arg("--report-json")
+        .arg(&report_path)
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .output()
+        .unwrap();
+    server_thread.join().unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("file 3 (gappy.bin): 3 of 4 packets; missing 1"),
+        "{stderr}"
+    );
+    assert_eq!(
+        fs::read_to_string(&report_path).unwrap(),
+        "{\"files\":[{\"file_id\":3,\"file_name\":\"gappy.bin\",\"expected_packets\":4,\
+         \"received_packets\":3,\"written\":false,\"failed\":false,\"missing\":[[1,1]]}]}\n"
+    );
+
+    // The hole is left where packet 1 belongs
+    let partial = fs::read(output_dir.join("gappy.bin")).unwrap();
+    assert_eq!(partial.len(), 3 * MAX_DATA_LEN + 5);
+    assert!(partial[..MAX_DATA_LEN].iter().all(|&byte| byte == 7));
+    assert!(partial[MAX_DATA_LEN..2 * MAX_DATA_LEN]
+        .iter()
+        .all(|&byte| byte == 0));
+    assert_eq!(
+        fs::read_to_string(output_dir.join("gappy.bin.missing")).unwrap(),
+        "1\n"
+    );
+
+    fs::remove_dir_all(&output_dir).unwrap();
+    fs::remove_file(&report_path).unwrap();
+}